@@ -0,0 +1 @@
+ALTER TABLE oauth_connections DROP COLUMN needs_reauth;
@@ -0,0 +1,2 @@
+ALTER TABLE oauth_connections
+    ADD COLUMN needs_reauth BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1 @@
+ALTER TABLE oauth_connections DROP COLUMN token_key_id;
@@ -0,0 +1,4 @@
+-- Id of the key access_token and refresh_token are encrypted with;
+-- NULL for rows written before tokens were encrypted (plaintext)
+ALTER TABLE oauth_connections
+    ADD COLUMN token_key_id TEXT;
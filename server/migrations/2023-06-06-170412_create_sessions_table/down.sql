@@ -0,0 +1,2 @@
+DROP TABLE session_provider_sessions;
+DROP TABLE sessions;
@@ -0,0 +1,28 @@
+CREATE TABLE sessions (
+    -- SHA-256 of the session cookie, so a database leak doesn't leak sessions
+    id_hash TEXT PRIMARY KEY,
+    user_id INT NOT NULL REFERENCES users ON DELETE CASCADE,
+
+    rp_logout_providers_with_open_sessions TEXT[] NOT NULL DEFAULT '{}',
+    user_agent TEXT,
+    ip_address TEXT,
+
+    expires TIMESTAMP NOT NULL,
+    created_on TIMESTAMP NOT NULL DEFAULT current_timestamp
+);
+
+CREATE INDEX sessions_user_id_idx ON sessions (user_id);
+CREATE INDEX sessions_expires_idx ON sessions (expires);
+
+-- OpenID Connect sessions each session is tied to, for back-channel logout
+CREATE TABLE session_provider_sessions (
+    session_id_hash TEXT NOT NULL REFERENCES sessions ON DELETE CASCADE,
+    provider TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    sid TEXT,
+
+    PRIMARY KEY (session_id_hash, provider, subject)
+);
+
+CREATE INDEX session_provider_sessions_subject_idx ON session_provider_sessions (provider, subject);
+CREATE INDEX session_provider_sessions_sid_idx ON session_provider_sessions (provider, sid);
@@ -0,0 +1,6 @@
+ALTER TABLE sessions
+    DROP COLUMN login_method,
+    DROP COLUMN login_provider,
+    DROP COLUMN last_seen;
+
+DROP TYPE LOGIN_METHOD;
@@ -0,0 +1,10 @@
+CREATE TYPE LOGIN_METHOD AS ENUM ('password', 'open_id');
+
+-- Existing sessions don't know how they were started; their users just sign
+-- in again
+DELETE FROM sessions;
+
+ALTER TABLE sessions
+    ADD COLUMN login_method LOGIN_METHOD NOT NULL,
+    ADD COLUMN login_provider TEXT,
+    ADD COLUMN last_seen TIMESTAMP NOT NULL DEFAULT current_timestamp;
@@ -0,0 +1,10 @@
+CREATE TABLE password_reset_tokens (
+    -- SHA-256 of the token sent by email
+    token_hash TEXT PRIMARY KEY,
+    user_id INT NOT NULL REFERENCES users ON DELETE CASCADE,
+
+    expires TIMESTAMP NOT NULL,
+    created_on TIMESTAMP NOT NULL DEFAULT current_timestamp
+);
+
+CREATE INDEX password_reset_tokens_user_id_idx ON password_reset_tokens (user_id);
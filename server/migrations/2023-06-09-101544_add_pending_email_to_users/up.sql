@@ -0,0 +1,2 @@
+-- New address the user asked to switch to; it replaces `email` once verified
+ALTER TABLE users ADD COLUMN pending_email EMAIL;
@@ -0,0 +1,3 @@
+ALTER TABLE sessions DROP COLUMN two_factor;
+DROP TABLE recovery_codes;
+DROP TABLE totp_logins;
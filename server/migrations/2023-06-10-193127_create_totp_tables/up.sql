@@ -0,0 +1,25 @@
+CREATE TABLE totp_logins (
+    user_id INT PRIMARY KEY REFERENCES users ON DELETE CASCADE,
+    -- Base32 secret, encrypted like the tokens in oauth_connections
+    secret TEXT NOT NULL,
+    secret_key_id TEXT NOT NULL,
+    -- Set once the user has entered a code from their authenticator
+    confirmed BOOLEAN NOT NULL DEFAULT false,
+    -- Time step of the last accepted code, so codes can't be replayed
+    last_used_step BIGINT,
+
+    created_on TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    updated_at TIMESTAMP NOT NULL DEFAULT current_timestamp
+);
+
+SELECT diesel_manage_updated_at('totp_logins'::regclass);
+
+CREATE TABLE recovery_codes (
+    user_id INT NOT NULL REFERENCES users ON DELETE CASCADE,
+    -- SHA-256 of the code
+    code_hash TEXT NOT NULL,
+
+    PRIMARY KEY (user_id, code_hash)
+);
+
+ALTER TABLE sessions ADD COLUMN two_factor BOOLEAN NOT NULL DEFAULT false;
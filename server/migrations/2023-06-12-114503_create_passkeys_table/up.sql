@@ -0,0 +1,20 @@
+CREATE TABLE passkeys (
+    id SERIAL PRIMARY KEY,
+    user_id INT NOT NULL REFERENCES users ON DELETE CASCADE,
+    -- Base64url credential id, as sent by authenticators
+    credential_id TEXT UNIQUE NOT NULL,
+    -- WebAuthn user handle; shared by all of a user's passkeys
+    user_handle TEXT NOT NULL,
+    name TEXT NOT NULL,
+    -- Serialized credential, including its public key
+    passkey TEXT NOT NULL,
+    -- Signature counter reported by the authenticator; 0 if it doesn't keep one
+    sign_count BIGINT NOT NULL DEFAULT 0,
+
+    created_on TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    last_used TIMESTAMP
+);
+
+CREATE INDEX passkeys_user_id_idx ON passkeys (user_id);
+
+ALTER TYPE LOGIN_METHOD ADD VALUE 'passkey';
@@ -0,0 +1,9 @@
+DROP TABLE magic_link_tokens;
+
+-- Enum values can't be dropped, so the type is recreated
+DELETE FROM sessions WHERE login_method = 'magic_link';
+ALTER TYPE LOGIN_METHOD RENAME TO LOGIN_METHOD_OLD;
+CREATE TYPE LOGIN_METHOD AS ENUM ('password', 'open_id', 'passkey');
+ALTER TABLE sessions
+    ALTER COLUMN login_method TYPE LOGIN_METHOD USING login_method::TEXT::LOGIN_METHOD;
+DROP TYPE LOGIN_METHOD_OLD;
@@ -0,0 +1,12 @@
+CREATE TABLE magic_link_tokens (
+    -- SHA-256 of the token sent by email
+    token_hash TEXT PRIMARY KEY,
+    user_id INT NOT NULL REFERENCES users ON DELETE CASCADE,
+
+    expires TIMESTAMP NOT NULL,
+    created_on TIMESTAMP NOT NULL DEFAULT current_timestamp
+);
+
+CREATE INDEX magic_link_tokens_user_id_idx ON magic_link_tokens (user_id);
+
+ALTER TYPE LOGIN_METHOD ADD VALUE 'magic_link';
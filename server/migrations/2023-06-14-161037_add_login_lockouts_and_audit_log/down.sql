@@ -0,0 +1,6 @@
+DROP TABLE audit_log;
+DROP TYPE AUDIT_EVENT;
+
+ALTER TABLE local_logins
+    DROP COLUMN failed_attempts,
+    DROP COLUMN locked_until;
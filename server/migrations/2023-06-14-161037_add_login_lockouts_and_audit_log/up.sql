@@ -0,0 +1,20 @@
+ALTER TABLE local_logins
+    ADD COLUMN failed_attempts INT NOT NULL DEFAULT 0,
+    ADD COLUMN locked_until TIMESTAMP;
+
+CREATE TYPE AUDIT_EVENT AS ENUM ('account_locked', 'account_unlocked');
+
+CREATE TABLE audit_log (
+    id SERIAL PRIMARY KEY,
+    event AUDIT_EVENT NOT NULL,
+    -- The account the event is about
+    user_id INT REFERENCES users ON DELETE SET NULL,
+    -- Who caused it, if it wasn't the system
+    actor_id INT REFERENCES users ON DELETE SET NULL,
+    ip_address TEXT,
+    details TEXT,
+    created_on TIMESTAMP NOT NULL DEFAULT current_timestamp
+);
+
+CREATE INDEX audit_log_user_id_idx ON audit_log (user_id);
+CREATE INDEX audit_log_created_on_idx ON audit_log (created_on);
@@ -0,0 +1,3 @@
+-- Argon2 hashes don't fit, so those accounts need a password reset
+DELETE FROM local_logins WHERE hash NOT LIKE '$2%';
+ALTER TABLE local_logins ALTER COLUMN hash TYPE CHAR(60);
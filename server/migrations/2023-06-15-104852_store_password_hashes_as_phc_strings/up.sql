@@ -0,0 +1,2 @@
+-- PHC strings vary in length with the algorithm and its parameters
+ALTER TABLE local_logins ALTER COLUMN hash TYPE TEXT;
@@ -0,0 +1,16 @@
+CREATE TYPE API_TOKEN_SCOPE AS ENUM ('read', 'appointments', 'admin');
+
+CREATE TABLE api_tokens (
+    id SERIAL PRIMARY KEY,
+    user_id INT NOT NULL REFERENCES users ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    -- SHA-256 of the token; the token itself is only shown once
+    token_hash TEXT NOT NULL UNIQUE,
+    scopes API_TOKEN_SCOPE[] NOT NULL,
+
+    expires TIMESTAMP NOT NULL,
+    created_on TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    last_used TIMESTAMP
+);
+
+CREATE INDEX api_tokens_user_id_idx ON api_tokens (user_id);
@@ -0,0 +1,11 @@
+CREATE TABLE saml_identities (
+    provider TEXT NOT NULL,
+    name_id TEXT NOT NULL,
+    user_id INT NOT NULL REFERENCES users ON DELETE CASCADE,
+    created_on TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    PRIMARY KEY (provider, name_id)
+);
+
+CREATE INDEX saml_identities_user_id_idx ON saml_identities (user_id);
+
+ALTER TYPE LOGIN_METHOD ADD VALUE 'saml';
@@ -0,0 +1,9 @@
+DROP TABLE ldap_identities;
+
+-- Enum values can't be dropped, so the type is recreated
+DELETE FROM sessions WHERE login_method = 'ldap';
+ALTER TYPE LOGIN_METHOD RENAME TO LOGIN_METHOD_OLD;
+CREATE TYPE LOGIN_METHOD AS ENUM ('password', 'open_id', 'passkey', 'magic_link', 'saml');
+ALTER TABLE sessions
+    ALTER COLUMN login_method TYPE LOGIN_METHOD USING login_method::TEXT::LOGIN_METHOD;
+DROP TYPE LOGIN_METHOD_OLD;
@@ -0,0 +1,11 @@
+CREATE TABLE ldap_identities (
+    provider TEXT NOT NULL,
+    dn TEXT NOT NULL,
+    user_id INT NOT NULL REFERENCES users ON DELETE CASCADE,
+    created_on TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    PRIMARY KEY (provider, dn)
+);
+
+CREATE INDEX ldap_identities_user_id_idx ON ldap_identities (user_id);
+
+ALTER TYPE LOGIN_METHOD ADD VALUE 'ldap';
@@ -0,0 +1,4 @@
+-- SCIM deactivates users instead of deleting them
+ALTER TABLE users ADD COLUMN active BOOLEAN NOT NULL DEFAULT true;
+-- The identity provider's id for the user
+ALTER TABLE users ADD COLUMN external_id TEXT UNIQUE;
@@ -0,0 +1,11 @@
+DELETE FROM sessions WHERE impersonator_id IS NOT NULL;
+ALTER TABLE sessions DROP COLUMN impersonator_id;
+
+-- Enum values can't be dropped, so the type is recreated
+DELETE FROM audit_log
+    WHERE event IN ('impersonation_started', 'impersonation_stopped', 'impersonated_action');
+ALTER TYPE AUDIT_EVENT RENAME TO AUDIT_EVENT_OLD;
+CREATE TYPE AUDIT_EVENT AS ENUM ('account_locked', 'account_unlocked');
+ALTER TABLE audit_log
+    ALTER COLUMN event TYPE AUDIT_EVENT USING event::TEXT::AUDIT_EVENT;
+DROP TYPE AUDIT_EVENT_OLD;
@@ -0,0 +1,7 @@
+-- The admin impersonating the session's user, if any
+ALTER TABLE sessions
+    ADD COLUMN impersonator_id INT REFERENCES users ON DELETE CASCADE;
+
+ALTER TYPE AUDIT_EVENT ADD VALUE 'impersonation_started';
+ALTER TYPE AUDIT_EVENT ADD VALUE 'impersonation_stopped';
+ALTER TYPE AUDIT_EVENT ADD VALUE 'impersonated_action';
@@ -0,0 +1,4 @@
+ALTER TABLE is_member_of DROP COLUMN role_id;
+DROP TABLE user_roles;
+DROP TABLE roles;
+DROP TYPE PERMISSION;
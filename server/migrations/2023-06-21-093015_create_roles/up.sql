@@ -0,0 +1,48 @@
+CREATE TYPE PERMISSION AS ENUM (
+    'manage_users',
+    'manage_groups',
+    'manage_topics',
+    'manage_locations',
+    'manage_roles',
+    'view_reports',
+    'view_audit_log',
+    'impersonate_users'
+);
+
+CREATE TABLE roles (
+    id SERIAL PRIMARY KEY,
+    name TEXT UNIQUE NOT NULL,
+    description TEXT,
+    permissions PERMISSION[] NOT NULL DEFAULT '{}',
+    -- Built-in roles are held by every user at their permission level
+    permission_level PERMISSION_LEVEL UNIQUE,
+    created_on TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    updated_at TIMESTAMP NOT NULL DEFAULT current_timestamp
+);
+
+SELECT diesel_manage_updated_at('roles'::regclass);
+
+-- What each permission level allowed before roles
+INSERT INTO roles (name, description, permissions, permission_level) VALUES
+    ('Student', 'Held by every student', '{}', 'student'),
+    ('Teacher', 'Held by every teacher', '{manage_locations}', 'teacher'),
+    (
+        'Admin',
+        'Held by every admin',
+        '{manage_users, manage_groups, manage_topics, manage_locations, manage_roles, view_reports, view_audit_log, impersonate_users}',
+        'admin'
+    );
+
+-- Roles held everywhere
+CREATE TABLE user_roles (
+    user_id INT NOT NULL REFERENCES users ON DELETE CASCADE,
+    role_id INT NOT NULL REFERENCES roles ON DELETE CASCADE,
+    created_on TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    PRIMARY KEY (user_id, role_id)
+);
+
+CREATE INDEX user_roles_role_id_idx ON user_roles (role_id);
+
+-- A role held only within the group
+ALTER TABLE is_member_of
+    ADD COLUMN role_id INT REFERENCES roles ON DELETE SET NULL;
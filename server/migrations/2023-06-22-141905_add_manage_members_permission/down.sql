@@ -0,0 +1,18 @@
+-- Enum values can't be dropped, so the type is recreated
+UPDATE roles SET permissions = array_remove(permissions, 'manage_members');
+ALTER TYPE PERMISSION RENAME TO PERMISSION_OLD;
+CREATE TYPE PERMISSION AS ENUM (
+    'manage_users',
+    'manage_groups',
+    'manage_topics',
+    'manage_locations',
+    'manage_roles',
+    'view_reports',
+    'view_audit_log',
+    'impersonate_users'
+);
+ALTER TABLE roles ALTER COLUMN permissions DROP DEFAULT;
+ALTER TABLE roles
+    ALTER COLUMN permissions TYPE PERMISSION[] USING permissions::TEXT[]::PERMISSION[];
+ALTER TABLE roles ALTER COLUMN permissions SET DEFAULT '{}';
+DROP TYPE PERMISSION_OLD;
@@ -0,0 +1,3 @@
+-- Added separately from the role that uses it, since new enum values can't be
+-- used in the transaction that adds them
+ALTER TYPE PERMISSION ADD VALUE 'manage_members';
@@ -0,0 +1 @@
+DELETE FROM roles WHERE name = 'Group manager' AND permission_level IS NULL;
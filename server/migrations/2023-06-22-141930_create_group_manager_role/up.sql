@@ -0,0 +1,7 @@
+UPDATE roles
+    SET permissions = array_append(permissions, 'manage_members')
+    WHERE permission_level = 'admin';
+
+-- Given to course coordinators on their membership in the group they run
+INSERT INTO roles (name, description, permissions) VALUES
+    ('Group manager', 'Manages the members of a group', '{manage_members}');
@@ -0,0 +1,3 @@
+ALTER TABLE totp_logins
+    DROP COLUMN failed_attempts,
+    DROP COLUMN locked_until;
@@ -0,0 +1,3 @@
+ALTER TABLE totp_logins
+    ADD COLUMN failed_attempts INT NOT NULL DEFAULT 0,
+    ADD COLUMN locked_until TIMESTAMP;
@@ -0,0 +1,2 @@
+COMMENT ON COLUMN users.permission_level IS NULL;
+COMMENT ON COLUMN roles.permission_level IS NULL;
@@ -0,0 +1,6 @@
+-- Permissions live only on roles; a permission level just picks the
+-- built-in role its users hold
+COMMENT ON COLUMN users.permission_level IS
+    'Selects the built-in role the user holds; grants nothing by itself';
+COMMENT ON COLUMN roles.permission_level IS
+    'Set on built-in roles, which every user at this level holds';
@@ -0,0 +1,68 @@
+use axum::{
+    extract::{Query, State},
+    routing::get,
+    Json, Router,
+};
+use axum_extra::extract::CookieJar;
+use diesel::{insert_into, prelude::*};
+use diesel_async::RunQueryDsl;
+use serde::Deserialize;
+
+use crate::{
+    http_error::HttpError,
+    model::{AuditLogEntry, NewAuditLogEntry},
+    user::{AuditorFromParts, UserFromParts},
+    AppState, PgConn, PgPool,
+};
+
+const PAGE_SIZE: i64 = 100;
+
+pub fn router() -> Router<AppState> {
+    Router::new().route("/", get(get_audit_log))
+}
+
+/// Add an entry to the audit log
+pub async fn record(
+    entry: NewAuditLogEntry<'_>,
+    conn: &mut PgConn<'_>,
+) -> Result<(), diesel::result::Error> {
+    insert_into(crate::schema::audit_log::table)
+        .values(&entry)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct AuditLogQuery {
+    user_id: Option<i32>,
+    /// Only return entries older than this id, for paging
+    before: Option<i32>,
+}
+
+/// Newest entries first
+#[axum_macros::debug_handler(state = AppState)]
+async fn get_audit_log(
+    AuditorFromParts(UserFromParts { jar, .. }): AuditorFromParts,
+    State(pool): State<PgPool>,
+    Query(query): Query<AuditLogQuery>,
+) -> Result<(CookieJar, Json<Vec<AuditLogEntry>>), HttpError> {
+    use crate::schema::audit_log::dsl::*;
+
+    let conn = &mut pool.get().await?;
+
+    let mut entries = audit_log.into_boxed();
+    if let Some(user_id_) = query.user_id {
+        entries = entries.filter(user_id.eq(user_id_));
+    }
+    if let Some(before) = query.before {
+        entries = entries.filter(id.lt(before));
+    }
+    let entries = entries
+        .order(id.desc())
+        .limit(PAGE_SIZE)
+        .load::<AuditLogEntry>(conn)
+        .await?;
+
+    Ok((jar, Json(entries)))
+}
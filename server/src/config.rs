@@ -1,41 +1,339 @@
 use std::{
     collections::HashMap,
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     path::{Path, PathBuf},
     sync::Arc,
 };
 
 use axum::{extract::State, Json};
 use color_eyre::{eyre::eyre, Result};
-use oauth2::{AuthUrl, ClientId, ClientSecret, TokenUrl};
+use oauth2::{AuthUrl, ClientId, ClientSecret, RevocationUrl, Scope, TokenUrl};
 use openidconnect::IssuerUrl;
-use serde::{Deserialize, Serialize};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
 use tokio::sync::RwLock;
 use typeshare::typeshare;
 
-use crate::{model::OAuthProvision, AppState};
+use crate::{
+    model::{OAuthProvision, PermissionLevel},
+    AppState,
+};
 
 #[derive(Deserialize)]
 pub struct Config {
     pub database_url: String,
     pub bind_address: SocketAddr,
+    /// Proxies whose `X-Forwarded-For` header is believed, e.g. the frontend
+    /// server when it forwards `/api` requests. Client addresses are used
+    /// for rate limits and shown with sessions.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
     /// For OAuth redirects; should include http/https schema
     pub base_url: String,
     #[serde(default)]
     pub allow_signups: bool,
     #[serde(default)]
     pub redirect_to_first_oauth_provider: bool,
-    pub integrations: HashMap<String, Provider>,
+    pub integrations: HashMap<String, Integration>,
+    /// SAML 2.0 identity providers by name
+    #[serde(default)]
+    pub saml: HashMap<String, SamlProvider>,
+    /// Needed to store OAuth tokens and TOTP secrets
+    pub token_encryption: Option<TokenEncryption>,
+    pub email: Option<Email>,
+    /// Base64-encoded key for signing emailed links. Without one, a random key
+    /// is generated and links stop working on restart.
+    pub link_signing_key: Option<String>,
+    /// What users can't do until they verify their email address
+    #[serde(default)]
+    pub require_verified_email: VerifiedEmailRequirement,
+    /// Keep users with admin permissions who signed in with a password out
+    /// of admin routes unless they also used two-factor authentication
+    #[serde(default)]
+    pub require_admin_2fa: bool,
+    #[serde(default)]
+    pub passwords: PasswordPolicy,
+    #[serde(default)]
+    pub passkeys: Passkeys,
+    /// Whether users can sign in with a link sent to their email address
+    #[serde(default)]
+    pub magic_link_login: MagicLinkLogin,
+    /// Omit to turn off the SCIM provisioning endpoints
+    pub scim: Option<Scim>,
+    /// Use `postgres` to keep sessions across restarts or share them between
+    /// instances
+    #[serde(default)]
+    pub session_backend: SessionBackendKind,
     pub live_reloading: bool,
 }
 
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionBackendKind {
+    #[default]
+    Memory,
+    Postgres,
+}
+
+/// Ordered from least to most restrictive
+#[typeshare]
+#[derive(Deserialize, Serialize, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum VerifiedEmailRequirement {
+    #[default]
+    Nothing,
+    Booking,
+    /// Also restricts booking
+    Login,
+}
+
+#[typeshare]
+#[derive(Deserialize, Serialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MagicLinkLogin {
+    #[default]
+    Disabled,
+    Enabled,
+    /// Turns off signing in with a password
+    Exclusive,
+}
+
+/// Rules for new passwords and how they're hashed
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    /// File with one breached password per line, e.g. a list of the most
+    /// common passwords; compared case-insensitively
+    pub breached_list: Option<PathBuf>,
+    /// Argon2id costs for new hashes. Existing hashes are upgraded when their
+    /// owners next sign in.
+    pub argon2_memory_kib: u32,
+    pub argon2_iterations: u32,
+    pub argon2_parallelism: u32,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        // Argon2id costs follow OWASP's minimum recommendation
+        Self {
+            min_length: 8,
+            breached_list: None,
+            argon2_memory_kib: 19 * 1024,
+            argon2_iterations: 2,
+            argon2_parallelism: 1,
+        }
+    }
+}
+
+/// Lets an identity provider manage users and groups through SCIM 2.0
+#[derive(Deserialize)]
+pub struct Scim {
+    /// Hex-encoded SHA-256 of the bearer token the identity provider sends,
+    /// e.g. from `echo -n "$TOKEN" | sha256sum`
+    pub token_sha256: String,
+}
+
+/// WebAuthn relying party settings. Both default to values derived from
+/// `base_url`; override them when the browser sees a different origin (e.g. a
+/// test authenticator talking to the server directly).
+#[derive(Deserialize, Default)]
+pub struct Passkeys {
+    /// Usually the domain, e.g. `example.com`
+    pub rp_id: Option<String>,
+    pub origin: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct Email {
+    /// Sender mailbox, e.g. `Sceideal <noreply@example.com>`
+    pub from: String,
+    pub smtp_host: String,
+    pub smtp_port: Option<u16>,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TokenEncryption {
+    /// Id of the key new tokens are encrypted with
+    pub current_key: String,
+    /// Base64-encoded 256-bit AES keys by id; keep old keys here until
+    /// `sceideal reencrypt-tokens` has been run
+    pub keys: HashMap<String, String>,
+}
+
+/// An entry in `integrations`, picked by its `type`: `ldap` for an LDAP
+/// directory, `oauth` for an OAuth or OpenID Connect provider. Entries
+/// without a `type` are OAuth providers, as they were before LDAP was added.
+pub enum Integration {
+    Ldap(LdapProvider),
+    OAuth(Provider),
+}
+
+impl<'de> Deserialize<'de> for Integration {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut entry = toml::Table::deserialize(deserializer)?;
+        let kind = match entry.remove("type") {
+            Some(toml::Value::String(kind)) => kind,
+            Some(_) => return Err(D::Error::custom("`type` must be a string")),
+            None => "oauth".to_string(),
+        };
+
+        let entry = toml::Value::Table(entry);
+        match kind.as_str() {
+            "oauth" => entry.try_into().map(Integration::OAuth),
+            "ldap" => entry.try_into().map(Integration::Ldap),
+            _ => return Err(D::Error::unknown_variant(&kind, &["oauth", "ldap"])),
+        }
+        .map_err(D::Error::custom)
+    }
+}
+
 #[derive(Deserialize)]
 pub struct Provider {
     pub client_id: ClientId,
     pub client_secret: Option<ClientSecret>,
     #[serde(flatten)]
     pub urls: ProviderUrls,
+    /// RFC 7009 endpoint used to revoke tokens when a connection is removed
+    pub revocation_url: Option<RevocationUrl>,
     pub provides: Vec<OAuthProvision>,
+    /// Scopes to request for each provision, e.g. calendar scopes for `calendar`
+    #[serde(default)]
+    pub scopes: HashMap<OAuthProvision, Vec<String>>,
+    /// Only used for OpenID Connect login
+    pub claim_mappings: Option<ClaimMappings>,
+    /// Profile fields to overwrite with the provider's values on every login
+    #[serde(default)]
+    pub sync_profile: Vec<ProfileField>,
+}
+
+/// An LDAP directory users sign in to by binding as themselves
+#[derive(Deserialize, Clone)]
+pub struct LdapProvider {
+    /// e.g. `ldap://localhost:389` or `ldaps://ldap.example.com`
+    pub ldap_url: String,
+    /// Upgrade `ldap://` connections with StartTLS
+    #[serde(default)]
+    pub starttls: bool,
+    /// DN to bind as, with `{username}` replaced by the escaped username, e.g.
+    /// `uid={username},ou=people,dc=example,dc=org`
+    pub bind_dn: String,
+    #[serde(default)]
+    pub attributes: LdapAttributes,
+    /// Link existing accounts with the same email address as the directory
+    /// entry. Only turn this on if users can't change their own `mail`.
+    #[serde(default)]
+    pub trust_email: bool,
+    /// Maps the values of an attribute, usually `memberOf` group DNs, to
+    /// permission levels and groups
+    pub claim_mappings: Option<ClaimMappings>,
+    /// Profile fields to overwrite with the directory's values on every login
+    #[serde(default)]
+    pub sync_profile: Vec<ProfileField>,
+}
+
+/// Attributes of the user's entry that hold profile fields
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct LdapAttributes {
+    pub email: String,
+    pub fname: String,
+    pub lname: String,
+    pub phone_number: Option<String>,
+}
+
+impl Default for LdapAttributes {
+    fn default() -> Self {
+        Self {
+            email: "mail".to_string(),
+            fname: "givenName".to_string(),
+            lname: "sn".to_string(),
+            phone_number: Some("telephoneNumber".to_string()),
+        }
+    }
+}
+
+/// A SAML identity provider. Its metadata is loaded on startup from a URL or
+/// a file, whichever is given.
+#[derive(Deserialize)]
+pub struct SamlProvider {
+    pub idp_metadata_url: Option<String>,
+    pub idp_metadata_path: Option<PathBuf>,
+    /// Defaults to the URL of this provider's SP metadata
+    pub entity_id: Option<String>,
+    /// PEM files for the SP's signing key pair
+    pub certificate_path: PathBuf,
+    pub private_key_path: PathBuf,
+    #[serde(default)]
+    pub attributes: SamlAttributes,
+}
+
+/// Names (or friendly names) of the assertion attributes that hold profile
+/// fields. Defaults to the usual OIDs from eduPerson and inetOrgPerson.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct SamlAttributes {
+    /// Falls back to the NameID if it's an email address
+    pub email: String,
+    pub fname: String,
+    pub lname: String,
+    pub phone_number: Option<String>,
+}
+
+impl Default for SamlAttributes {
+    fn default() -> Self {
+        Self {
+            email: "urn:oid:0.9.2342.19200300.100.1.3".to_string(),
+            fname: "urn:oid:2.5.4.42".to_string(),
+            lname: "urn:oid:2.5.4.4".to_string(),
+            phone_number: None,
+        }
+    }
+}
+
+/// User profile fields that can be kept in sync with an OpenID Connect
+/// provider
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProfileField {
+    Name,
+    PhoneNumber,
+    ProfileImage,
+}
+
+/// Maps the values of an ID token claim (or an LDAP attribute) to permission
+/// levels and groups. Applied on sign-up and on every login.
+#[derive(Deserialize, Clone)]
+pub struct ClaimMappings {
+    /// Claim holding a list of roles or groups, e.g. `groups` or
+    /// `realm_access.roles`; for LDAP, an attribute such as `memberOf`
+    pub claim: String,
+    /// Claim value to permission level; the highest match wins, and users
+    /// without a match become students
+    #[serde(default)]
+    pub permission_levels: HashMap<String, PermissionLevel>,
+    /// Claim value to group id
+    #[serde(default)]
+    pub groups: HashMap<String, i32>,
+    /// Remove memberships in mapped groups that are no longer asserted
+    #[serde(default)]
+    pub remove_unasserted_memberships: bool,
+}
+
+impl Provider {
+    /// Scopes to request for a provision. Login asks for `email` and `profile`
+    /// unless configured otherwise, since sign-up needs them.
+    pub fn scopes(&self, provision: OAuthProvision) -> Vec<Scope> {
+        match self.scopes.get(&provision) {
+            Some(scopes) => scopes.iter().cloned().map(Scope::new).collect(),
+            None if provision == OAuthProvision::Auth => vec![
+                Scope::new("email".to_string()),
+                Scope::new("profile".to_string()),
+            ],
+            None => Vec::new(),
+        }
+    }
 }
 
 #[derive(Deserialize, Clone)]
@@ -123,6 +421,30 @@ impl Config {
         let config_str = String::from_utf8(tokio::fs::read(path).await?)?;
         Ok(toml::from_str(&config_str)?)
     }
+
+    /// The OAuth and OpenID Connect entries in `integrations`
+    pub fn oauth_providers(&self) -> impl Iterator<Item = (&String, &Provider)> {
+        self.integrations
+            .iter()
+            .filter_map(|(name, integration)| match integration {
+                Integration::OAuth(provider) => Some((name, provider)),
+                Integration::Ldap(_) => None,
+            })
+    }
+
+    pub fn oauth_provider(&self, name: &str) -> Option<&Provider> {
+        match self.integrations.get(name)? {
+            Integration::OAuth(provider) => Some(provider),
+            Integration::Ldap(_) => None,
+        }
+    }
+
+    pub fn ldap_provider(&self, name: &str) -> Option<&LdapProvider> {
+        match self.integrations.get(name)? {
+            Integration::Ldap(provider) => Some(provider),
+            Integration::OAuth(_) => None,
+        }
+    }
 }
 
 #[typeshare]
@@ -130,13 +452,18 @@ impl Config {
 pub struct PublicConfig {
     pub redirect_to_first_oauth_provider: bool,
     pub oauth_providers: HashMap<OAuthProvision, Vec<String>>,
+    pub saml_providers: Vec<String>,
+    pub ldap_providers: Vec<String>,
+    pub require_verified_email: VerifiedEmailRequirement,
+    pub magic_link_login: MagicLinkLogin,
+    pub min_password_length: usize,
 }
 
 impl From<&Config> for PublicConfig {
     fn from(value: &Config) -> Self {
         let mut oauth_providers: HashMap<OAuthProvision, Vec<String>> = HashMap::new();
 
-        for (provider, data) in value.integrations.iter() {
+        for (provider, data) in value.oauth_providers() {
             for provision in data.provides.iter() {
                 oauth_providers
                     .entry(*provision)
@@ -148,6 +475,16 @@ impl From<&Config> for PublicConfig {
         PublicConfig {
             redirect_to_first_oauth_provider: value.redirect_to_first_oauth_provider,
             oauth_providers,
+            saml_providers: value.saml.keys().cloned().collect(),
+            ldap_providers: value
+                .integrations
+                .iter()
+                .filter(|(_, integration)| matches!(integration, Integration::Ldap(_)))
+                .map(|(name, _)| name.clone())
+                .collect(),
+            require_verified_email: value.require_verified_email,
+            magic_link_login: value.magic_link_login,
+            min_password_length: value.passwords.min_length,
         }
     }
 }
@@ -0,0 +1,327 @@
+use std::{collections::HashMap, sync::Arc};
+
+use aes_gcm::{
+    aead::{Aead, OsRng, Payload},
+    AeadCore, Aes256Gcm, KeyInit, Nonce,
+};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use chrono::Utc;
+use color_eyre::{eyre::eyre, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use thiserror::Error;
+use tracing::warn;
+
+use crate::{config::Config, oauth::impl_oauth_error};
+
+const NONCE_LENGTH: usize = 12; // 96 bits, as used by AES-GCM
+
+#[derive(Debug, Error)]
+pub enum CipherError {
+    #[error("no key with id {0} is configured")]
+    UnknownKey(String),
+    #[error("malformed ciphertext")]
+    Malformed,
+    #[error("decryption failed")]
+    Decryption,
+    #[error("encryption failed")]
+    Encryption,
+    #[error("token encryption isn't configured")]
+    NotConfigured,
+}
+
+impl_oauth_error!(CipherError, "Encryption error: {}");
+
+/// Encrypts secrets (like OAuth tokens) before they are written to the
+/// database
+///
+/// Every ciphertext is stored next to the id of the key that produced it so
+/// that old keys can be retired after everything has been re-encrypted.
+#[derive(Clone)]
+pub struct TokenCipher(Arc<TokenCipherInner>);
+
+struct TokenCipherInner {
+    /// `None` without `token_encryption`, in which case nothing can be
+    /// encrypted
+    current_key_id: Option<String>,
+    keys: HashMap<String, Aes256Gcm>,
+}
+
+impl TokenCipher {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let Some(encryption_config) = &config.token_encryption else {
+            if config.oauth_providers().next().is_some() {
+                return Err(eyre!(
+                    "OAuth integrations store tokens; configure token_encryption"
+                ));
+            }
+            warn!(
+                "No token encryption keys configured; two-factor authentication can't be enabled"
+            );
+            return Ok(Self(Arc::new(TokenCipherInner {
+                current_key_id: None,
+                keys: HashMap::new(),
+            })));
+        };
+
+        Self::new(&encryption_config.current_key, &encryption_config.keys)
+    }
+
+    /// `keys` maps key ids to base64-encoded 256-bit keys
+    pub fn new(current_key: &str, keys: &HashMap<String, String>) -> Result<Self> {
+        let keys = keys
+            .iter()
+            .map(|(id, key)| -> Result<(String, Aes256Gcm)> {
+                let key = STANDARD
+                    .decode(key)
+                    .map_err(|e| eyre!("Token encryption key {id} is not valid base64: {e}"))?;
+                let cipher = Aes256Gcm::new_from_slice(&key)
+                    .map_err(|_| eyre!("Token encryption key {id} must be 32 bytes long"))?;
+                Ok((id.clone(), cipher))
+            })
+            .collect::<Result<HashMap<_, _>>>()?;
+
+        if !keys.contains_key(current_key) {
+            return Err(eyre!(
+                "The current token encryption key ({current_key}) is not in the key list"
+            ));
+        }
+
+        Ok(Self(Arc::new(TokenCipherInner {
+            current_key_id: Some(current_key.to_string()),
+            keys,
+        })))
+    }
+
+    /// Empty if encryption isn't configured, since `encrypt` fails before
+    /// anything is stored under it
+    pub fn current_key_id(&self) -> &str {
+        self.0.current_key_id.as_deref().unwrap_or_default()
+    }
+
+    /// Encrypt with the current key; the result is base64(nonce || ciphertext)
+    pub fn encrypt(&self, plaintext: &str) -> Result<String, CipherError> {
+        let key_id = self
+            .0
+            .current_key_id
+            .as_deref()
+            .ok_or(CipherError::NotConfigured)?;
+        let cipher = &self.0.keys[key_id];
+
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext.as_bytes(),
+                    aad: key_id.as_bytes(),
+                },
+            )
+            .map_err(|_| CipherError::Encryption)?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        Ok(STANDARD.encode(sealed))
+    }
+
+    /// Decrypt a value written under `key_id`. Rows from before encryption was
+    /// introduced have no key id and are passed through as-is.
+    pub fn decrypt(&self, key_id: Option<&str>, sealed: &str) -> Result<String, CipherError> {
+        let Some(key_id) = key_id else {
+            return Ok(sealed.to_string());
+        };
+        let cipher = self
+            .0
+            .keys
+            .get(key_id)
+            .ok_or_else(|| CipherError::UnknownKey(key_id.to_string()))?;
+
+        let sealed = STANDARD
+            .decode(sealed)
+            .map_err(|_| CipherError::Malformed)?;
+        if sealed.len() < NONCE_LENGTH {
+            return Err(CipherError::Malformed);
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+
+        let plaintext = cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: key_id.as_bytes(),
+                },
+            )
+            .map_err(|_| CipherError::Decryption)?;
+
+        String::from_utf8(plaintext).map_err(|_| CipherError::Malformed)
+    }
+}
+
+/// Signs links that are sent by email, so they can be checked later without
+/// being stored
+#[derive(Clone)]
+pub struct LinkSigner(Arc<Hmac<Sha256>>);
+
+impl LinkSigner {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let key = match &config.link_signing_key {
+            Some(key) => STANDARD
+                .decode(key)
+                .map_err(|e| eyre!("The link signing key is not valid base64: {e}"))?,
+            None => {
+                warn!("No link signing key configured; emailed links will stop working on restart");
+                rand::random::<[u8; 32]>().to_vec()
+            }
+        };
+        let mac = Hmac::<Sha256>::new_from_slice(&key)
+            .map_err(|_| eyre!("The link signing key has an invalid length"))?;
+
+        Ok(Self(Arc::new(mac)))
+    }
+
+    fn signature(&self, message: &str, expires: i64) -> Hmac<Sha256> {
+        let mut mac = Hmac::clone(&self.0);
+        mac.update(format!("{expires}.{message}").as_bytes());
+        mac
+    }
+
+    /// Sign `message` for `ttl` seconds. The result is `{expires}.{signature}`
+    /// and is safe to put in a URL.
+    pub fn sign(&self, message: &str, ttl: i64) -> String {
+        let expires = Utc::now().timestamp() + ttl;
+        let signature = self.signature(message, expires).finalize().into_bytes();
+        format!("{expires}.{}", URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    /// Check that `token` was made by `sign` for `message` and hasn't expired
+    pub fn verify(&self, message: &str, token: &str) -> bool {
+        let Some((expires, signature)) = token.split_once('.') else {
+            return false;
+        };
+        let (Ok(expires), Ok(signature)) =
+            (expires.parse::<i64>(), URL_SAFE_NO_PAD.decode(signature))
+        else {
+            return false;
+        };
+
+        expires > Utc::now().timestamp()
+            && self
+                .signature(message, expires)
+                .verify_slice(&signature)
+                .is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> String {
+        STANDARD.encode(rand::random::<[u8; 32]>())
+    }
+
+    fn cipher(current_key: &str, keys: &[(&str, &str)]) -> TokenCipher {
+        let keys = keys
+            .iter()
+            .map(|(id, key)| (id.to_string(), key.to_string()))
+            .collect();
+        TokenCipher::new(current_key, &keys).unwrap()
+    }
+
+    #[test]
+    fn round_trips_with_the_current_key() {
+        let cipher = cipher("2023-06", &[("2023-06", &key())]);
+
+        let sealed = cipher.encrypt("access token").unwrap();
+        assert_ne!(sealed, "access token");
+        assert_eq!(cipher.current_key_id(), "2023-06");
+        assert_eq!(
+            cipher.decrypt(Some("2023-06"), &sealed).unwrap(),
+            "access token"
+        );
+    }
+
+    #[test]
+    fn nonces_are_not_reused() {
+        let cipher = cipher("2023-06", &[("2023-06", &key())]);
+        assert_ne!(
+            cipher.encrypt("access token").unwrap(),
+            cipher.encrypt("access token").unwrap()
+        );
+    }
+
+    #[test]
+    fn old_keys_still_decrypt_after_rotation() {
+        let (old_key, new_key) = (key(), key());
+        let sealed = cipher("2023-06", &[("2023-06", &old_key)])
+            .encrypt("access token")
+            .unwrap();
+
+        let rotated = cipher("2023-07", &[("2023-06", &old_key), ("2023-07", &new_key)]);
+        assert_eq!(rotated.current_key_id(), "2023-07");
+        assert_eq!(
+            rotated.decrypt(Some("2023-06"), &sealed).unwrap(),
+            "access token"
+        );
+    }
+
+    #[test]
+    fn removed_keys_are_reported() {
+        let sealed = cipher("2023-06", &[("2023-06", &key())])
+            .encrypt("access token")
+            .unwrap();
+
+        let rotated = cipher("2023-07", &[("2023-07", &key())]);
+        assert!(matches!(
+            rotated.decrypt(Some("2023-06"), &sealed),
+            Err(CipherError::UnknownKey(id)) if id == "2023-06"
+        ));
+    }
+
+    #[test]
+    fn ciphertexts_are_bound_to_their_key_id() {
+        // The key id is authenticated, so relabelling a row fails even when
+        // both ids hold the same key
+        let shared_key = key();
+        let cipher = cipher("a", &[("a", &shared_key), ("b", &shared_key)]);
+        let sealed = cipher.encrypt("access token").unwrap();
+        assert!(matches!(
+            cipher.decrypt(Some("b"), &sealed),
+            Err(CipherError::Decryption)
+        ));
+    }
+
+    #[test]
+    fn rows_without_a_key_id_are_plaintext() {
+        let cipher = cipher("2023-06", &[("2023-06", &key())]);
+        assert_eq!(
+            cipher.decrypt(None, "access token").unwrap(),
+            "access token"
+        );
+    }
+
+    #[test]
+    fn malformed_ciphertexts_are_rejected() {
+        let cipher = cipher("2023-06", &[("2023-06", &key())]);
+        assert!(matches!(
+            cipher.decrypt(Some("2023-06"), "not base64!"),
+            Err(CipherError::Malformed)
+        ));
+        assert!(matches!(
+            cipher.decrypt(Some("2023-06"), &STANDARD.encode([0; 4])),
+            Err(CipherError::Malformed)
+        ));
+    }
+
+    #[test]
+    fn keys_are_validated() {
+        let keys = |key: &str| HashMap::from([("2023-06".to_string(), key.to_string())]);
+        assert!(TokenCipher::new("2023-06", &keys(&STANDARD.encode([0; 16]))).is_err());
+        assert!(TokenCipher::new("2023-06", &keys("not base64!")).is_err());
+        assert!(TokenCipher::new("2023-07", &keys(&key())).is_err());
+    }
+}
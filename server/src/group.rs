@@ -9,22 +9,26 @@ use diesel_async::RunQueryDsl;
 
 use crate::{
     http_error::HttpError,
-    model::{CreateGroup, Group, IsMemberOf, UpdateGroup, User},
+    model::{CreateGroup, Group, IsMemberOf, Permission, UpdateGroup, User},
     schema,
-    user::{session::SessionStore, AdminFromParts, PublicUserData, UserFromParts},
+    user::{
+        session::SessionStore, GroupManagerFromParts, MemberManagerFromParts, PublicUserData,
+        UserFromParts,
+    },
     AppState, PgPool,
 };
 
 pub fn router() -> Router<AppState> {
     Router::new()
         .route("/", get(get_all_groups))
+        .route("/managed", get(get_managed_groups))
         .route("/:id", get(get_group))
         .route("/a", get(get_all_groups_admin).post(create_group))
         .route(
-            "/a/:id",
+            "/a/:group_id",
             get(get_group_admin).put(update_group).delete(delete_group),
         )
-        .route("/a/:id/members", get(get_group_members))
+        .route("/a/:group_id/members", get(get_group_members))
 }
 
 #[axum_macros::debug_handler(state = AppState)]
@@ -40,7 +44,7 @@ async fn get_all_groups(
     let group_dsl = groups.filter(public.eq(true)).select(Group::as_select());
 
     let groups_ = if let Some(user) = User::from_jar(&jar, &session, conn).await? {
-        jar = session.reup(jar).await;
+        jar = session.reup(jar).await?;
         group_dsl
             .union(
                 IsMemberOf::belonging_to(&user)
@@ -83,7 +87,7 @@ async fn get_group(
     let user = User::from_jar(&jar, &session, conn)
         .await?
         .ok_or(HttpError::forbidden("No user found"))?;
-    jar = session.reup(jar).await;
+    jar = session.reup(jar).await?;
 
     // Check membership
     is_member_of
@@ -96,9 +100,40 @@ async fn get_group(
     Ok((jar, Json(group)))
 }
 
+/// Groups whose members the user can manage
+#[axum_macros::debug_handler(state = AppState)]
+async fn get_managed_groups(
+    UserFromParts { user, jar }: UserFromParts,
+    State(pool): State<PgPool>,
+) -> Result<(CookieJar, Json<Vec<Group>>), HttpError> {
+    use crate::schema::groups::dsl::*;
+    use crate::schema::{is_member_of, roles};
+
+    let conn = &mut pool.get().await?;
+
+    let groups_ = if user
+        .permissions(None, conn)
+        .await?
+        .contains(&Permission::ManageMembers)
+    {
+        groups.select(Group::as_select()).load(conn).await?
+    } else {
+        is_member_of::table
+            .inner_join(groups)
+            .inner_join(roles::table)
+            .filter(is_member_of::user_id.eq(user.id))
+            .filter(roles::permissions.contains(vec![Permission::ManageMembers]))
+            .select(Group::as_select())
+            .load(conn)
+            .await?
+    };
+
+    Ok((jar, Json(groups_)))
+}
+
 #[axum_macros::debug_handler(state = AppState)]
 async fn get_all_groups_admin(
-    AdminFromParts(UserFromParts { jar, .. }): AdminFromParts,
+    GroupManagerFromParts(UserFromParts { jar, .. }): GroupManagerFromParts,
     State(pool): State<PgPool>,
 ) -> Result<(CookieJar, Json<Vec<Group>>), HttpError> {
     use crate::schema::groups::dsl::*;
@@ -112,7 +147,7 @@ async fn get_all_groups_admin(
 
 #[axum_macros::debug_handler(state = AppState)]
 async fn get_group_admin(
-    AdminFromParts(UserFromParts { jar, .. }): AdminFromParts,
+    GroupManagerFromParts(UserFromParts { jar, .. }): GroupManagerFromParts,
     State(pool): State<PgPool>,
     Path(id_): Path<i32>,
 ) -> Result<(CookieJar, Json<Group>), HttpError> {
@@ -133,7 +168,7 @@ async fn get_group_admin(
 
 #[axum_macros::debug_handler(state = AppState)]
 async fn create_group(
-    AdminFromParts(UserFromParts { jar, .. }): AdminFromParts,
+    GroupManagerFromParts(UserFromParts { jar, .. }): GroupManagerFromParts,
     State(pool): State<PgPool>,
     Json(group_data): Json<CreateGroup>,
 ) -> Result<(CookieJar, String), HttpError> {
@@ -152,7 +187,7 @@ async fn create_group(
 
 #[axum_macros::debug_handler(state = AppState)]
 async fn update_group(
-    AdminFromParts(UserFromParts { jar, .. }): AdminFromParts,
+    GroupManagerFromParts(UserFromParts { jar, .. }): GroupManagerFromParts,
     State(pool): State<PgPool>,
     Path(id_): Path<i32>,
     Json(group_data): Json<UpdateGroup>,
@@ -171,7 +206,7 @@ async fn update_group(
 
 #[axum_macros::debug_handler(state = AppState)]
 async fn delete_group(
-    AdminFromParts(UserFromParts { jar, .. }): AdminFromParts,
+    GroupManagerFromParts(UserFromParts { jar, .. }): GroupManagerFromParts,
     State(pool): State<PgPool>,
     Path(id_): Path<i32>,
 ) -> Result<CookieJar, HttpError> {
@@ -186,7 +221,7 @@ async fn delete_group(
 
 #[axum_macros::debug_handler(state = AppState)]
 async fn get_group_members(
-    AdminFromParts(UserFromParts { jar, .. }): AdminFromParts,
+    MemberManagerFromParts(UserFromParts { jar, .. }): MemberManagerFromParts,
     State(pool): State<PgPool>,
     Path(id_): Path<i32>,
 ) -> Result<
@@ -7,10 +7,22 @@ use axum::{
 use color_eyre::Report;
 use tracing::error;
 
+use crate::integrations::tokens::TokenError;
+
 #[derive(Debug)]
 pub enum HttpError {
-    WithCode { code: StatusCode, msg: &'static str },
-    Internal { err: String },
+    WithCode {
+        code: StatusCode,
+        msg: &'static str,
+    },
+    /// 429 with a `Retry-After` header, in seconds
+    RetryAfter {
+        msg: &'static str,
+        seconds: u64,
+    },
+    Internal {
+        err: String,
+    },
 }
 
 macro_rules! constructor {
@@ -23,9 +35,15 @@ macro_rules! constructor {
 
 impl HttpError {
     constructor!(internal, StatusCode::INTERNAL_SERVER_ERROR);
+    constructor!(unauthorized, StatusCode::UNAUTHORIZED);
     constructor!(forbidden, StatusCode::FORBIDDEN);
-    // constructor!(bad_request, StatusCode::BAD_REQUEST);
+    constructor!(bad_request, StatusCode::BAD_REQUEST);
     constructor!(not_found, StatusCode::NOT_FOUND);
+    constructor!(too_many_requests, StatusCode::TOO_MANY_REQUESTS);
+
+    pub fn retry_after(msg: &'static str, seconds: u64) -> Self {
+        HttpError::RetryAfter { msg, seconds }
+    }
 
     fn from_report(err: Report) -> Self {
         error!("HTTP handler error: {}", err);
@@ -50,11 +68,36 @@ impl_from!(color_eyre::Report);
 impl_from!(diesel::result::Error);
 impl_from!(diesel_async::pooled_connection::bb8::RunError);
 impl_from!(bcrypt::BcryptError);
+impl_from!(serde_json::Error);
+impl_from!(ldap3::LdapError);
+impl_from!(crate::crypto::CipherError);
+impl_from!(crate::mail::MailError);
+impl_from!(crate::user::password::PasswordError);
+impl_from!(crate::user::session::SessionError);
+
+impl From<TokenError> for HttpError {
+    fn from(err: TokenError) -> Self {
+        match err {
+            TokenError::NotFound => HttpError::not_found("no connection found"),
+            TokenError::NeedsReauthorization => HttpError::WithCode {
+                code: StatusCode::UNAUTHORIZED,
+                msg: "connection needs to be reauthorized",
+            },
+            err => Self::from_report(err.into()),
+        }
+    }
+}
 
 impl IntoResponse for HttpError {
     fn into_response(self) -> Response {
         match self {
             HttpError::WithCode { code, msg } => (code, msg).into_response(),
+            HttpError::RetryAfter { msg, seconds } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, seconds.to_string())],
+                msg,
+            )
+                .into_response(),
             HttpError::Internal { err } => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 [(header::CONTENT_TYPE, "text/plain")],
@@ -1,3 +1,5 @@
+pub mod tokens;
+
 pub trait LocationProvider {}
 
 pub trait CalendarProvider {}
@@ -0,0 +1,383 @@
+use std::{error::Error, time::Duration};
+
+use chrono::Local;
+use diesel::{prelude::*, update};
+use diesel_async::RunQueryDsl;
+use oauth2::{
+    AccessToken, ConfigurationError, ErrorResponse, RefreshToken, RequestTokenError,
+    StandardRevocableToken, TokenResponse, TokenType,
+};
+use thiserror::Error;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+use crate::{
+    crypto::{CipherError, TokenCipher},
+    model::{OAuthConnection, OAuthProvision, TotpLogin},
+    oauth::OAuthClients,
+    user::openid_connect::OpenIdClients,
+    PgConn, PgPool,
+};
+
+const REFRESH_MARGIN: i64 = 300; // 5 minutes in seconds
+const REFRESH_INTERVAL: u64 = 60; // 1 minute in seconds
+
+#[derive(Debug, Error)]
+pub enum TokenError {
+    #[error("no connection found")]
+    NotFound,
+    #[error("connection needs to be reauthorized")]
+    NeedsReauthorization,
+    #[error("no client configured for provider {0}")]
+    UnknownProvider(String),
+    #[error("provider rejected the refresh token: {0}")]
+    Rejected(String),
+    #[error("provider error: {0}")]
+    Provider(String),
+    #[error("encryption error: {0}")]
+    Cipher(#[from] CipherError),
+    #[error("database error: {0}")]
+    Database(#[from] diesel::result::Error),
+    #[error("pool error: {0}")]
+    Pool(#[from] diesel_async::pooled_connection::bb8::RunError),
+}
+
+impl<RE: Error + 'static, T: ErrorResponse + 'static> From<RequestTokenError<RE, T>>
+    for TokenError
+{
+    fn from(value: RequestTokenError<RE, T>) -> Self {
+        match value {
+            // The provider answered, but refused the grant (revoked, expired, etc.)
+            RequestTokenError::ServerResponse(_) => Self::Rejected(value.to_string()),
+            _ => Self::Provider(value.to_string()),
+        }
+    }
+}
+
+struct RefreshedToken {
+    access_token: AccessToken,
+    expires_in: Option<Duration>,
+    refresh_token: Option<RefreshToken>,
+}
+
+impl RefreshedToken {
+    fn from_response<TT: TokenType>(response: &impl TokenResponse<TT>) -> Self {
+        Self {
+            access_token: response.access_token().clone(),
+            expires_in: response.expires_in(),
+            refresh_token: response.refresh_token().cloned(),
+        }
+    }
+}
+
+/// Keeps the access tokens in `oauth_connections` fresh
+#[derive(Clone)]
+pub struct TokenManager {
+    pool: PgPool,
+    oauth_clients: OAuthClients,
+    openid_clients: OpenIdClients,
+    cipher: TokenCipher,
+}
+
+impl TokenManager {
+    pub fn new(
+        pool: PgPool,
+        oauth_clients: OAuthClients,
+        openid_clients: OpenIdClients,
+        cipher: TokenCipher,
+    ) -> Self {
+        Self {
+            pool,
+            oauth_clients,
+            openid_clients,
+            cipher,
+        }
+    }
+
+    pub fn spawn_refresh_thread(&self) -> JoinHandle<()> {
+        let manager = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(REFRESH_INTERVAL));
+            loop {
+                interval.tick().await;
+                if let Err(e) = manager.refresh_expiring().await {
+                    warn!("Failed to refresh OAuth tokens: {e}");
+                }
+            }
+        })
+    }
+
+    /// Get a valid access token for a connection, refreshing it first if it
+    /// is about to expire
+    pub async fn get_access_token(
+        &self,
+        user_id_: i32,
+        provider_: &str,
+        provides_: OAuthProvision,
+    ) -> Result<AccessToken, TokenError> {
+        use crate::schema::oauth_connections::dsl::*;
+
+        let conn = &mut self.pool.get().await?;
+
+        let connection: OAuthConnection = oauth_connections
+            .find((user_id_, provider_, provides_))
+            .first(conn)
+            .await
+            .optional()?
+            .ok_or(TokenError::NotFound)?;
+
+        if connection.needs_reauth {
+            return Err(TokenError::NeedsReauthorization);
+        }
+
+        let expiring = connection
+            .access_token_expires
+            .map(|expires| expires < refresh_threshold())
+            .unwrap_or(false);
+        if expiring {
+            self.refresh(conn, &connection).await
+        } else {
+            Ok(AccessToken::new(self.cipher.decrypt(
+                connection.token_key_id.as_deref(),
+                &connection.access_token,
+            )?))
+        }
+    }
+
+    /// Revoke a connection's tokens at the provider, if it has a revocation
+    /// endpoint
+    pub async fn revoke(&self, connection: &OAuthConnection) -> Result<(), TokenError> {
+        let key_id = connection.token_key_id.as_deref();
+
+        // Revoking the refresh token ends the whole grant at most providers
+        let token = match &connection.refresh_token {
+            Some(token) => StandardRevocableToken::RefreshToken(RefreshToken::new(
+                self.cipher.decrypt(key_id, token)?,
+            )),
+            None => StandardRevocableToken::AccessToken(AccessToken::new(
+                self.cipher.decrypt(key_id, &connection.access_token)?,
+            )),
+        };
+
+        // Providers without a revocation URL produce a configuration error
+        if let Some(client) = self.oauth_clients.get(&connection.provider).await {
+            match client.revoke_token(token) {
+                Ok(request) => {
+                    request
+                        .request_async(oauth2::reqwest::async_http_client)
+                        .await?
+                }
+                Err(ConfigurationError::MissingUrl(_)) => {}
+                Err(e) => return Err(TokenError::Provider(e.to_string())),
+            }
+        } else if let Some(client_record) = self.openid_clients.get(&connection.provider).await {
+            match client_record.client.revoke_token(token) {
+                Ok(request) => {
+                    request
+                        .request_async(openidconnect::reqwest::async_http_client)
+                        .await?
+                }
+                Err(ConfigurationError::MissingUrl(_)) => {}
+                Err(e) => return Err(TokenError::Provider(e.to_string())),
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn refresh_expiring(&self) -> Result<(), TokenError> {
+        use crate::schema::oauth_connections::dsl::*;
+
+        let conn = &mut self.pool.get().await?;
+
+        // Auth connections only exist to sign in; nothing uses their tokens afterwards
+        let expiring: Vec<OAuthConnection> = oauth_connections
+            .filter(provides.ne(OAuthProvision::Auth))
+            .filter(needs_reauth.eq(false))
+            .filter(access_token_expires.lt(refresh_threshold()))
+            .load(conn)
+            .await?;
+
+        for connection in expiring {
+            if let Err(e) = self.refresh(conn, &connection).await {
+                warn!(
+                    "Failed to refresh {} token for user {}: {e}",
+                    connection.provider, connection.user_id
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn refresh(
+        &self,
+        conn: &mut PgConn<'_>,
+        connection: &OAuthConnection,
+    ) -> Result<AccessToken, TokenError> {
+        use crate::schema::oauth_connections::dsl::*;
+
+        let connection_row = oauth_connections.find((
+            connection.user_id,
+            &connection.provider,
+            connection.provides,
+        ));
+
+        let refreshed = match &connection.refresh_token {
+            Some(token) => {
+                let token = self
+                    .cipher
+                    .decrypt(connection.token_key_id.as_deref(), token)?;
+                self.request_refresh(&connection.provider, &RefreshToken::new(token))
+                    .await
+            }
+            None => Err(TokenError::Rejected("no refresh token".to_string())),
+        };
+
+        let refreshed = match refreshed {
+            Ok(refreshed) => refreshed,
+            Err(TokenError::Rejected(e)) => {
+                debug!(
+                    "Marking {} connection for user {} as needing reauthorization: {e}",
+                    connection.provider, connection.user_id
+                );
+                update(connection_row)
+                    .set(needs_reauth.eq(true))
+                    .execute(conn)
+                    .await?;
+                return Err(TokenError::NeedsReauthorization);
+            }
+            Err(e) => return Err(e),
+        };
+
+        let access_token_expires_ = refreshed.expires_in.and_then(|d| {
+            let chrono_duration = chrono::Duration::from_std(d).ok()?;
+            Some(Local::now().naive_local() + chrono_duration)
+        });
+        // Providers that don't rotate refresh tokens leave it out of the response
+        let refresh_token_ = match refreshed.refresh_token {
+            Some(token) => Some(token.secret().clone()),
+            None => connection
+                .refresh_token
+                .as_ref()
+                .map(|t| self.cipher.decrypt(connection.token_key_id.as_deref(), t))
+                .transpose()?,
+        };
+
+        update(connection_row)
+            .set((
+                access_token.eq(self.cipher.encrypt(refreshed.access_token.secret())?),
+                access_token_expires.eq(access_token_expires_),
+                refresh_token.eq(refresh_token_
+                    .map(|t| self.cipher.encrypt(&t))
+                    .transpose()?),
+                token_key_id.eq(self.cipher.current_key_id()),
+            ))
+            .execute(conn)
+            .await?;
+
+        Ok(refreshed.access_token)
+    }
+
+    async fn request_refresh(
+        &self,
+        provider_: &str,
+        token: &RefreshToken,
+    ) -> Result<RefreshedToken, TokenError> {
+        if let Some(client) = self.oauth_clients.get(provider_).await {
+            let response = client
+                .exchange_refresh_token(token)
+                .request_async(oauth2::reqwest::async_http_client)
+                .await?;
+            Ok(RefreshedToken::from_response(&response))
+        } else if let Some(client_record) = self.openid_clients.get(provider_).await {
+            let response = client_record
+                .client
+                .exchange_refresh_token(token)
+                .request_async(openidconnect::reqwest::async_http_client)
+                .await?;
+            Ok(RefreshedToken::from_response(&response))
+        } else {
+            Err(TokenError::UnknownProvider(provider_.to_string()))
+        }
+    }
+}
+
+/// Re-encrypt every connection and TOTP secret that isn't encrypted with the
+/// current key (including plaintext rows from before tokens were encrypted)
+pub async fn reencrypt_tokens(pool: &PgPool, cipher: &TokenCipher) -> Result<usize, TokenError> {
+    let conn = &mut pool.get().await?;
+
+    Ok(reencrypt_connections(cipher, conn).await? + reencrypt_totp_secrets(cipher, conn).await?)
+}
+
+async fn reencrypt_connections(
+    cipher: &TokenCipher,
+    conn: &mut PgConn<'_>,
+) -> Result<usize, TokenError> {
+    use crate::schema::oauth_connections::dsl::*;
+
+    let stale: Vec<OAuthConnection> = oauth_connections
+        .filter(
+            token_key_id
+                .is_null()
+                .or(token_key_id.ne(cipher.current_key_id())),
+        )
+        .load(conn)
+        .await?;
+
+    for connection in stale.iter() {
+        let old_key_id = connection.token_key_id.as_deref();
+        let access_token_ = cipher.decrypt(old_key_id, &connection.access_token)?;
+        let refresh_token_ = connection
+            .refresh_token
+            .as_ref()
+            .map(|t| cipher.decrypt(old_key_id, t))
+            .transpose()?;
+
+        update(oauth_connections.find((
+            connection.user_id,
+            &connection.provider,
+            connection.provides,
+        )))
+        .set((
+            access_token.eq(cipher.encrypt(&access_token_)?),
+            refresh_token.eq(refresh_token_.map(|t| cipher.encrypt(&t)).transpose()?),
+            token_key_id.eq(cipher.current_key_id()),
+        ))
+        .execute(conn)
+        .await?;
+    }
+
+    Ok(stale.len())
+}
+
+async fn reencrypt_totp_secrets(
+    cipher: &TokenCipher,
+    conn: &mut PgConn<'_>,
+) -> Result<usize, TokenError> {
+    use crate::schema::totp_logins::dsl::*;
+
+    let stale: Vec<TotpLogin> = totp_logins
+        .filter(secret_key_id.ne(cipher.current_key_id()))
+        .load(conn)
+        .await?;
+
+    for totp_login in stale.iter() {
+        let secret_ = cipher.decrypt(Some(&totp_login.secret_key_id), &totp_login.secret)?;
+
+        update(totp_logins.find(totp_login.user_id))
+            .set((
+                secret.eq(cipher.encrypt(&secret_)?),
+                secret_key_id.eq(cipher.current_key_id()),
+            ))
+            .execute(conn)
+            .await?;
+    }
+
+    Ok(stale.len())
+}
+
+fn refresh_threshold() -> chrono::NaiveDateTime {
+    Local::now().naive_local() + chrono::Duration::seconds(REFRESH_MARGIN)
+}
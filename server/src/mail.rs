@@ -0,0 +1,92 @@
+use std::sync::Arc;
+
+use color_eyre::{eyre::eyre, Result};
+use lettre::{
+    address::AddressError, message::Mailbox, transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use thiserror::Error;
+use tracing::{info, warn};
+
+use crate::{config::Config, oauth::impl_oauth_error};
+
+#[derive(Debug, Error)]
+pub enum MailError {
+    #[error("invalid address: {0}")]
+    Address(#[from] AddressError),
+    #[error("could not build message: {0}")]
+    Message(#[from] lettre::error::Error),
+    #[error("could not send message: {0}")]
+    Smtp(#[from] lettre::transport::smtp::Error),
+}
+
+impl_oauth_error!(MailError, "Email error: {}");
+
+/// Sends transactional emails (sign-in links, verification, etc.)
+///
+/// Without an `[email]` section in the config, messages are logged instead so
+/// that development setups don't need an SMTP server.
+#[derive(Clone)]
+pub struct Mailer(Option<Arc<SmtpMailer>>);
+
+struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl Mailer {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let Some(email_config) = &config.email else {
+            warn!("No email configuration found; emails will be logged instead of sent");
+            return Ok(Self(None));
+        };
+
+        let mut builder =
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&email_config.smtp_host)?;
+        if let Some(port) = email_config.smtp_port {
+            builder = builder.port(port);
+        }
+        if let (Some(username), Some(password)) =
+            (&email_config.smtp_username, &email_config.smtp_password)
+        {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        let from = email_config
+            .from
+            .parse()
+            .map_err(|e| eyre!("Invalid sender address {}: {e}", email_config.from))?;
+
+        Ok(Self(Some(Arc::new(SmtpMailer {
+            transport: builder.build(),
+            from,
+        }))))
+    }
+
+    pub async fn send(&self, to: &str, subject: &str, body: String) -> Result<(), MailError> {
+        let Some(mailer) = &self.0 else {
+            info!("Email to {to} ({subject}):\n{body}");
+            return Ok(());
+        };
+
+        let message = Message::builder()
+            .from(mailer.from.clone())
+            .to(to.parse()?)
+            .subject(subject)
+            .body(body)?;
+        mailer.transport.send(message).await?;
+
+        Ok(())
+    }
+
+    /// Send without waiting, logging failures. For emails that shouldn't hold
+    /// up or fail the request that triggers them.
+    pub fn send_in_background(&self, to: String, subject: &'static str, body: String) {
+        let mailer = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = mailer.send(&to, subject, body).await {
+                warn!("Failed to send \"{subject}\" email: {e}");
+            }
+        });
+    }
+}
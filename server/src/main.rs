@@ -1,30 +1,48 @@
+use std::net::SocketAddr;
+
 use axum::routing::get;
 use axum::{Router, Server};
 use axum_macros::FromRef;
 use color_eyre::eyre::Context;
 use color_eyre::Result;
 use config::{Config, StatefulConfig};
+use crypto::{LinkSigner, TokenCipher};
 use diesel::Connection;
 use diesel_async::pooled_connection::bb8::{Pool, PooledConnection};
 use diesel_async::pooled_connection::AsyncDieselConnectionManager;
 use diesel_async::AsyncPgConnection;
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use integrations::tokens::{reencrypt_tokens, TokenManager};
+use mail::Mailer;
 use oauth::{CsrfCache, OAuthClients};
 use tracing::{info, Level};
 use tracing_subscriber::FmtSubscriber;
+use user::account_link::PendingLinkCache;
+use user::lockout::LoginAttemptLimits;
+use user::magic_link::MagicLinkLimits;
 use user::openid_connect::{CsrfNonceCache, OpenIdClients};
+use user::passkey::PasskeyAuth;
+use user::password::Passwords;
+use user::saml::{SamlProviders, SamlRequestCache};
 use user::session::SessionStore;
+use user::totp::PendingTotpLoginCache;
 
 use crate::config::get_config;
 
+mod audit;
 mod config;
+mod crypto;
 mod group;
 mod http_error;
 mod integrations;
 mod locations;
+mod mail;
 mod model;
 mod oauth;
+mod rate_limit;
+mod role;
 mod schema;
+mod scim;
 mod user;
 mod utils;
 
@@ -42,6 +60,18 @@ pub struct AppState {
     c_cache: CsrfCache,
     openid_clients: OpenIdClients,
     cn_cache: CsrfNonceCache,
+    saml_providers: SamlProviders,
+    saml_requests: SamlRequestCache,
+    token_manager: TokenManager,
+    cipher: TokenCipher,
+    links: PendingLinkCache,
+    pending_totp_logins: PendingTotpLoginCache,
+    passkey_auth: PasskeyAuth,
+    magic_link_limits: MagicLinkLimits,
+    login_attempt_limits: LoginAttemptLimits,
+    passwords: Passwords,
+    mailer: Mailer,
+    signer: LinkSigner,
 }
 
 #[tokio::main]
@@ -73,8 +103,21 @@ pub async fn main() -> Result<()> {
         .await
         .wrap_err("could not create database connection pool")?;
 
+    // Load token encryption keys
+    let cipher = TokenCipher::from_config(&*config.read().await)?;
+
+    // `sceideal reencrypt-tokens` moves every stored OAuth token and TOTP secret to the
+    // current key
+    if std::env::args().nth(1).as_deref() == Some("reencrypt-tokens") {
+        let count = reencrypt_tokens(&pool, &cipher)
+            .await
+            .wrap_err("could not re-encrypt stored secrets")?;
+        info!("Re-encrypted {count} OAuth connections and TOTP secrets");
+        return Ok(());
+    }
+
     // Create session store
-    let session_store = SessionStore::new();
+    let session_store = SessionStore::from_config(&*config.read().await, &pool);
     let session_monitor = session_store.spawn_monitor_thread();
 
     // Create csrf cache
@@ -85,10 +128,54 @@ pub async fn main() -> Result<()> {
     let cn_cache = CsrfNonceCache::new();
     let cn_monitor = cn_cache.spawn_monitor_thread();
 
+    // Create pending SAML request cache
+    let saml_requests = SamlRequestCache::new();
+    let saml_requests_monitor = saml_requests.spawn_monitor_thread();
+
+    // Create pending account link cache
+    let links = PendingLinkCache::new();
+    let links_monitor = links.spawn_monitor_thread();
+
+    // Create pending two-factor login cache
+    let pending_totp_logins = PendingTotpLoginCache::new();
+    let pending_totp_monitor = pending_totp_logins.spawn_monitor_thread();
+
+    // Set up passkeys
+    let passkey_auth = PasskeyAuth::from_config(&*config.read().await)?;
+    let passkey_monitor = passkey_auth.spawn_monitor_thread();
+
+    // Create magic link rate limits
+    let magic_link_limits = MagicLinkLimits::new();
+    let magic_link_monitor = magic_link_limits.spawn_monitor_thread();
+
+    // Track failed logins by IP address
+    let login_attempt_limits = LoginAttemptLimits::new();
+    let login_attempt_monitor = login_attempt_limits.spawn_monitor_thread();
+
+    // Set up password hashing and policy
+    let passwords = Passwords::from_config(&*config.read().await)?;
+
+    // Create mailer
+    let mailer = Mailer::from_config(&*config.read().await)?;
+
+    // Load link signing key
+    let signer = LinkSigner::from_config(&*config.read().await)?;
+
     // App state and other things
     let addr = config.read().await.bind_address;
     let oauth_clients = OAuthClients::from_config(&*config.read().await).await;
     let openid_clients = OpenIdClients::from_config(&*config.read().await).await;
+    let saml_providers = SamlProviders::from_config(&*config.read().await).await;
+
+    // Create token manager
+    let token_manager = TokenManager::new(
+        pool.clone(),
+        oauth_clients.clone(),
+        openid_clients.clone(),
+        cipher.clone(),
+    );
+    let token_monitor = token_manager.spawn_refresh_thread();
+
     let state = AppState {
         pool,
         session_store,
@@ -97,6 +184,18 @@ pub async fn main() -> Result<()> {
         c_cache,
         openid_clients,
         cn_cache,
+        saml_providers,
+        saml_requests,
+        token_manager,
+        cipher,
+        links,
+        pending_totp_logins,
+        passkey_auth,
+        magic_link_limits,
+        login_attempt_limits,
+        passwords,
+        mailer,
+        signer,
     };
 
     // Build routes
@@ -105,12 +204,19 @@ pub async fn main() -> Result<()> {
         .nest("/oauth", oauth::router())
         .nest("/location", locations::router())
         .nest("/group", group::router())
+        .nest("/role", role::router())
+        .nest("/audit", audit::router())
+        .nest("/scim/v2", scim::router())
         .route("/config", get(get_config))
         .with_state(state);
 
     info!("Listening on {:?}", addr);
     Server::bind(&addr)
-        .serve(Router::new().nest("/api", app).into_make_service())
+        .serve(
+            Router::new()
+                .nest("/api", app)
+                .into_make_service_with_connect_info::<SocketAddr>(),
+        )
         .await
         .unwrap();
 
@@ -118,6 +224,13 @@ pub async fn main() -> Result<()> {
     session_monitor.abort();
     c_cache_monitor.abort();
     cn_monitor.abort();
+    saml_requests_monitor.abort();
+    token_monitor.abort();
+    links_monitor.abort();
+    pending_totp_monitor.abort();
+    passkey_monitor.abort();
+    magic_link_monitor.abort();
+    login_attempt_monitor.abort();
 
     Ok(())
 }
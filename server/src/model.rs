@@ -8,8 +8,12 @@ use typeshare::typeshare;
 use crate::schema::*;
 use crate::utils::some_option;
 
+/// Which built-in role a user holds. Levels grant nothing themselves; access
+/// is always checked against role permissions, so editing a built-in role
+/// changes what everyone at its level can do. Ordered from least to most
+/// privileged.
 #[typeshare]
-#[derive(Debug, Clone, Copy, PartialEq, Eq, DbEnum, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, DbEnum, Serialize, Deserialize)]
 #[ExistingTypePath = "crate::schema::sql_types::PermissionLevel"]
 pub enum PermissionLevel {
     Student,
@@ -17,6 +21,34 @@ pub enum PermissionLevel {
     Admin,
 }
 
+/// Something a role allows its holders to do
+#[typeshare]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, DbEnum, Serialize, Deserialize)]
+#[ExistingTypePath = "crate::schema::sql_types::Permission"]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    ManageUsers,
+    ManageGroups,
+    /// Add and remove a group's members and assign their teachers
+    ManageMembers,
+    ManageTopics,
+    /// Host meetings at their own locations
+    ManageLocations,
+    /// Create roles and assign them
+    ManageRoles,
+    ViewReports,
+    ViewAuditLog,
+    ImpersonateUsers,
+}
+
+impl Permission {
+    /// Whether the permission's routes are admin routes, which need an API
+    /// token's admin scope and are subject to `require_admin_2fa`
+    pub fn is_admin(self) -> bool {
+        !matches!(self, Permission::ManageLocations)
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Queryable, Identifiable, Selectable)]
 pub struct User {
     pub id: i32,
@@ -27,10 +59,14 @@ pub struct User {
     pub lname: String,
     pub bio: Option<String>,
     pub profile_image: Option<String>,
+    /// Only selects the user's built-in role; see [`User::permissions`]
     pub permission_level: PermissionLevel,
     pub joined_on: NaiveDateTime,
     pub updated_at: NaiveDateTime,
     pub last_login: Option<NaiveDateTime>,
+    pub pending_email: Option<String>,
+    pub active: bool,
+    pub external_id: Option<String>,
 }
 
 #[derive(Insertable)]
@@ -73,6 +109,29 @@ pub struct UpdateUser {
     pub bio: Option<Option<String>>,
 }
 
+/// The user fields a SCIM client manages
+#[derive(AsChangeset)]
+#[diesel(table_name = users)]
+pub struct ScimUpdateUser {
+    pub email: String,
+    pub email_verified: Option<bool>,
+    pub phone_number: Option<Option<String>>,
+    pub fname: String,
+    pub lname: String,
+    pub active: bool,
+    pub external_id: Option<Option<String>>,
+}
+
+/// Profile fields copied from an identity provider; `None` leaves a field as is
+#[derive(AsChangeset)]
+#[diesel(table_name = users)]
+pub struct SyncProfile {
+    pub phone_number: Option<String>,
+    pub fname: Option<String>,
+    pub lname: Option<String>,
+    pub profile_image: Option<String>,
+}
+
 #[derive(
     Clone, Debug, PartialEq, Eq, Deserialize, Queryable, Identifiable, Selectable, Associations,
 )]
@@ -82,6 +141,8 @@ pub struct LocalLogin {
     pub user_id: i32,
     pub hash: String,
     pub updated_at: NaiveDateTime,
+    pub failed_attempts: i32,
+    pub locked_until: Option<NaiveDateTime>,
 }
 
 #[derive(Insertable)]
@@ -91,6 +152,183 @@ pub struct NewLocalLogin<'a> {
     pub hash: &'a str,
 }
 
+#[derive(Debug, PartialEq, Eq, Queryable, Identifiable, Selectable, Associations)]
+#[diesel(belongs_to(User), table_name = passkeys)]
+pub struct PasskeyCredential {
+    pub id: i32,
+    pub user_id: i32,
+    pub credential_id: String,
+    pub user_handle: String,
+    pub name: String,
+    pub passkey: String,
+    pub sign_count: i64,
+    pub created_on: NaiveDateTime,
+    pub last_used: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = passkeys)]
+pub struct NewPasskey<'a> {
+    pub user_id: i32,
+    pub credential_id: &'a str,
+    pub user_handle: &'a str,
+    pub name: &'a str,
+    pub passkey: &'a str,
+    pub sign_count: i64,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = password_reset_tokens)]
+pub struct NewPasswordResetToken<'a> {
+    pub token_hash: &'a str,
+    pub user_id: i32,
+    pub expires: NaiveDateTime,
+}
+
+#[derive(Debug, PartialEq, Eq, Queryable, Identifiable, Selectable, Associations)]
+#[diesel(belongs_to(User), primary_key(provider, name_id))]
+pub struct SamlIdentity {
+    pub provider: String,
+    pub name_id: String,
+    pub user_id: i32,
+    pub created_on: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = saml_identities)]
+pub struct NewSamlIdentity<'a> {
+    pub provider: &'a str,
+    pub name_id: &'a str,
+    pub user_id: i32,
+}
+
+#[derive(Debug, PartialEq, Eq, Queryable, Identifiable, Selectable, Associations)]
+#[diesel(belongs_to(User), primary_key(provider, dn))]
+pub struct LdapIdentity {
+    pub provider: String,
+    pub dn: String,
+    pub user_id: i32,
+    pub created_on: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = ldap_identities)]
+pub struct NewLdapIdentity<'a> {
+    pub provider: &'a str,
+    pub dn: &'a str,
+    pub user_id: i32,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = magic_link_tokens)]
+pub struct NewMagicLinkToken<'a> {
+    pub token_hash: &'a str,
+    pub user_id: i32,
+    pub expires: NaiveDateTime,
+}
+
+/// What an API token can be used for. Every scope can read.
+#[typeshare]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DbEnum, Serialize, Deserialize)]
+#[ExistingTypePath = "crate::schema::sql_types::ApiTokenScope"]
+#[serde(rename_all = "snake_case")]
+pub enum ApiTokenScope {
+    Read,
+    /// Make changes outside of account settings, like locations and groups
+    Appointments,
+    /// Use admin routes
+    Admin,
+}
+
+#[derive(Debug, PartialEq, Eq, Queryable, Identifiable, Selectable, Associations)]
+#[diesel(belongs_to(User))]
+pub struct ApiToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    pub token_hash: String,
+    pub scopes: Vec<ApiTokenScope>,
+    pub expires: NaiveDateTime,
+    pub created_on: NaiveDateTime,
+    pub last_used: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = api_tokens)]
+pub struct NewApiToken<'a> {
+    pub user_id: i32,
+    pub name: &'a str,
+    pub token_hash: &'a str,
+    pub scopes: &'a [ApiTokenScope],
+    pub expires: NaiveDateTime,
+}
+
+#[typeshare]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DbEnum, Serialize, Deserialize)]
+#[ExistingTypePath = "crate::schema::sql_types::AuditEvent"]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEvent {
+    AccountLocked,
+    AccountUnlocked,
+    ImpersonationStarted,
+    ImpersonationStopped,
+    /// A change made by an admin while impersonating the user
+    ImpersonatedAction,
+}
+
+#[typeshare]
+#[derive(Debug, Serialize, Queryable, Identifiable, Selectable)]
+#[diesel(table_name = audit_log)]
+pub struct AuditLogEntry {
+    pub id: i32,
+    pub event: AuditEvent,
+    pub user_id: Option<i32>,
+    pub actor_id: Option<i32>,
+    pub ip_address: Option<String>,
+    pub details: Option<String>,
+    #[typeshare(serialized_as = "String")]
+    pub created_on: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = audit_log)]
+pub struct NewAuditLogEntry<'a> {
+    pub event: AuditEvent,
+    pub user_id: Option<i32>,
+    pub actor_id: Option<i32>,
+    pub ip_address: Option<&'a str>,
+    pub details: Option<&'a str>,
+}
+
+#[derive(Debug, PartialEq, Eq, Queryable, Identifiable, Selectable, Associations)]
+#[diesel(belongs_to(User), primary_key(user_id))]
+pub struct TotpLogin {
+    pub user_id: i32,
+    pub secret: String,
+    pub secret_key_id: String,
+    pub confirmed: bool,
+    pub last_used_step: Option<i64>,
+    pub created_on: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub failed_attempts: i32,
+    pub locked_until: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = totp_logins)]
+pub struct NewTotpLogin<'a> {
+    pub user_id: i32,
+    pub secret: &'a str,
+    pub secret_key_id: &'a str,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = recovery_codes)]
+pub struct NewRecoveryCode<'a> {
+    pub user_id: i32,
+    pub code_hash: &'a str,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Queryable)]
 pub struct NonUser {
     pub id: i32,
@@ -196,6 +434,8 @@ pub struct OAuthConnection {
     pub oid_subject: Option<String>,
     pub created_on: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    pub needs_reauth: bool,
+    pub token_key_id: Option<String>,
 }
 
 #[derive(Insertable)]
@@ -209,6 +449,7 @@ pub struct NewOAuthConnection<'a> {
     pub refresh_token: Option<&'a str>,
     pub refresh_token_expires: Option<&'a NaiveDateTime>,
     pub oid_subject: Option<&'a str>,
+    pub token_key_id: Option<&'a str>,
 }
 
 #[typeshare]
@@ -263,6 +504,7 @@ pub struct IsMemberOf {
     assigned_teacher: Option<i32>,
     updated_at: NaiveDateTime,
     joined_on: NaiveDateTime,
+    pub role_id: Option<i32>,
 }
 
 #[derive(Insertable)]
@@ -279,3 +521,110 @@ pub struct CreateIsMemberOf {
 pub struct UpdateIsMemberOf {
     pub assigned_teacher: Option<i32>,
 }
+
+#[typeshare]
+#[derive(Debug, PartialEq, Eq, Queryable, Identifiable, Selectable, Serialize)]
+pub struct Role {
+    pub id: i32,
+    pub name: String,
+    pub description: Option<String>,
+    pub permissions: Vec<Permission>,
+    /// Set for the built-in roles, which every user at the level holds
+    pub permission_level: Option<PermissionLevel>,
+    #[typeshare(serialized_as = "String")]
+    pub created_on: NaiveDateTime,
+    #[typeshare(serialized_as = "String")]
+    pub updated_at: NaiveDateTime,
+}
+
+#[typeshare]
+#[derive(Deserialize, Insertable)]
+#[diesel(table_name = roles)]
+pub struct CreateRole {
+    pub name: String,
+    pub description: Option<String>,
+    pub permissions: Vec<Permission>,
+}
+
+#[typeshare]
+#[derive(AsChangeset, Deserialize)]
+#[diesel(table_name = roles)]
+pub struct UpdateRole {
+    pub name: Option<String>,
+    #[serde(default, deserialize_with = "some_option")]
+    pub description: Option<Option<String>>,
+    pub permissions: Option<Vec<Permission>>,
+}
+
+#[derive(Debug, PartialEq, Eq, Queryable, Identifiable, Selectable, Associations)]
+#[diesel(
+    table_name = user_roles,
+    belongs_to(User),
+    belongs_to(Role),
+    primary_key(user_id, role_id)
+)]
+pub struct UserRole {
+    pub user_id: i32,
+    pub role_id: i32,
+    pub created_on: NaiveDateTime,
+}
+
+#[typeshare]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DbEnum, Serialize, Deserialize)]
+#[ExistingTypePath = "crate::schema::sql_types::LoginMethod"]
+#[serde(rename_all = "snake_case")]
+pub enum LoginMethod {
+    Password,
+    OpenId,
+    Passkey,
+    MagicLink,
+    Saml,
+    Ldap,
+}
+
+#[derive(Debug, PartialEq, Eq, Queryable, Identifiable, Selectable, Associations)]
+#[diesel(belongs_to(User), table_name = sessions, primary_key(id_hash))]
+pub struct Session {
+    pub id_hash: String,
+    pub user_id: i32,
+    pub rp_logout_providers_with_open_sessions: Vec<String>,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub expires: NaiveDateTime,
+    pub created_on: NaiveDateTime,
+    pub login_method: LoginMethod,
+    pub login_provider: Option<String>,
+    pub last_seen: NaiveDateTime,
+    pub two_factor: bool,
+    pub impersonator_id: Option<i32>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = sessions)]
+pub struct NewSession<'a> {
+    pub id_hash: &'a str,
+    pub user_id: i32,
+    pub rp_logout_providers_with_open_sessions: &'a [String],
+    pub user_agent: Option<&'a str>,
+    pub ip_address: Option<&'a str>,
+    pub expires: NaiveDateTime,
+    pub created_on: NaiveDateTime,
+    pub login_method: LoginMethod,
+    pub login_provider: Option<&'a str>,
+    pub last_seen: NaiveDateTime,
+    pub two_factor: bool,
+    pub impersonator_id: Option<i32>,
+}
+
+#[derive(Debug, PartialEq, Eq, Queryable, Identifiable, Selectable, Associations, Insertable)]
+#[diesel(
+    table_name = session_provider_sessions,
+    belongs_to(Session, foreign_key = session_id_hash),
+    primary_key(session_id_hash, provider, subject)
+)]
+pub struct SessionProviderSession {
+    pub session_id_hash: String,
+    pub provider: String,
+    pub subject: String,
+    pub sid: Option<String>,
+}
@@ -9,12 +9,12 @@ use axum::{
 };
 use axum_extra::extract::CookieJar;
 use chrono::Local;
-use diesel::insert_into;
+use diesel::{insert_into, prelude::*, upsert::excluded};
 use diesel_async::RunQueryDsl;
 use futures::{stream::FuturesUnordered, StreamExt};
 use oauth2::{
-    basic::BasicClient, reqwest::async_http_client, AuthorizationCode, CsrfToken, RedirectUrl,
-    TokenResponse,
+    basic::BasicClient, reqwest::async_http_client, AuthorizationCode, CsrfToken,
+    PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, TokenResponse,
 };
 use retainer::Cache;
 use serde::{Deserialize, Serialize};
@@ -23,11 +23,11 @@ use tracing::warn;
 use typeshare::typeshare;
 
 use crate::{
-    config::{Config, ProviderUrls},
+    config::{Config, ProviderUrls, StatefulConfig},
+    crypto::TokenCipher,
     model::{NewOAuthConnection, OAuthProvision},
-    schema::oauth_connections,
     user::UserFromParts,
-    AppState, PgPool,
+    AppState, PgConn, PgPool,
 };
 
 pub fn router() -> Router<AppState> {
@@ -42,8 +42,7 @@ pub struct OAuthClients(Arc<HashMap<String, BasicClient>>);
 impl OAuthClients {
     pub async fn from_config(config: &Config) -> Self {
         let futures: FuturesUnordered<_> = config
-            .integrations
-            .iter()
+            .oauth_providers()
             .filter(|(_, p)| p.urls.is_oauth_only())
             .map(|(k, v)| async move {
                 let client_id = v.client_id.clone();
@@ -68,8 +67,12 @@ impl OAuthClients {
                         }
                     };
 
-                let client = BasicClient::new(client_id, client_secret, auth_url, Some(token_url))
-                    .set_redirect_uri(redirect_url);
+                let mut client =
+                    BasicClient::new(client_id, client_secret, auth_url, Some(token_url))
+                        .set_redirect_uri(redirect_url);
+                if let Some(revocation_url) = v.revocation_url.clone() {
+                    client = client.set_revocation_uri(revocation_url);
+                }
 
                 Some((k.clone(), client))
             })
@@ -88,6 +91,30 @@ impl OAuthClients {
     }
 }
 
+impl NewOAuthConnection<'_> {
+    /// Insert the connection, replacing the tokens of an existing connection
+    /// (e.g. one that had to be reauthorized)
+    pub async fn upsert(&self, conn: &mut PgConn<'_>) -> Result<usize, diesel::result::Error> {
+        use crate::schema::oauth_connections::dsl::*;
+
+        insert_into(oauth_connections)
+            .values(self)
+            .on_conflict((user_id, provider, provides))
+            .do_update()
+            .set((
+                access_token.eq(excluded(access_token)),
+                access_token_expires.eq(excluded(access_token_expires)),
+                refresh_token.eq(excluded(refresh_token)),
+                refresh_token_expires.eq(excluded(refresh_token_expires)),
+                oid_subject.eq(excluded(oid_subject)),
+                token_key_id.eq(excluded(token_key_id)),
+                needs_reauth.eq(false),
+            ))
+            .execute(conn)
+            .await
+    }
+}
+
 #[derive(Clone)]
 pub struct CachableCsrfToken(pub CsrfToken);
 
@@ -114,6 +141,7 @@ impl Ord for CachableCsrfToken {
 struct CsrfCacheRecord {
     user_id: i32,
     provides: OAuthProvision,
+    pkce_verifier: PkceCodeVerifier,
 }
 
 #[derive(Clone)]
@@ -146,6 +174,7 @@ const CSRF_TIMEOUT: u64 = 3600; // 1 hour in seconds
 async fn get_oauth_url(
     State(oauth_clients): State<OAuthClients>,
     State(c_cache): State<CsrfCache>,
+    State(config): State<StatefulConfig>,
     Path((provider_, provides_)): Path<(String, OAuthProvision)>,
     UserFromParts { user, jar }: UserFromParts,
 ) -> Result<(CookieJar, String), StatusCode> {
@@ -157,10 +186,19 @@ async fn get_oauth_url(
         .get(&provider_)
         .await
         .ok_or(StatusCode::NOT_FOUND)?;
+    let scopes = config
+        .read()
+        .await
+        .oauth_provider(&provider_)
+        .ok_or(StatusCode::NOT_FOUND)?
+        .scopes(provides_);
 
+    // PKCE protects the code exchange, even for public clients without a secret
+    let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
     let (authorize_url, csrf_state) = client
         .authorize_url(CsrfToken::new_random)
-        // .add_scopes(config.read().await.integrations.get(&provider_).provides)
+        .add_scopes(scopes)
+        .set_pkce_challenge(pkce_challenge)
         .url();
 
     c_cache
@@ -169,6 +207,7 @@ async fn get_oauth_url(
             CsrfCacheRecord {
                 user_id: user.id,
                 provides: provides_,
+                pkce_verifier,
             },
         )
         .await;
@@ -274,6 +313,7 @@ async fn oauth_callback(
     State(oauth_clients): State<OAuthClients>,
     State(c_cache): State<CsrfCache>,
     State(pool): State<PgPool>,
+    State(cipher): State<TokenCipher>,
     UserFromParts { user, jar }: UserFromParts,
 ) -> Result<(CookieJar, OAuthRedirect), OAuthError> {
     let CodeStatePair { code, state } = pair;
@@ -295,6 +335,7 @@ async fn oauth_callback(
 
     let token_response = client
         .exchange_code(code)
+        .set_pkce_verifier(csrf_record.pkce_verifier)
         .request_async(async_http_client)
         .await?;
 
@@ -305,20 +346,23 @@ async fn oauth_callback(
         let chrono_duration = chrono::Duration::from_std(d).ok()?;
         Some(Local::now().naive_local() + chrono_duration)
     });
+    let access_token = cipher.encrypt(token_response.access_token().secret())?;
+    let refresh_token = token_response
+        .refresh_token()
+        .map(|t| cipher.encrypt(t.secret()))
+        .transpose()?;
     let new_oauth_login = &NewOAuthConnection {
         user_id: user.id,
         provider: &provider_,
         provides: &csrf_record.provides,
-        access_token: token_response.access_token().secret(),
+        access_token: &access_token,
         access_token_expires: access_token_expires.as_ref(),
-        refresh_token: token_response.refresh_token().map(|t| t.secret().as_str()),
+        refresh_token: refresh_token.as_deref(),
         refresh_token_expires: None,
         oid_subject: None,
+        token_key_id: Some(cipher.current_key_id()),
     };
-    insert_into(oauth_connections::table)
-        .values(new_oauth_login)
-        .execute(conn)
-        .await?;
+    new_oauth_login.upsert(conn).await?;
 
     Ok((jar, OAuthRedirect))
 }
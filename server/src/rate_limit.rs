@@ -0,0 +1,162 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::{sync::Mutex, task::JoinHandle};
+
+/// Hits in the current window and when it ends
+type Window = (u32, Instant);
+
+/// Allows up to `max` hits per key in a fixed window that starts with the
+/// first hit
+#[derive(Clone)]
+pub struct RateLimiter {
+    // Every read and update happens under the lock, so concurrent requests
+    // can't both take the last hit
+    hits: Arc<Mutex<HashMap<String, Window>>>,
+    max: u32,
+    window: Duration,
+}
+
+impl RateLimiter {
+    pub fn new(max: u32, window: Duration) -> Self {
+        Self {
+            hits: Arc::new(Mutex::new(HashMap::new())),
+            max,
+            window,
+        }
+    }
+
+    pub fn spawn_monitor_thread(&self) -> JoinHandle<()> {
+        let limiter = self.clone();
+        tokio::spawn(async move { limiter.monitor().await })
+    }
+
+    /// Evict expired windows; runs forever
+    pub async fn monitor(&self) {
+        let mut interval = tokio::time::interval(Duration::from_secs(3));
+        loop {
+            interval.tick().await;
+            let now = Instant::now();
+            self.hits.lock().await.retain(|_, (_, ends)| *ends > now);
+        }
+    }
+
+    /// Record a hit, returning false if the key is over its limit
+    pub async fn check(&self, key: String) -> bool {
+        let mut hits = self.hits.lock().await;
+        let (count, _) = self.current_window(&mut hits, key);
+        if *count >= self.max {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    /// Whether the key has used up its hits, without recording one
+    pub async fn is_limited(&self, key: &str) -> bool {
+        let hits = self.hits.lock().await;
+        hits.get(key)
+            .filter(|(_, ends)| *ends > Instant::now())
+            .map_or(false, |(count, _)| *count >= self.max)
+    }
+
+    /// Record a hit, e.g. after a failed attempt
+    pub async fn record(&self, key: String) {
+        let mut hits = self.hits.lock().await;
+        let (count, _) = self.current_window(&mut hits, key);
+        *count += 1;
+    }
+
+    /// The key's window, starting a new one if the last has ended
+    fn current_window<'a>(
+        &self,
+        hits: &'a mut HashMap<String, Window>,
+        key: String,
+    ) -> &'a mut Window {
+        let now = Instant::now();
+        let window = hits.entry(key).or_insert((0, now + self.window));
+        if window.1 <= now {
+            *window = (0, now + self.window);
+        }
+        window
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_millis(100);
+
+    #[tokio::test]
+    async fn allows_up_to_max_hits() {
+        let limiter = RateLimiter::new(3, WINDOW);
+        for _ in 0..3 {
+            assert!(limiter.check("key".to_string()).await);
+        }
+        assert!(!limiter.check("key".to_string()).await);
+        assert!(limiter.is_limited("key").await);
+    }
+
+    #[tokio::test]
+    async fn keys_are_limited_separately() {
+        let limiter = RateLimiter::new(1, WINDOW);
+        assert!(limiter.check("a".to_string()).await);
+        assert!(!limiter.check("a".to_string()).await);
+        assert!(limiter.check("b".to_string()).await);
+    }
+
+    #[tokio::test]
+    async fn rejected_hits_dont_extend_the_window() {
+        let limiter = RateLimiter::new(1, WINDOW);
+        assert!(limiter.check("key".to_string()).await);
+        for _ in 0..5 {
+            assert!(!limiter.check("key".to_string()).await);
+        }
+        tokio::time::sleep(WINDOW).await;
+        assert!(limiter.check("key".to_string()).await);
+    }
+
+    #[tokio::test]
+    async fn recorded_hits_count_towards_the_limit() {
+        let limiter = RateLimiter::new(2, WINDOW);
+        assert!(!limiter.is_limited("key").await);
+        limiter.record("key".to_string()).await;
+        limiter.record("key".to_string()).await;
+        assert!(limiter.is_limited("key").await);
+        assert!(!limiter.check("key".to_string()).await);
+    }
+
+    #[tokio::test]
+    async fn windows_end() {
+        let limiter = RateLimiter::new(1, WINDOW);
+        limiter.record("key".to_string()).await;
+        assert!(limiter.is_limited("key").await);
+
+        tokio::time::sleep(WINDOW).await;
+        assert!(!limiter.is_limited("key").await);
+        assert!(limiter.check("key".to_string()).await);
+    }
+
+    #[tokio::test]
+    async fn concurrent_checks_share_the_limit() {
+        let limiter = RateLimiter::new(10, Duration::from_secs(60));
+        let checks: Vec<_> = (0..50)
+            .map(|_| {
+                let limiter = limiter.clone();
+                tokio::spawn(async move { limiter.check("key".to_string()).await })
+            })
+            .collect();
+
+        let mut allowed = 0;
+        for check in checks {
+            if check.await.unwrap() {
+                allowed += 1;
+            }
+        }
+        assert_eq!(allowed, 10);
+    }
+}
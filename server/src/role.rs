@@ -0,0 +1,442 @@
+use std::collections::HashSet;
+
+use axum::{
+    extract::{Path, Query, State},
+    routing::{get, post},
+    Json, Router,
+};
+use axum_extra::extract::CookieJar;
+use diesel::{delete, insert_into, prelude::*, result::DatabaseErrorKind, update};
+use diesel_async::RunQueryDsl;
+use serde::Deserialize;
+
+use crate::{
+    http_error::HttpError,
+    model::{CreateRole, Permission, PermissionLevel, Role, UpdateRole, User, UserRole},
+    user::{PublicUserData, RoleManagerFromParts, UserFromParts},
+    AppState, PgConn, PgPool,
+};
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/", get(get_all_roles).post(create_role))
+        .route("/me", get(get_my_permissions))
+        .route("/:id", get(get_role).put(update_role).delete(delete_role))
+        .route("/:id/members", get(get_role_members))
+        .route(
+            "/:id/members/:user_id",
+            post(assign_role).delete(unassign_role),
+        )
+        .route(
+            "/:id/groups/:group_id/members/:user_id",
+            post(assign_group_role).delete(unassign_group_role),
+        )
+}
+
+impl User {
+    /// Everything the user's roles allow: the built-in role for their
+    /// permission level and any assigned roles. With a group, roles held
+    /// within that group count too.
+    pub async fn permissions(
+        &self,
+        group_id_: Option<i32>,
+        conn: &mut PgConn<'_>,
+    ) -> Result<HashSet<Permission>, diesel::result::Error> {
+        use crate::schema::roles::dsl::*;
+        use crate::schema::{is_member_of, user_roles};
+
+        let global_roles = user_roles::table
+            .filter(user_roles::user_id.eq(self.id))
+            .select(user_roles::role_id);
+        let mut held = roles
+            .select(permissions)
+            .filter(permission_level.eq(self.permission_level))
+            .or_filter(id.eq_any(global_roles))
+            .into_boxed();
+
+        if let Some(group_id_) = group_id_ {
+            let group_roles = is_member_of::table
+                .filter(is_member_of::user_id.eq(self.id))
+                .filter(is_member_of::group_id.eq(group_id_))
+                .select(is_member_of::role_id);
+            held = held.or_filter(id.nullable().eq_any(group_roles));
+        }
+
+        Ok(held
+            .load::<Vec<Permission>>(conn)
+            .await?
+            .into_iter()
+            .flatten()
+            .collect())
+    }
+
+    /// Fail unless the user holds the permission, globally or within the
+    /// group
+    pub async fn require(
+        &self,
+        permission: Permission,
+        group_id_: Option<i32>,
+        conn: &mut PgConn<'_>,
+    ) -> Result<(), HttpError> {
+        if self
+            .permissions(group_id_, conn)
+            .await?
+            .contains(&permission)
+        {
+            Ok(())
+        } else {
+            Err(HttpError::forbidden("insufficient permissions"))
+        }
+    }
+
+    /// Everything the user's roles allow anywhere, including roles held
+    /// within any of their groups
+    pub async fn all_permissions(
+        &self,
+        conn: &mut PgConn<'_>,
+    ) -> Result<HashSet<Permission>, diesel::result::Error> {
+        use crate::schema::{is_member_of, roles};
+
+        let group_permissions: Vec<Vec<Permission>> = is_member_of::table
+            .inner_join(roles::table)
+            .filter(is_member_of::user_id.eq(self.id))
+            .select(roles::permissions)
+            .load(conn)
+            .await?;
+
+        let mut held = self.permissions(None, conn).await?;
+        held.extend(group_permissions.into_iter().flatten());
+        Ok(held)
+    }
+
+    /// Whether the user holds an admin permission anywhere, including within
+    /// a group
+    pub async fn is_staff(&self, conn: &mut PgConn<'_>) -> Result<bool, diesel::result::Error> {
+        Ok(self
+            .all_permissions(conn)
+            .await?
+            .into_iter()
+            .any(Permission::is_admin))
+    }
+}
+
+async fn find_role(id_: i32, conn: &mut PgConn<'_>) -> Result<Role, HttpError> {
+    use crate::schema::roles::dsl::*;
+
+    roles
+        .find(id_)
+        .select(Role::as_select())
+        .first(conn)
+        .await
+        .optional()?
+        .ok_or(HttpError::not_found("role not found"))
+}
+
+/// Managing roles can't be used to hand out permissions the manager doesn't
+/// hold themselves
+pub(crate) async fn require_grantable(
+    user: &User,
+    permissions: &[Permission],
+    conn: &mut PgConn<'_>,
+) -> Result<(), HttpError> {
+    let held = user.permissions(None, conn).await?;
+    if permissions
+        .iter()
+        .all(|permission| held.contains(permission))
+    {
+        Ok(())
+    } else {
+        Err(HttpError::forbidden(
+            "you can't grant permissions you don't have",
+        ))
+    }
+}
+
+/// The built-in role every user at the level holds
+pub(crate) async fn built_in_role(
+    level: PermissionLevel,
+    conn: &mut PgConn<'_>,
+) -> Result<Role, HttpError> {
+    use crate::schema::roles::dsl::*;
+
+    roles
+        .filter(permission_level.eq(level))
+        .select(Role::as_select())
+        .first(conn)
+        .await
+        .optional()?
+        .ok_or(HttpError::internal("built-in role not found"))
+}
+
+fn map_name_conflict(e: diesel::result::Error) -> HttpError {
+    if let diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _) = e {
+        HttpError::bad_request("a role with that name already exists")
+    } else {
+        e.into()
+    }
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn get_all_roles(
+    RoleManagerFromParts(UserFromParts { jar, .. }): RoleManagerFromParts,
+    State(pool): State<PgPool>,
+) -> Result<(CookieJar, Json<Vec<Role>>), HttpError> {
+    use crate::schema::roles::dsl::*;
+
+    let conn = &mut pool.get().await?;
+
+    let roles_ = roles
+        .select(Role::as_select())
+        .order(id.asc())
+        .load(conn)
+        .await?;
+
+    Ok((jar, Json(roles_)))
+}
+
+#[derive(Deserialize)]
+pub struct PermissionsQuery {
+    group_id: Option<i32>,
+}
+
+/// What the signed-in user may do, so the UI can hide what they can't
+#[axum_macros::debug_handler(state = AppState)]
+async fn get_my_permissions(
+    UserFromParts { user, jar }: UserFromParts,
+    State(pool): State<PgPool>,
+    Query(query): Query<PermissionsQuery>,
+) -> Result<(CookieJar, Json<Vec<Permission>>), HttpError> {
+    let conn = &mut pool.get().await?;
+
+    let permissions = user
+        .permissions(query.group_id, conn)
+        .await?
+        .into_iter()
+        .collect();
+
+    Ok((jar, Json(permissions)))
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn get_role(
+    RoleManagerFromParts(UserFromParts { jar, .. }): RoleManagerFromParts,
+    State(pool): State<PgPool>,
+    Path(id_): Path<i32>,
+) -> Result<(CookieJar, Json<Role>), HttpError> {
+    let conn = &mut pool.get().await?;
+
+    let role = find_role(id_, conn).await?;
+
+    Ok((jar, Json(role)))
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn create_role(
+    RoleManagerFromParts(UserFromParts { user, jar }): RoleManagerFromParts,
+    State(pool): State<PgPool>,
+    Json(role_data): Json<CreateRole>,
+) -> Result<(CookieJar, String), HttpError> {
+    use crate::schema::roles::dsl::*;
+
+    if role_data.name.trim().is_empty() {
+        return Err(HttpError::bad_request("roles need a name"));
+    }
+
+    let conn = &mut pool.get().await?;
+
+    require_grantable(&user, &role_data.permissions, conn).await?;
+
+    let id_: i32 = insert_into(roles)
+        .values(role_data)
+        .returning(id)
+        .get_result(conn)
+        .await
+        .map_err(map_name_conflict)?;
+
+    Ok((jar, id_.to_string()))
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn update_role(
+    RoleManagerFromParts(UserFromParts { user, jar }): RoleManagerFromParts,
+    State(pool): State<PgPool>,
+    Path(id_): Path<i32>,
+    Json(role_data): Json<UpdateRole>,
+) -> Result<CookieJar, HttpError> {
+    use crate::schema::roles::dsl::*;
+
+    let conn = &mut pool.get().await?;
+
+    // Admins keep every permission, so nobody can lock everyone out of
+    // managing roles
+    if find_role(id_, conn).await?.permission_level == Some(PermissionLevel::Admin) {
+        return Err(HttpError::forbidden("the admin role can't be changed"));
+    }
+    // Including built-in roles, whose holders gain whatever is added
+    if let Some(permissions_) = &role_data.permissions {
+        require_grantable(&user, permissions_, conn).await?;
+    }
+
+    update(roles.find(id_))
+        .set(role_data)
+        .execute(conn)
+        .await
+        .map_err(map_name_conflict)?;
+
+    Ok(jar)
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn delete_role(
+    RoleManagerFromParts(UserFromParts { jar, .. }): RoleManagerFromParts,
+    State(pool): State<PgPool>,
+    Path(id_): Path<i32>,
+) -> Result<CookieJar, HttpError> {
+    use crate::schema::roles::dsl::*;
+
+    let conn = &mut pool.get().await?;
+
+    if find_role(id_, conn).await?.permission_level.is_some() {
+        return Err(HttpError::forbidden("built-in roles can't be deleted"));
+    }
+
+    delete(roles.find(id_)).execute(conn).await?;
+
+    Ok(jar)
+}
+
+/// Users holding the role globally. Holders of built-in roles are found by
+/// their permission level instead.
+#[axum_macros::debug_handler(state = AppState)]
+async fn get_role_members(
+    RoleManagerFromParts(UserFromParts { jar, .. }): RoleManagerFromParts,
+    State(pool): State<PgPool>,
+    Path(id_): Path<i32>,
+) -> Result<(CookieJar, Json<Vec<PublicUserData>>), HttpError> {
+    use crate::schema::{user_roles, users};
+
+    let conn = &mut pool.get().await?;
+
+    let role = find_role(id_, conn).await?;
+    let members: Vec<(UserRole, User)> = UserRole::belonging_to(&role)
+        .inner_join(users::table)
+        .select((UserRole::as_select(), User::as_select()))
+        .order(user_roles::created_on.asc())
+        .load(conn)
+        .await?;
+
+    let members = members
+        .into_iter()
+        .map(|(_, user)| user.get_public_user_data())
+        .collect();
+
+    Ok((jar, Json(members)))
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn assign_role(
+    RoleManagerFromParts(UserFromParts { user, jar }): RoleManagerFromParts,
+    State(pool): State<PgPool>,
+    Path((id_, user_id_)): Path<(i32, i32)>,
+) -> Result<CookieJar, HttpError> {
+    use crate::schema::user_roles::dsl::*;
+
+    let conn = &mut pool.get().await?;
+
+    let role = find_role(id_, conn).await?;
+    if role.permission_level.is_some() {
+        return Err(HttpError::bad_request(
+            "built-in roles are held through permission levels",
+        ));
+    }
+    require_grantable(&user, &role.permissions, conn).await?;
+
+    insert_into(user_roles)
+        .values((user_id.eq(user_id_), role_id.eq(id_)))
+        .on_conflict_do_nothing()
+        .execute(conn)
+        .await
+        .map_err(|e| {
+            if let diesel::result::Error::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _) =
+                e
+            {
+                HttpError::not_found("user not found")
+            } else {
+                e.into()
+            }
+        })?;
+
+    Ok(jar)
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn unassign_role(
+    RoleManagerFromParts(UserFromParts { jar, .. }): RoleManagerFromParts,
+    State(pool): State<PgPool>,
+    Path((id_, user_id_)): Path<(i32, i32)>,
+) -> Result<CookieJar, HttpError> {
+    use crate::schema::user_roles::dsl::*;
+
+    let conn = &mut pool.get().await?;
+
+    delete(user_roles.find((user_id_, id_)))
+        .execute(conn)
+        .await?;
+
+    Ok(jar)
+}
+
+/// Give a group member the role within the group, replacing the role they
+/// held there
+#[axum_macros::debug_handler(state = AppState)]
+async fn assign_group_role(
+    RoleManagerFromParts(UserFromParts { user, jar }): RoleManagerFromParts,
+    State(pool): State<PgPool>,
+    Path((id_, group_id_, user_id_)): Path<(i32, i32, i32)>,
+) -> Result<CookieJar, HttpError> {
+    use crate::schema::is_member_of::dsl::*;
+
+    let conn = &mut pool.get().await?;
+
+    let role = find_role(id_, conn).await?;
+    if role.permission_level.is_some() {
+        return Err(HttpError::bad_request(
+            "built-in roles are held through permission levels",
+        ));
+    }
+    require_grantable(&user, &role.permissions, conn).await?;
+
+    let updated = update(is_member_of.find((user_id_, group_id_)))
+        .set(role_id.eq(id_))
+        .execute(conn)
+        .await?;
+    if updated == 0 {
+        return Err(HttpError::not_found(
+            "this user isn't a member of the group",
+        ));
+    }
+
+    Ok(jar)
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn unassign_group_role(
+    RoleManagerFromParts(UserFromParts { jar, .. }): RoleManagerFromParts,
+    State(pool): State<PgPool>,
+    Path((id_, group_id_, user_id_)): Path<(i32, i32, i32)>,
+) -> Result<CookieJar, HttpError> {
+    use crate::schema::is_member_of::dsl::*;
+
+    let conn = &mut pool.get().await?;
+
+    update(
+        is_member_of
+            .find((user_id_, group_id_))
+            .filter(role_id.eq(id_)),
+    )
+    .set(role_id.eq(None::<i32>))
+    .execute(conn)
+    .await?;
+
+    Ok(jar)
+}
@@ -1,10 +1,26 @@
 // @generated automatically by Diesel CLI.
 
 pub mod sql_types {
+    #[derive(diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "api_token_scope"))]
+    pub struct ApiTokenScope;
+
+    #[derive(diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "audit_event"))]
+    pub struct AuditEvent;
+
     #[derive(diesel::sql_types::SqlType)]
     #[diesel(postgres_type(name = "location_type"))]
     pub struct LocationType;
 
+    #[derive(diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "login_method"))]
+    pub struct LoginMethod;
+
+    #[derive(diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "permission"))]
+    pub struct Permission;
+
     #[derive(diesel::sql_types::SqlType)]
     #[diesel(postgres_type(name = "permission_level"))]
     pub struct PermissionLevel;
@@ -14,6 +30,22 @@ pub mod sql_types {
     pub struct Provision;
 }
 
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::ApiTokenScope;
+
+    api_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        name -> Text,
+        token_hash -> Text,
+        scopes -> Array<ApiTokenScope>,
+        expires -> Timestamp,
+        created_on -> Timestamp,
+        last_used -> Nullable<Timestamp>,
+    }
+}
+
 diesel::table! {
     appointment_types (id) {
         id -> Int4,
@@ -44,6 +76,21 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::AuditEvent;
+
+    audit_log (id) {
+        id -> Int4,
+        event -> AuditEvent,
+        user_id -> Nullable<Int4>,
+        actor_id -> Nullable<Int4>,
+        ip_address -> Nullable<Text>,
+        details -> Nullable<Text>,
+        created_on -> Timestamp,
+    }
+}
+
 diesel::table! {
     can_teach (user_id, topic_id) {
         user_id -> Int4,
@@ -83,14 +130,26 @@ diesel::table! {
         assigned_teacher -> Nullable<Int4>,
         updated_at -> Timestamp,
         joined_on -> Timestamp,
+        role_id -> Nullable<Int4>,
+    }
+}
+
+diesel::table! {
+    ldap_identities (provider, dn) {
+        provider -> Text,
+        dn -> Text,
+        user_id -> Int4,
+        created_on -> Timestamp,
     }
 }
 
 diesel::table! {
     local_logins (user_id) {
         user_id -> Int4,
-        hash -> Bpchar,
+        hash -> Text,
         updated_at -> Timestamp,
+        failed_attempts -> Int4,
+        locked_until -> Nullable<Timestamp>,
     }
 }
 
@@ -111,6 +170,15 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    magic_link_tokens (token_hash) {
+        token_hash -> Text,
+        user_id -> Int4,
+        expires -> Timestamp,
+        created_on -> Timestamp,
+    }
+}
+
 diesel::table! {
     non_users (id) {
         id -> Int4,
@@ -136,6 +204,31 @@ diesel::table! {
         oid_subject -> Nullable<Text>,
         created_on -> Timestamp,
         updated_at -> Timestamp,
+        needs_reauth -> Bool,
+        token_key_id -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    passkeys (id) {
+        id -> Int4,
+        user_id -> Int4,
+        credential_id -> Text,
+        user_handle -> Text,
+        name -> Text,
+        passkey -> Text,
+        sign_count -> Int8,
+        created_on -> Timestamp,
+        last_used -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    password_reset_tokens (token_hash) {
+        token_hash -> Text,
+        user_id -> Int4,
+        expires -> Timestamp,
+        created_on -> Timestamp,
     }
 }
 
@@ -146,6 +239,67 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    recovery_codes (user_id, code_hash) {
+        user_id -> Int4,
+        code_hash -> Text,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::Permission;
+    use super::sql_types::PermissionLevel;
+
+    roles (id) {
+        id -> Int4,
+        name -> Text,
+        description -> Nullable<Text>,
+        permissions -> Array<Permission>,
+        permission_level -> Nullable<PermissionLevel>,
+        created_on -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    saml_identities (provider, name_id) {
+        provider -> Text,
+        name_id -> Text,
+        user_id -> Int4,
+        created_on -> Timestamp,
+    }
+}
+
+diesel::table! {
+    session_provider_sessions (session_id_hash, provider, subject) {
+        session_id_hash -> Text,
+        provider -> Text,
+        subject -> Text,
+        sid -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::LoginMethod;
+
+    sessions (id_hash) {
+        id_hash -> Text,
+        user_id -> Int4,
+        rp_logout_providers_with_open_sessions -> Array<Text>,
+        user_agent -> Nullable<Text>,
+        ip_address -> Nullable<Text>,
+        expires -> Timestamp,
+        created_on -> Timestamp,
+        login_method -> LoginMethod,
+        login_provider -> Nullable<Text>,
+        last_seen -> Timestamp,
+        two_factor -> Bool,
+        impersonator_id -> Nullable<Int4>,
+    }
+}
+
 diesel::table! {
     topics (id) {
         id -> Int4,
@@ -159,6 +313,20 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    totp_logins (user_id) {
+        user_id -> Int4,
+        secret -> Text,
+        secret_key_id -> Text,
+        confirmed -> Bool,
+        last_used_step -> Nullable<Int8>,
+        created_on -> Timestamp,
+        updated_at -> Timestamp,
+        failed_attempts -> Int4,
+        locked_until -> Nullable<Timestamp>,
+    }
+}
+
 diesel::table! {
     uploads (id) {
         id -> Int4,
@@ -169,6 +337,14 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    user_roles (user_id, role_id) {
+        user_id -> Int4,
+        role_id -> Int4,
+        created_on -> Timestamp,
+    }
+}
+
 diesel::table! {
     use diesel::sql_types::*;
     use super::sql_types::PermissionLevel;
@@ -186,40 +362,69 @@ diesel::table! {
         joined_on -> Timestamp,
         updated_at -> Timestamp,
         last_login -> Nullable<Timestamp>,
+        pending_email -> Nullable<Text>,
+        active -> Bool,
+        external_id -> Nullable<Text>,
     }
 }
 
+diesel::joinable!(api_tokens -> users (user_id));
 diesel::joinable!(appointment_types -> users (user_id));
 diesel::joinable!(appointments -> appointment_types (appointment_type_id));
 diesel::joinable!(appointments -> topics (topic_id));
 diesel::joinable!(appointments -> users (user_id));
+diesel::joinable!(audit_log -> users (user_id));
 diesel::joinable!(can_teach -> topics (topic_id));
 diesel::joinable!(can_teach -> users (user_id));
 diesel::joinable!(is_attending -> appointments (appointment_id));
 diesel::joinable!(is_attending -> non_users (non_user_id));
 diesel::joinable!(is_attending -> users (user_id));
 diesel::joinable!(is_member_of -> groups (group_id));
+diesel::joinable!(is_member_of -> roles (role_id));
+diesel::joinable!(ldap_identities -> users (user_id));
 diesel::joinable!(local_logins -> users (user_id));
 diesel::joinable!(locations -> users (user_id));
+diesel::joinable!(magic_link_tokens -> users (user_id));
 diesel::joinable!(oauth_connections -> users (user_id));
+diesel::joinable!(passkeys -> users (user_id));
+diesel::joinable!(password_reset_tokens -> users (user_id));
 diesel::joinable!(provides_type -> appointment_types (appointment_type_id));
 diesel::joinable!(provides_type -> users (user_id));
+diesel::joinable!(recovery_codes -> users (user_id));
+diesel::joinable!(saml_identities -> users (user_id));
+diesel::joinable!(session_provider_sessions -> sessions (session_id_hash));
 diesel::joinable!(topics -> groups (group_id));
+diesel::joinable!(totp_logins -> users (user_id));
 diesel::joinable!(uploads -> is_attending (is_attending_id));
+diesel::joinable!(user_roles -> roles (role_id));
+diesel::joinable!(user_roles -> users (user_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    api_tokens,
     appointment_types,
     appointments,
+    audit_log,
     can_teach,
     groups,
     is_attending,
     is_member_of,
+    ldap_identities,
     local_logins,
     locations,
+    magic_link_tokens,
     non_users,
     oauth_connections,
+    passkeys,
+    password_reset_tokens,
     provides_type,
+    recovery_codes,
+    roles,
+    saml_identities,
+    session_provider_sessions,
+    sessions,
     topics,
+    totp_logins,
     uploads,
+    user_roles,
     users,
 );
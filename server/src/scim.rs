@@ -0,0 +1,1261 @@
+use std::collections::HashSet;
+
+use async_trait::async_trait;
+use axum::{
+    extract::{FromRequestParts, Path, Query, State},
+    http::{header, request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use chrono::NaiveDateTime;
+use diesel::{delete, insert_into, pg::Pg, prelude::*, result::DatabaseErrorKind, update};
+use diesel_async::{AsyncConnection, RunQueryDsl};
+use scoped_futures::ScopedFutureExt;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use serde_with::skip_serializing_none;
+
+use crate::{
+    config::StatefulConfig,
+    http_error::HttpError,
+    model::{CreateIsMemberOf, Group, NewGroup, NewUser, ScimUpdateUser, User},
+    schema::{groups, is_member_of, users},
+    user::{api_token::bearer_token, session::SessionStore},
+    utils::sha256_hex,
+    AppState, PgConn, PgPool,
+};
+
+const SCIM_CONTENT_TYPE: &str = "application/scim+json";
+const USER_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:User";
+const GROUP_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:Group";
+const LIST_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:ListResponse";
+const ERROR_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:Error";
+const MAX_PAGE_SIZE: i64 = 100;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/ServiceProviderConfig", get(get_service_provider_config))
+        .route("/Users", get(list_users).post(create_user))
+        .route(
+            "/Users/:id",
+            get(get_user)
+                .put(replace_user)
+                .patch(patch_user)
+                .delete(deactivate_user),
+        )
+        .route("/Groups", get(list_groups).post(create_group))
+        .route(
+            "/Groups/:id",
+            get(get_group)
+                .put(replace_group)
+                .patch(patch_group)
+                .delete(delete_group),
+        )
+}
+
+/// An error in the shape SCIM clients expect
+#[derive(Debug)]
+pub struct ScimError {
+    status: StatusCode,
+    /// Only used with 400 and 409 responses
+    scim_type: Option<&'static str>,
+    detail: String,
+}
+
+impl ScimError {
+    fn new(status: StatusCode, detail: impl Into<String>) -> Self {
+        Self {
+            status,
+            scim_type: None,
+            detail: detail.into(),
+        }
+    }
+
+    fn bad_request(scim_type: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            scim_type: Some(scim_type),
+            detail: detail.into(),
+        }
+    }
+
+    fn invalid_value(detail: impl Into<String>) -> Self {
+        Self::bad_request("invalidValue", detail)
+    }
+
+    fn not_found() -> Self {
+        Self::new(StatusCode::NOT_FOUND, "resource not found")
+    }
+}
+
+impl From<HttpError> for ScimError {
+    fn from(err: HttpError) -> Self {
+        match err {
+            HttpError::WithCode { code, msg } => Self::new(code, msg),
+            HttpError::RetryAfter { msg, .. } => Self::new(StatusCode::TOO_MANY_REQUESTS, msg),
+            HttpError::Internal { err } => Self::new(StatusCode::INTERNAL_SERVER_ERROR, err),
+        }
+    }
+}
+
+macro_rules! impl_from {
+    ($from:ty) => {
+        impl From<$from> for ScimError {
+            fn from(err: $from) -> Self {
+                HttpError::from(err).into()
+            }
+        }
+    };
+}
+
+impl_from!(diesel::result::Error);
+impl_from!(diesel_async::pooled_connection::bb8::RunError);
+impl_from!(crate::user::session::SessionError);
+
+impl IntoResponse for ScimError {
+    fn into_response(self) -> Response {
+        let body = json!({
+            "schemas": [ERROR_SCHEMA],
+            "status": self.status.as_u16().to_string(),
+            "scimType": self.scim_type,
+            "detail": self.detail,
+        });
+        (
+            self.status,
+            [(header::CONTENT_TYPE, SCIM_CONTENT_TYPE)],
+            Json(body),
+        )
+            .into_response()
+    }
+}
+
+/// JSON with SCIM's media type
+struct ScimJson<T>(T);
+
+impl<T: Serialize> IntoResponse for ScimJson<T> {
+    fn into_response(self) -> Response {
+        ([(header::CONTENT_TYPE, SCIM_CONTENT_TYPE)], Json(self.0)).into_response()
+    }
+}
+
+/// The identity provider, authenticated by the SCIM bearer token
+pub struct ScimClient;
+
+#[async_trait]
+impl FromRequestParts<AppState> for ScimClient {
+    type Rejection = ScimError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, ScimError> {
+        let config = state.config.read().await;
+        let scim = config
+            .scim
+            .as_ref()
+            .ok_or(ScimError::new(StatusCode::NOT_FOUND, "SCIM is turned off"))?;
+
+        let token = bearer_token(parts).ok_or(ScimError::new(
+            StatusCode::UNAUTHORIZED,
+            "missing bearer token",
+        ))?;
+        if !sha256_hex(&token).eq_ignore_ascii_case(&scim.token_sha256) {
+            return Err(ScimError::new(
+                StatusCode::UNAUTHORIZED,
+                "invalid bearer token",
+            ));
+        }
+
+        Ok(ScimClient)
+    }
+}
+
+#[skip_serializing_none]
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct Name {
+    given_name: Option<String>,
+    family_name: Option<String>,
+}
+
+/// An entry of a multi-valued attribute, like `emails` or `members`
+#[skip_serializing_none]
+#[derive(Serialize, Deserialize)]
+struct MultiValued {
+    value: String,
+    display: Option<String>,
+    primary: Option<bool>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Meta {
+    resource_type: &'static str,
+    created: NaiveDateTime,
+    last_modified: NaiveDateTime,
+    location: String,
+}
+
+#[skip_serializing_none]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UserResource {
+    #[serde(default)]
+    schemas: Vec<String>,
+    id: Option<String>,
+    external_id: Option<String>,
+    /// The user's email address
+    user_name: String,
+    #[serde(default)]
+    name: Name,
+    #[serde(default)]
+    emails: Vec<MultiValued>,
+    #[serde(default)]
+    phone_numbers: Vec<MultiValued>,
+    #[serde(default = "default_active")]
+    active: bool,
+    #[serde(skip_deserializing)]
+    meta: Option<Meta>,
+}
+
+fn default_active() -> bool {
+    true
+}
+
+fn user_resource(user: User, base_url: &str) -> UserResource {
+    UserResource {
+        schemas: vec![USER_SCHEMA.to_string()],
+        id: Some(user.id.to_string()),
+        external_id: user.external_id,
+        user_name: user.email.clone(),
+        name: Name {
+            given_name: Some(user.fname),
+            family_name: Some(user.lname),
+        },
+        emails: vec![MultiValued {
+            value: user.email,
+            display: None,
+            primary: Some(true),
+        }],
+        phone_numbers: user
+            .phone_number
+            .map(|value| MultiValued {
+                value,
+                display: None,
+                primary: Some(true),
+            })
+            .into_iter()
+            .collect(),
+        active: user.active,
+        meta: Some(Meta {
+            resource_type: "User",
+            created: user.joined_on,
+            last_modified: user.updated_at,
+            location: format!("{base_url}/api/scim/v2/Users/{}", user.id),
+        }),
+    }
+}
+
+#[skip_serializing_none]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GroupResource {
+    #[serde(default)]
+    schemas: Vec<String>,
+    id: Option<String>,
+    display_name: String,
+    /// Left out when the client excludes it, since it can be long
+    members: Option<Vec<MultiValued>>,
+    #[serde(skip_deserializing)]
+    meta: Option<Meta>,
+}
+
+fn group_resource(
+    group: Group,
+    members: Option<Vec<MultiValued>>,
+    base_url: &str,
+) -> GroupResource {
+    GroupResource {
+        schemas: vec![GROUP_SCHEMA.to_string()],
+        id: Some(group.id.to_string()),
+        display_name: group.name,
+        members,
+        meta: Some(Meta {
+            resource_type: "Group",
+            created: group.created_on,
+            last_modified: group.updated_at,
+            location: format!("{base_url}/api/scim/v2/Groups/{}", group.id),
+        }),
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ListResponse<T> {
+    schemas: [&'static str; 1],
+    total_results: i64,
+    start_index: i64,
+    items_per_page: usize,
+    #[serde(rename = "Resources")]
+    resources: Vec<T>,
+}
+
+impl<T> ListResponse<T> {
+    fn new(total_results: i64, query: &ListQuery, resources: Vec<T>) -> Self {
+        Self {
+            schemas: [LIST_SCHEMA],
+            total_results,
+            start_index: query.offset() + 1,
+            items_per_page: resources.len(),
+            resources,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListQuery {
+    filter: Option<String>,
+    start_index: Option<i64>,
+    count: Option<i64>,
+    excluded_attributes: Option<String>,
+}
+
+impl ListQuery {
+    /// `startIndex` counts from 1
+    fn offset(&self) -> i64 {
+        self.start_index.unwrap_or(1).max(1) - 1
+    }
+
+    fn limit(&self) -> i64 {
+        self.count.unwrap_or(MAX_PAGE_SIZE).clamp(0, MAX_PAGE_SIZE)
+    }
+
+    fn excludes_members(&self) -> bool {
+        self.excluded_attributes
+            .as_deref()
+            .map_or(false, |excluded| {
+                excluded
+                    .split(',')
+                    .any(|attribute| attribute.trim().eq_ignore_ascii_case("members"))
+            })
+    }
+}
+
+/// `attribute eq "value"`, the only kind of filter clients send in practice
+struct Filter {
+    /// Lowercased, since attribute names are case-insensitive
+    attribute: String,
+    value: String,
+}
+
+fn parse_filter(filter: &str) -> Result<Filter, ScimError> {
+    let invalid = || {
+        ScimError::bad_request(
+            "invalidFilter",
+            "only filters of the form `attribute eq \"value\"` are supported",
+        )
+    };
+
+    let (attribute, rest) = filter.trim().split_once(' ').ok_or_else(invalid)?;
+    let (op, value) = rest.trim_start().split_once(' ').ok_or_else(invalid)?;
+    if !op.eq_ignore_ascii_case("eq") {
+        return Err(invalid());
+    }
+    let value = value
+        .trim()
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .ok_or_else(invalid)?;
+
+    Ok(Filter {
+        attribute: attribute.to_ascii_lowercase(),
+        value: value.replace("\\\"", "\""),
+    })
+}
+
+/// Resource ids are database ids; anything else can't match a resource
+fn parse_id(id_: &str) -> Result<i32, ScimError> {
+    id_.parse().map_err(|_| ScimError::not_found())
+}
+
+#[derive(Deserialize)]
+pub struct PatchRequest {
+    #[serde(rename = "Operations")]
+    operations: Vec<PatchOperation>,
+}
+
+#[derive(Deserialize)]
+struct PatchOperation {
+    op: String,
+    path: Option<String>,
+    #[serde(default)]
+    value: Value,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PatchOp {
+    Add,
+    Remove,
+    Replace,
+}
+
+impl PatchOperation {
+    /// Some clients capitalize the operation, e.g. `Replace`
+    fn op(&self) -> Result<PatchOp, ScimError> {
+        match self.op.to_ascii_lowercase().as_str() {
+            "add" => Ok(PatchOp::Add),
+            "remove" => Ok(PatchOp::Remove),
+            "replace" => Ok(PatchOp::Replace),
+            _ => Err(ScimError::bad_request(
+                "invalidSyntax",
+                "op must be add, remove or replace",
+            )),
+        }
+    }
+
+    /// The attributes to change, by path. Without a path, the value holds
+    /// the attributes.
+    fn targets(&self) -> Result<Vec<(AttributePath, &Value)>, ScimError> {
+        match (&self.path, &self.value) {
+            (Some(path), value) => Ok(vec![(parse_path(path), value)]),
+            (None, Value::Object(attributes)) => Ok(attributes
+                .iter()
+                .map(|(path, value)| (parse_path(path), value))
+                .collect()),
+            (None, _) => Err(ScimError::bad_request(
+                "noTarget",
+                "give a path or an object of attributes",
+            )),
+        }
+    }
+}
+
+/// A PATCH path like `emails[type eq "work"].value`, lowercased
+struct AttributePath {
+    attribute: String,
+    filter: Option<String>,
+    sub_attribute: Option<String>,
+}
+
+fn parse_path(path: &str) -> AttributePath {
+    // Attributes may be qualified with their schema's URN
+    let path = if path.to_ascii_lowercase().starts_with("urn:") {
+        path.rsplit_once(':')
+            .map_or(path, |(_, attribute)| attribute)
+    } else {
+        path
+    };
+
+    let (head, sub_attribute) = match path.rfind(']') {
+        Some(end) => (&path[..=end], path[end + 1..].strip_prefix('.')),
+        None => match path.split_once('.') {
+            Some((head, sub_attribute)) => (head, Some(sub_attribute)),
+            None => (path, None),
+        },
+    };
+    let (attribute, filter) = match head.split_once('[') {
+        Some((attribute, filter)) => (attribute, filter.strip_suffix(']')),
+        None => (head, None),
+    };
+
+    AttributePath {
+        attribute: attribute.to_ascii_lowercase(),
+        filter: filter.map(str::to_string),
+        sub_attribute: sub_attribute.map(str::to_ascii_lowercase),
+    }
+}
+
+fn string(value: &Value) -> Result<String, ScimError> {
+    value
+        .as_str()
+        .map(str::to_string)
+        .ok_or(ScimError::invalid_value("expected a string"))
+}
+
+/// Some clients send booleans as strings, e.g. `"False"`
+fn boolean(value: &Value) -> Result<bool, ScimError> {
+    match value {
+        Value::Bool(value) => Ok(*value),
+        Value::String(value) if value.eq_ignore_ascii_case("true") => Ok(true),
+        Value::String(value) if value.eq_ignore_ascii_case("false") => Ok(false),
+        _ => Err(ScimError::invalid_value("expected a boolean")),
+    }
+}
+
+/// The value of a multi-valued attribute: the primary entry's, or else the
+/// first entry's
+fn multi_value(value: &Value) -> Option<String> {
+    let entry = match value {
+        Value::String(value) => return Some(value.clone()),
+        Value::Array(entries) => entries
+            .iter()
+            .find(|entry| entry.get("primary") == Some(&Value::Bool(true)))
+            .or(entries.first())?,
+        entry => entry,
+    };
+    entry.get("value")?.as_str().map(str::to_string)
+}
+
+fn cant_remove() -> ScimError {
+    ScimError::bad_request("mutability", "this attribute is required")
+}
+
+/// The user columns SCIM manages
+struct UserFields {
+    email: String,
+    phone_number: Option<String>,
+    fname: String,
+    lname: String,
+    active: bool,
+    external_id: Option<String>,
+}
+
+impl From<User> for UserFields {
+    fn from(user: User) -> Self {
+        Self {
+            email: user.email,
+            phone_number: user.phone_number,
+            fname: user.fname,
+            lname: user.lname,
+            active: user.active,
+            external_id: user.external_id,
+        }
+    }
+}
+
+impl TryFrom<UserResource> for UserFields {
+    type Error = ScimError;
+
+    fn try_from(resource: UserResource) -> Result<Self, Self::Error> {
+        let primary = |values: &[MultiValued]| {
+            values
+                .iter()
+                .find(|value| value.primary == Some(true))
+                .or(values.first())
+                .map(|value| value.value.clone())
+        };
+
+        // Users sign in with their email address, so that's their user name
+        let email = if resource.user_name.contains('@') {
+            resource.user_name
+        } else {
+            primary(&resource.emails).ok_or(ScimError::invalid_value(
+                "userName must be an email address, or emails must have one",
+            ))?
+        };
+
+        Ok(Self {
+            email,
+            phone_number: primary(&resource.phone_numbers),
+            fname: resource
+                .name
+                .given_name
+                .ok_or(ScimError::invalid_value("name.givenName is required"))?,
+            lname: resource
+                .name
+                .family_name
+                .ok_or(ScimError::invalid_value("name.familyName is required"))?,
+            active: resource.active,
+            external_id: resource.external_id,
+        })
+    }
+}
+
+impl UserFields {
+    fn apply(&mut self, operation: &PatchOperation) -> Result<(), ScimError> {
+        let remove = operation.op()? == PatchOp::Remove;
+
+        for (path, value) in operation.targets()? {
+            match (path.attribute.as_str(), path.sub_attribute.as_deref()) {
+                ("active" | "username" | "name" | "emails", _) if remove => {
+                    return Err(cant_remove())
+                }
+                ("active", _) => self.active = boolean(value)?,
+                ("username", _) => self.email = string(value)?,
+                ("externalid", _) if remove => self.external_id = None,
+                ("externalid", _) => self.external_id = Some(string(value)?),
+                ("name", Some("givenname")) => self.fname = string(value)?,
+                ("name", Some("familyname")) => self.lname = string(value)?,
+                ("name", None) => {
+                    if let Some(given_name) = value.get("givenName") {
+                        self.fname = string(given_name)?;
+                    }
+                    if let Some(family_name) = value.get("familyName") {
+                        self.lname = string(family_name)?;
+                    }
+                }
+                ("emails", _) => {
+                    if let Some(email) = multi_value(value) {
+                        self.email = email;
+                    }
+                }
+                ("phonenumbers", _) if remove => self.phone_number = None,
+                ("phonenumbers", _) => self.phone_number = multi_value(value),
+                // Attributes that aren't kept, like `title`
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn uniqueness_error(err: diesel::result::Error) -> ScimError {
+    if let diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _) = err {
+        ScimError {
+            status: StatusCode::CONFLICT,
+            scim_type: Some("uniqueness"),
+            detail: "a user with that userName or externalId already exists".to_string(),
+        }
+    } else {
+        err.into()
+    }
+}
+
+/// Write the fields to the user, and sign them out if they've been
+/// deactivated
+async fn save_user(
+    id_: i32,
+    fields: UserFields,
+    email_verified: Option<bool>,
+    session: &SessionStore,
+    conn: &mut PgConn<'_>,
+) -> Result<User, ScimError> {
+    let user: User = update(users::table.find(id_))
+        .set(&ScimUpdateUser {
+            email: fields.email,
+            email_verified,
+            phone_number: Some(fields.phone_number),
+            fname: fields.fname,
+            lname: fields.lname,
+            active: fields.active,
+            external_id: Some(fields.external_id),
+        })
+        .get_result(conn)
+        .await
+        .map_err(uniqueness_error)?;
+
+    if !user.active {
+        session.remove_user_sessions(user.id, None).await?;
+    }
+
+    Ok(user)
+}
+
+diesel::sql_function!(fn lower(x: diesel::sql_types::Text) -> diesel::sql_types::Text);
+
+fn users_matching(filter: Option<&Filter>) -> Result<users::BoxedQuery<'static, Pg>, ScimError> {
+    use crate::schema::users::dsl::*;
+
+    let query = users.into_boxed();
+    let Some(filter) = filter else {
+        return Ok(query);
+    };
+
+    match filter.attribute.as_str() {
+        // SCIM compares userName case-insensitively
+        "username" | "emails" | "emails.value" => {
+            Ok(query.filter(lower(email).eq(lower(filter.value.clone()))))
+        }
+        "externalid" => Ok(query.filter(external_id.eq(filter.value.clone()))),
+        "id" => Ok(query.filter(id.eq(parse_id(&filter.value).unwrap_or(0)))),
+        _ => Err(ScimError::bad_request(
+            "invalidFilter",
+            "users can be filtered by userName, emails, externalId or id",
+        )),
+    }
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn get_service_provider_config(_: ScimClient) -> ScimJson<Value> {
+    ScimJson(json!({
+        "schemas": ["urn:ietf:params:scim:schemas:core:2.0:ServiceProviderConfig"],
+        "patch": { "supported": true },
+        "bulk": { "supported": false, "maxOperations": 0, "maxPayloadSize": 0 },
+        "filter": { "supported": true, "maxResults": MAX_PAGE_SIZE },
+        "changePassword": { "supported": false },
+        "sort": { "supported": false },
+        "etag": { "supported": false },
+        "authenticationSchemes": [{
+            "type": "oauthbearertoken",
+            "name": "Bearer token",
+            "description": "The token whose hash is set in the `scim` config section",
+        }],
+    }))
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn list_users(
+    _: ScimClient,
+    State(pool): State<PgPool>,
+    State(config): State<StatefulConfig>,
+    Query(query): Query<ListQuery>,
+) -> Result<ScimJson<ListResponse<UserResource>>, ScimError> {
+    let filter = query.filter.as_deref().map(parse_filter).transpose()?;
+
+    let conn = &mut pool.get().await?;
+
+    let total_results: i64 = users_matching(filter.as_ref())?
+        .count()
+        .get_result(conn)
+        .await?;
+    let users_: Vec<User> = users_matching(filter.as_ref())?
+        .order(users::id)
+        .offset(query.offset())
+        .limit(query.limit())
+        .load(conn)
+        .await?;
+
+    let base_url = config.read().await.base_url.clone();
+    let resources = users_
+        .into_iter()
+        .map(|user| user_resource(user, &base_url))
+        .collect();
+
+    Ok(ScimJson(ListResponse::new(
+        total_results,
+        &query,
+        resources,
+    )))
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn create_user(
+    _: ScimClient,
+    State(pool): State<PgPool>,
+    State(config): State<StatefulConfig>,
+    Json(resource): Json<UserResource>,
+) -> Result<(StatusCode, ScimJson<UserResource>), ScimError> {
+    let fields = UserFields::try_from(resource)?;
+
+    let conn = &mut pool.get().await?;
+
+    let new_user_data = NewUser {
+        email: &fields.email,
+        // The identity provider vouches for the address
+        email_verified: true,
+        phone_number: fields.phone_number.as_deref(),
+        fname: &fields.fname,
+        lname: &fields.lname,
+        bio: None,
+        profile_image: None,
+        permission_level: None,
+    };
+    let user: User = insert_into(users::table)
+        .values((
+            &new_user_data,
+            users::active.eq(fields.active),
+            users::external_id.eq(&fields.external_id),
+        ))
+        .get_result(conn)
+        .await
+        .map_err(uniqueness_error)?;
+
+    Ok((
+        StatusCode::CREATED,
+        ScimJson(user_resource(user, &config.read().await.base_url)),
+    ))
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn get_user(
+    _: ScimClient,
+    State(pool): State<PgPool>,
+    State(config): State<StatefulConfig>,
+    Path(id_): Path<String>,
+) -> Result<ScimJson<UserResource>, ScimError> {
+    let conn = &mut pool.get().await?;
+
+    let user = User::get(parse_id(&id_)?, conn)
+        .await?
+        .ok_or(ScimError::not_found())?;
+
+    Ok(ScimJson(user_resource(user, &config.read().await.base_url)))
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn replace_user(
+    _: ScimClient,
+    State(pool): State<PgPool>,
+    State(config): State<StatefulConfig>,
+    State(session): State<SessionStore>,
+    Path(id_): Path<String>,
+    Json(resource): Json<UserResource>,
+) -> Result<ScimJson<UserResource>, ScimError> {
+    let fields = UserFields::try_from(resource)?;
+
+    let conn = &mut pool.get().await?;
+
+    let user = User::get(parse_id(&id_)?, conn)
+        .await?
+        .ok_or(ScimError::not_found())?;
+    let email_verified = (fields.email != user.email).then_some(true);
+    let user = save_user(user.id, fields, email_verified, &session, conn).await?;
+
+    Ok(ScimJson(user_resource(user, &config.read().await.base_url)))
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn patch_user(
+    _: ScimClient,
+    State(pool): State<PgPool>,
+    State(config): State<StatefulConfig>,
+    State(session): State<SessionStore>,
+    Path(id_): Path<String>,
+    Json(patch): Json<PatchRequest>,
+) -> Result<ScimJson<UserResource>, ScimError> {
+    let conn = &mut pool.get().await?;
+
+    let user = User::get(parse_id(&id_)?, conn)
+        .await?
+        .ok_or(ScimError::not_found())?;
+    let (id_, old_email) = (user.id, user.email.clone());
+
+    let mut fields = UserFields::from(user);
+    for operation in &patch.operations {
+        fields.apply(operation)?;
+    }
+    let email_verified = (fields.email != old_email).then_some(true);
+    let user = save_user(id_, fields, email_verified, &session, conn).await?;
+
+    Ok(ScimJson(user_resource(user, &config.read().await.base_url)))
+}
+
+/// Users are deactivated rather than deleted, so their appointments and
+/// history are kept
+#[axum_macros::debug_handler(state = AppState)]
+async fn deactivate_user(
+    _: ScimClient,
+    State(pool): State<PgPool>,
+    State(session): State<SessionStore>,
+    Path(id_): Path<String>,
+) -> Result<StatusCode, ScimError> {
+    use crate::schema::users::dsl::*;
+
+    let conn = &mut pool.get().await?;
+
+    let user_id_ = parse_id(&id_)?;
+    let updated = update(users.find(user_id_))
+        .set(active.eq(false))
+        .execute(conn)
+        .await?;
+    if updated == 0 {
+        return Err(ScimError::not_found());
+    }
+    session.remove_user_sessions(user_id_, None).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The group columns and memberships SCIM manages
+struct GroupFields {
+    name: String,
+    members: HashSet<i32>,
+}
+
+impl TryFrom<GroupResource> for GroupFields {
+    type Error = ScimError;
+
+    fn try_from(resource: GroupResource) -> Result<Self, Self::Error> {
+        let members = resource
+            .members
+            .unwrap_or_default()
+            .iter()
+            .map(|member| {
+                member
+                    .value
+                    .parse()
+                    .map_err(|_| ScimError::invalid_value("member values must be user ids"))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            name: resource.display_name,
+            members,
+        })
+    }
+}
+
+/// User ids from a list of `{"value": "<id>"}` objects, or just one
+fn member_ids(value: &Value) -> Result<Vec<i32>, ScimError> {
+    let members = match value {
+        Value::Null => Vec::new(),
+        Value::Array(members) => members.iter().collect(),
+        member => vec![member],
+    };
+
+    members
+        .into_iter()
+        .map(|member| {
+            member
+                .get("value")
+                .and_then(Value::as_str)
+                .and_then(|id_| id_.parse().ok())
+                .ok_or(ScimError::invalid_value("member values must be user ids"))
+        })
+        .collect()
+}
+
+impl GroupFields {
+    fn apply(&mut self, operation: &PatchOperation) -> Result<(), ScimError> {
+        let op = operation.op()?;
+
+        for (path, value) in operation.targets()? {
+            match path.attribute.as_str() {
+                "displayname" if op == PatchOp::Remove => return Err(cant_remove()),
+                "displayname" => self.name = string(value)?,
+                "members" => {
+                    // `members[value eq "42"]` picks out one member
+                    let ids = match &path.filter {
+                        Some(filter) => {
+                            let filter = parse_filter(filter)?;
+                            if filter.attribute != "value" {
+                                return Err(ScimError::bad_request(
+                                    "invalidPath",
+                                    "members can only be picked out by value",
+                                ));
+                            }
+                            vec![filter.value.parse().map_err(|_| {
+                                ScimError::invalid_value("member values must be user ids")
+                            })?]
+                        }
+                        None => member_ids(value)?,
+                    };
+
+                    match op {
+                        PatchOp::Add => self.members.extend(ids),
+                        PatchOp::Remove if path.filter.is_none() && value.is_null() => {
+                            self.members.clear()
+                        }
+                        PatchOp::Remove => {
+                            for id_ in ids {
+                                self.members.remove(&id_);
+                            }
+                        }
+                        PatchOp::Replace => self.members = ids.into_iter().collect(),
+                    }
+                }
+                // Attributes that aren't kept, like `externalId`
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+async fn group_members(
+    group_id_: i32,
+    conn: &mut PgConn<'_>,
+) -> Result<Vec<MultiValued>, diesel::result::Error> {
+    let members: Vec<(i32, String, String)> = is_member_of::table
+        .filter(is_member_of::group_id.eq(group_id_))
+        .inner_join(users::table.on(users::id.eq(is_member_of::user_id)))
+        .select((users::id, users::fname, users::lname))
+        .order(users::id)
+        .load(conn)
+        .await?;
+
+    Ok(members
+        .into_iter()
+        .map(|(id_, fname, lname)| MultiValued {
+            value: id_.to_string(),
+            display: Some(format!("{fname} {lname}")),
+            primary: None,
+        })
+        .collect())
+}
+
+/// Write the fields to the group. Members who stay keep their assigned
+/// teachers. Run it in a transaction so a bad member leaves the group as it
+/// was.
+async fn save_group(
+    group_id_: i32,
+    fields: &GroupFields,
+    conn: &mut PgConn<'_>,
+) -> Result<Group, ScimError> {
+    use crate::schema::is_member_of::dsl::*;
+
+    update(groups::table.find(group_id_))
+        .set(groups::name.eq(&fields.name))
+        .execute(conn)
+        .await?;
+
+    let members: Vec<i32> = fields.members.iter().copied().collect();
+    delete(
+        is_member_of
+            .filter(group_id.eq(group_id_))
+            .filter(user_id.ne_all(members.clone())),
+    )
+    .execute(conn)
+    .await?;
+
+    let new_memberships: Vec<CreateIsMemberOf> = members
+        .into_iter()
+        .map(|user_id_| CreateIsMemberOf {
+            user_id: user_id_,
+            group_id: group_id_,
+            assigned_teacher: None,
+        })
+        .collect();
+    insert_into(is_member_of)
+        .values(&new_memberships)
+        .on_conflict_do_nothing()
+        .execute(conn)
+        .await
+        .map_err(|e| {
+            if let diesel::result::Error::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _) =
+                e
+            {
+                ScimError::invalid_value("a member doesn't exist")
+            } else {
+                e.into()
+            }
+        })?;
+
+    find_group(group_id_, conn)
+        .await?
+        .ok_or(ScimError::not_found())
+}
+
+async fn find_group(
+    group_id_: i32,
+    conn: &mut PgConn<'_>,
+) -> Result<Option<Group>, diesel::result::Error> {
+    groups::table
+        .find(group_id_)
+        .select(Group::as_select())
+        .first(conn)
+        .await
+        .optional()
+}
+
+fn groups_matching(filter: Option<&Filter>) -> Result<groups::BoxedQuery<'static, Pg>, ScimError> {
+    use crate::schema::groups::dsl::*;
+
+    let query = groups.into_boxed();
+    let Some(filter) = filter else {
+        return Ok(query);
+    };
+
+    match filter.attribute.as_str() {
+        "displayname" => Ok(query.filter(name.eq(filter.value.clone()))),
+        "id" => Ok(query.filter(id.eq(parse_id(&filter.value).unwrap_or(0)))),
+        _ => Err(ScimError::bad_request(
+            "invalidFilter",
+            "groups can be filtered by displayName or id",
+        )),
+    }
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn list_groups(
+    _: ScimClient,
+    State(pool): State<PgPool>,
+    State(config): State<StatefulConfig>,
+    Query(query): Query<ListQuery>,
+) -> Result<ScimJson<ListResponse<GroupResource>>, ScimError> {
+    let filter = query.filter.as_deref().map(parse_filter).transpose()?;
+
+    let conn = &mut pool.get().await?;
+
+    let total_results: i64 = groups_matching(filter.as_ref())?
+        .count()
+        .get_result(conn)
+        .await?;
+    let groups_: Vec<Group> = groups_matching(filter.as_ref())?
+        .select(Group::as_select())
+        .order(groups::id)
+        .offset(query.offset())
+        .limit(query.limit())
+        .load(conn)
+        .await?;
+
+    let base_url = config.read().await.base_url.clone();
+    let mut resources = Vec::with_capacity(groups_.len());
+    for group in groups_ {
+        let members = if query.excludes_members() {
+            None
+        } else {
+            Some(group_members(group.id, conn).await?)
+        };
+        resources.push(group_resource(group, members, &base_url));
+    }
+
+    Ok(ScimJson(ListResponse::new(
+        total_results,
+        &query,
+        resources,
+    )))
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn create_group(
+    _: ScimClient,
+    State(pool): State<PgPool>,
+    State(config): State<StatefulConfig>,
+    Json(resource): Json<GroupResource>,
+) -> Result<(StatusCode, ScimJson<GroupResource>), ScimError> {
+    let fields = GroupFields::try_from(resource)?;
+
+    let conn = &mut pool.get().await?;
+
+    let (group, members) = conn
+        .transaction(|conn| {
+            async move {
+                // Provisioned groups are private until an admin says otherwise
+                let group_id_: i32 = insert_into(groups::table)
+                    .values(&NewGroup {
+                        name: &fields.name,
+                        description: None,
+                        public: false,
+                    })
+                    .returning(groups::id)
+                    .get_result(conn)
+                    .await?;
+                let group = save_group(group_id_, &fields, conn).await?;
+                let members = group_members(group.id, conn).await?;
+                Ok::<_, ScimError>((group, members))
+            }
+            .scope_boxed()
+        })
+        .await?;
+
+    Ok((
+        StatusCode::CREATED,
+        ScimJson(group_resource(
+            group,
+            Some(members),
+            &config.read().await.base_url,
+        )),
+    ))
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn get_group(
+    _: ScimClient,
+    State(pool): State<PgPool>,
+    State(config): State<StatefulConfig>,
+    Path(id_): Path<String>,
+    Query(query): Query<ListQuery>,
+) -> Result<ScimJson<GroupResource>, ScimError> {
+    let conn = &mut pool.get().await?;
+
+    let group = find_group(parse_id(&id_)?, conn)
+        .await?
+        .ok_or(ScimError::not_found())?;
+    let members = if query.excludes_members() {
+        None
+    } else {
+        Some(group_members(group.id, conn).await?)
+    };
+
+    Ok(ScimJson(group_resource(
+        group,
+        members,
+        &config.read().await.base_url,
+    )))
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn replace_group(
+    _: ScimClient,
+    State(pool): State<PgPool>,
+    State(config): State<StatefulConfig>,
+    Path(id_): Path<String>,
+    Json(resource): Json<GroupResource>,
+) -> Result<ScimJson<GroupResource>, ScimError> {
+    let fields = GroupFields::try_from(resource)?;
+    let group_id_ = parse_id(&id_)?;
+
+    let conn = &mut pool.get().await?;
+
+    let (group, members) = conn
+        .transaction(|conn| {
+            async move {
+                let group = find_group(group_id_, conn)
+                    .await?
+                    .ok_or(ScimError::not_found())?;
+                let group = save_group(group.id, &fields, conn).await?;
+                let members = group_members(group.id, conn).await?;
+                Ok::<_, ScimError>((group, members))
+            }
+            .scope_boxed()
+        })
+        .await?;
+
+    Ok(ScimJson(group_resource(
+        group,
+        Some(members),
+        &config.read().await.base_url,
+    )))
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn patch_group(
+    _: ScimClient,
+    State(pool): State<PgPool>,
+    State(config): State<StatefulConfig>,
+    Path(id_): Path<String>,
+    Json(patch): Json<PatchRequest>,
+) -> Result<ScimJson<GroupResource>, ScimError> {
+    let group_id_ = parse_id(&id_)?;
+
+    let conn = &mut pool.get().await?;
+
+    let (group, members) = conn
+        .transaction(|conn| {
+            async move {
+                // Locked so that concurrent patches apply one after another
+                let group: Group = groups::table
+                    .find(group_id_)
+                    .select(Group::as_select())
+                    .for_update()
+                    .first(conn)
+                    .await
+                    .optional()?
+                    .ok_or(ScimError::not_found())?;
+                let members = is_member_of::table
+                    .filter(is_member_of::group_id.eq(group.id))
+                    .select(is_member_of::user_id)
+                    .load::<i32>(conn)
+                    .await?;
+
+                let mut fields = GroupFields {
+                    name: group.name,
+                    members: members.into_iter().collect(),
+                };
+                for operation in &patch.operations {
+                    fields.apply(operation)?;
+                }
+                let group = save_group(group.id, &fields, conn).await?;
+                let members = group_members(group.id, conn).await?;
+                Ok::<_, ScimError>((group, members))
+            }
+            .scope_boxed()
+        })
+        .await?;
+
+    Ok(ScimJson(group_resource(
+        group,
+        Some(members),
+        &config.read().await.base_url,
+    )))
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn delete_group(
+    _: ScimClient,
+    State(pool): State<PgPool>,
+    Path(id_): Path<String>,
+) -> Result<StatusCode, ScimError> {
+    let conn = &mut pool.get().await?;
+
+    let deleted = delete(groups::table.find(parse_id(&id_)?))
+        .execute(conn)
+        .await?;
+    if deleted == 0 {
+        return Err(ScimError::not_found());
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,315 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use axum_extra::extract::CookieJar;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
+use retainer::Cache;
+use serde::Deserialize;
+use tokio::task::JoinHandle;
+use typeshare::typeshare;
+
+use crate::{
+    config::StatefulConfig,
+    crypto::TokenCipher,
+    http_error::HttpError,
+    mail::Mailer,
+    model::{LocalLogin, NewOAuthConnection, OAuthProvision, User},
+    oauth::{OAuthError, OAuthRedirect},
+    user::{
+        lockout::{check_password, LoginAttemptLimits},
+        password::Passwords,
+        session::{ClientInfo, ProviderSession, SessionData, SessionStore},
+        totp::{totp_enabled, verify_second_factor, PendingTotpLoginCache, TotpLoginRedirect},
+        UserData,
+    },
+    utils::random_token,
+    AppState, PgConn, PgPool,
+};
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/password", post(link_with_password))
+        .route("/email", post(send_link_email))
+        .route("/confirm", get(confirm_link_email))
+}
+
+const LINK_TIMEOUT: u64 = 900; // 15 minutes in seconds
+
+/// An OpenID Connect identity whose email matches an existing account. It is
+/// only attached once the owner of that account proves it's theirs.
+#[derive(Clone)]
+pub struct PendingLink {
+    pub user_id: i32,
+    pub provider: String,
+    pub oid_subject: String,
+    /// The provider's `sid` claim, used for back-channel logout
+    pub sid: Option<String>,
+    /// Tokens are kept encrypted, just like in `oauth_connections`
+    pub access_token: String,
+    pub access_token_expires: Option<NaiveDateTime>,
+    pub refresh_token: Option<String>,
+    pub token_key_id: String,
+    pub email_secret: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct PendingLinkCache(Arc<Cache<String, PendingLink>>);
+
+impl Default for PendingLinkCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PendingLinkCache {
+    pub fn new() -> Self {
+        Self(Arc::new(Cache::new()))
+    }
+
+    pub fn spawn_monitor_thread(&self) -> JoinHandle<()> {
+        let store = self.0.clone();
+        tokio::spawn(async move { store.monitor(4, 0.25, Duration::from_secs(3)).await })
+    }
+
+    /// Store the pending link and return the token the browser uses to refer to it
+    pub async fn insert(&self, link: PendingLink) -> String {
+        let link_token = random_token(30);
+        self.0
+            .insert(link_token.clone(), link, Duration::from_secs(LINK_TIMEOUT))
+            .await;
+        link_token
+    }
+
+    async fn get(&self, link_token: &str) -> Option<PendingLink> {
+        self.0
+            .get(&link_token.to_string())
+            .await
+            .map(|link| PendingLink::clone(&link))
+    }
+}
+
+/// Sends the browser to the frontend page that asks the user to confirm the link
+pub struct LinkAccountRedirect(pub String);
+
+impl IntoResponse for LinkAccountRedirect {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::FOUND,
+            [(
+                header::LOCATION,
+                format!("/link-account?link_token={}", self.0),
+            )],
+        )
+            .into_response()
+    }
+}
+
+/// Attach the identity to the account, returning the session it signs in to
+async fn complete_link(
+    link: &PendingLink,
+    conn: &mut PgConn<'_>,
+    client: ClientInfo,
+) -> Result<SessionData, diesel::result::Error> {
+    let new_oauth_login = &NewOAuthConnection {
+        user_id: link.user_id,
+        provider: &link.provider,
+        provides: &OAuthProvision::Auth,
+        access_token: &link.access_token,
+        access_token_expires: link.access_token_expires.as_ref(),
+        refresh_token: link.refresh_token.as_deref(),
+        refresh_token_expires: None,
+        oid_subject: Some(&link.oid_subject),
+        token_key_id: Some(&link.token_key_id),
+    };
+    new_oauth_login.upsert(conn).await?;
+
+    Ok(SessionData::from_provider_session(
+        link.user_id,
+        ProviderSession {
+            provider: link.provider.clone(),
+            subject: link.oid_subject.clone(),
+            sid: link.sid.clone(),
+        },
+        client,
+    ))
+}
+
+#[typeshare]
+#[derive(Deserialize)]
+pub struct PasswordLinkData {
+    link_token: String,
+    password: String,
+    /// Required if the account has two-factor authentication
+    totp_code: Option<String>,
+}
+
+#[allow(clippy::too_many_arguments)]
+#[axum_macros::debug_handler(state = AppState)]
+async fn link_with_password(
+    State(pool): State<PgPool>,
+    State(session): State<SessionStore>,
+    State(links): State<PendingLinkCache>,
+    State(cipher): State<TokenCipher>,
+    State(passwords): State<Passwords>,
+    State(attempt_limits): State<LoginAttemptLimits>,
+    mut jar: CookieJar,
+    client: ClientInfo,
+    Json(link_data): Json<PasswordLinkData>,
+) -> Result<(CookieJar, Json<UserData>), HttpError> {
+    let link = links
+        .get(&link_data.link_token)
+        .await
+        .ok_or(HttpError::not_found("link request not found or expired"))?;
+
+    let conn = &mut pool.get().await?;
+
+    let user = User::get(link.user_id, conn)
+        .await?
+        .ok_or(HttpError::not_found("user not found"))?;
+    let local_login_info = LocalLogin::belonging_to(&user)
+        .first::<LocalLogin>(conn)
+        .await
+        .optional()?
+        .ok_or(HttpError::forbidden(
+            "this account has no password; confirm by email instead",
+        ))?;
+    if !check_password(
+        &local_login_info,
+        &link_data.password,
+        &passwords,
+        &attempt_limits,
+        &client,
+        conn,
+    )
+    .await?
+    {
+        return Err(HttpError::forbidden("incorrect password"));
+    }
+    let two_factor = totp_enabled(user.id, conn).await?;
+    if two_factor {
+        let totp_code = link_data.totp_code.ok_or(HttpError::forbidden(
+            "two-factor authentication code required",
+        ))?;
+        if !verify_second_factor(&user, &totp_code, &cipher, conn).await? {
+            return Err(HttpError::forbidden("incorrect code"));
+        }
+    }
+
+    links.0.remove(&link_data.link_token).await;
+    let session_data = complete_link(&link, conn, client).await?;
+    jar = session
+        .insert(
+            SessionData {
+                two_factor,
+                ..session_data
+            },
+            jar,
+        )
+        .await?;
+
+    let user_data = user.get_user_data(conn).await?;
+    Ok((jar, Json(user_data)))
+}
+
+#[typeshare]
+#[derive(Deserialize)]
+pub struct EmailLinkData {
+    link_token: String,
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn send_link_email(
+    State(pool): State<PgPool>,
+    State(links): State<PendingLinkCache>,
+    State(mailer): State<Mailer>,
+    State(config): State<StatefulConfig>,
+    Json(link_data): Json<EmailLinkData>,
+) -> Result<(), HttpError> {
+    let link = links
+        .get(&link_data.link_token)
+        .await
+        .ok_or(HttpError::not_found("link request not found or expired"))?;
+
+    // The browser already knows the link token, so the email needs its own secret
+    let secret = random_token(30);
+    links
+        .0
+        .update(&link_data.link_token, |link| {
+            link.email_secret = Some(secret.clone())
+        })
+        .await;
+
+    let conn = &mut pool.get().await?;
+    let user = User::get(link.user_id, conn)
+        .await?
+        .ok_or(HttpError::not_found("user not found"))?;
+
+    // Both tokens are alphanumeric, so they don't need to be escaped
+    let confirm_url = format!(
+        "{}/api/user/openid/link/confirm?link_token={}&secret={secret}",
+        config.read().await.base_url,
+        link_data.link_token
+    );
+    mailer
+        .send(
+            &user.email,
+            "Confirm linking your account",
+            format!(
+                "Someone signed in with {} using this email address. If this was you, \
+                open the link below to connect it to your account. Otherwise, you can \
+                ignore this email.\n\n{confirm_url}",
+                link.provider
+            ),
+        )
+        .await?;
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct EmailLinkConfirmation {
+    link_token: String,
+    secret: String,
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn confirm_link_email(
+    State(pool): State<PgPool>,
+    State(session): State<SessionStore>,
+    State(links): State<PendingLinkCache>,
+    State(pending_totp_logins): State<PendingTotpLoginCache>,
+    mut jar: CookieJar,
+    client: ClientInfo,
+    Query(confirmation): Query<EmailLinkConfirmation>,
+) -> Result<(CookieJar, Response), OAuthError> {
+    let link = links
+        .get(&confirmation.link_token)
+        .await
+        .ok_or(OAuthError("This link has expired".to_string()))?;
+    if link.email_secret.as_deref() != Some(confirmation.secret.as_str()) {
+        return Err(OAuthError("Invalid confirmation link".to_string()));
+    }
+
+    links.0.remove(&confirmation.link_token).await;
+
+    let conn = &mut pool.get().await?;
+    let session_data = complete_link(&link, conn, client).await?;
+
+    // The mailbox only stands in for the password, not the second factor
+    if totp_enabled(link.user_id, conn).await? {
+        let token = pending_totp_logins.insert(session_data).await;
+        return Ok((jar, TotpLoginRedirect(token).into_response()));
+    }
+
+    jar = session.insert(session_data, jar).await?;
+
+    Ok((jar, OAuthRedirect.into_response()))
+}
@@ -0,0 +1,248 @@
+use axum::{
+    extract::{OriginalUri, Path, State},
+    http::{header, request::Parts, Method},
+    routing::get,
+    Json, Router,
+};
+use axum_extra::extract::CookieJar;
+use chrono::{Duration, Local, NaiveDateTime};
+use diesel::{delete, insert_into, prelude::*, update};
+use diesel_async::RunQueryDsl;
+use serde::{Deserialize, Serialize};
+use typeshare::typeshare;
+
+use crate::{
+    config::StatefulConfig,
+    http_error::HttpError,
+    model::{ApiToken, ApiTokenScope, NewApiToken, User},
+    user::{check_admin_session, session::SessionStore, UserFromParts},
+    utils::{random_token, sha256_hex},
+    AppState, PgConn, PgPool,
+};
+
+const TOKEN_PREFIX: &str = "sct_";
+const MAX_TOKEN_LIFETIME_DAYS: i64 = 365;
+const ACCOUNT_ROUTES: &str = "/api/user";
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/", get(get_my_tokens).post(create_token))
+        .route("/:id", axum::routing::delete(revoke_token))
+}
+
+/// The token from an `Authorization: Bearer` header
+pub fn bearer_token(parts: &Parts) -> Option<String> {
+    parts
+        .headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::to_string)
+}
+
+/// Find the user a token belongs to, if the token may be used for this
+/// request
+pub async fn authenticate(
+    token: &str,
+    parts: &Parts,
+    admin_route: bool,
+    conn: &mut PgConn<'_>,
+) -> Result<User, HttpError> {
+    use crate::schema::api_tokens::dsl::*;
+
+    let now = Local::now().naive_local();
+    let api_token: ApiToken = update(
+        api_tokens
+            .filter(token_hash.eq(sha256_hex(token)))
+            .filter(expires.gt(now)),
+    )
+    .set(last_used.eq(now))
+    .get_result(conn)
+    .await
+    .optional()?
+    .ok_or(HttpError::forbidden("invalid or expired API token"))?;
+
+    check_scopes(&api_token.scopes, parts, admin_route)?;
+
+    User::get(api_token.user_id, conn)
+        .await?
+        .filter(|user| user.active)
+        .ok_or(HttpError::forbidden("No user found"))
+}
+
+/// The full path of the request
+pub fn request_path(parts: &Parts) -> &str {
+    // Nested routers only see the end of the path
+    parts
+        .extensions
+        .get::<OriginalUri>()
+        .map_or(parts.uri.path(), |OriginalUri(uri)| uri.path())
+}
+
+/// Whether the request is for one of the account settings routes
+pub fn is_account_route(parts: &Parts) -> bool {
+    let path = request_path(parts);
+    path == ACCOUNT_ROUTES || path.starts_with(&format!("{ACCOUNT_ROUTES}/"))
+}
+
+fn check_scopes(
+    scopes_: &[ApiTokenScope],
+    parts: &Parts,
+    admin_route: bool,
+) -> Result<(), HttpError> {
+    if admin_route {
+        return if scopes_.contains(&ApiTokenScope::Admin) {
+            Ok(())
+        } else {
+            Err(HttpError::forbidden("this API token lacks the admin scope"))
+        };
+    }
+
+    if matches!(parts.method, Method::GET | Method::HEAD) {
+        return Ok(());
+    }
+
+    // Tokens can't change account settings, so a leaked token can't be used
+    // to take over the account
+    if is_account_route(parts) {
+        return Err(HttpError::forbidden(
+            "API tokens can't change account settings",
+        ));
+    }
+    if !scopes_.contains(&ApiTokenScope::Appointments) {
+        return Err(HttpError::forbidden(
+            "this API token lacks the appointments scope",
+        ));
+    }
+
+    Ok(())
+}
+
+#[typeshare]
+#[derive(Serialize)]
+pub struct ApiTokenData {
+    pub id: i32,
+    pub name: String,
+    pub scopes: Vec<ApiTokenScope>,
+    #[typeshare(serialized_as = "String")]
+    pub expires: NaiveDateTime,
+    #[typeshare(serialized_as = "String")]
+    pub created_on: NaiveDateTime,
+    #[typeshare(serialized_as = "Option<String>")]
+    pub last_used: Option<NaiveDateTime>,
+}
+
+impl From<ApiToken> for ApiTokenData {
+    fn from(value: ApiToken) -> Self {
+        Self {
+            id: value.id,
+            name: value.name,
+            scopes: value.scopes,
+            expires: value.expires,
+            created_on: value.created_on,
+            last_used: value.last_used,
+        }
+    }
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn get_my_tokens(
+    UserFromParts { user, jar }: UserFromParts,
+    State(pool): State<PgPool>,
+) -> Result<(CookieJar, Json<Vec<ApiTokenData>>), HttpError> {
+    use crate::schema::api_tokens::dsl::*;
+
+    let conn = &mut pool.get().await?;
+
+    let tokens = ApiToken::belonging_to(&user)
+        .order(created_on.desc())
+        .load::<ApiToken>(conn)
+        .await?
+        .into_iter()
+        .map(ApiTokenData::from)
+        .collect();
+
+    Ok((jar, Json(tokens)))
+}
+
+#[typeshare]
+#[derive(Deserialize)]
+pub struct CreateApiToken {
+    name: String,
+    scopes: Vec<ApiTokenScope>,
+    expires_in_days: i64,
+}
+
+#[typeshare]
+#[derive(Serialize)]
+pub struct CreatedApiToken {
+    /// Only shown once
+    token: String,
+    data: ApiTokenData,
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn create_token(
+    UserFromParts { user, jar }: UserFromParts,
+    State(pool): State<PgPool>,
+    State(session): State<SessionStore>,
+    State(config): State<StatefulConfig>,
+    Json(create_data): Json<CreateApiToken>,
+) -> Result<(CookieJar, Json<CreatedApiToken>), HttpError> {
+    if create_data.scopes.is_empty() {
+        return Err(HttpError::bad_request("choose at least one scope"));
+    }
+    if !(1..=MAX_TOKEN_LIFETIME_DAYS).contains(&create_data.expires_in_days) {
+        return Err(HttpError::bad_request("tokens must expire within a year"));
+    }
+    let conn = &mut pool.get().await?;
+
+    if create_data.scopes.contains(&ApiTokenScope::Admin) {
+        if !user.is_staff(conn).await? {
+            return Err(HttpError::forbidden("insufficient permissions"));
+        }
+        check_admin_session(&jar, &session, &config).await?;
+    }
+
+    let token = format!("{TOKEN_PREFIX}{}", random_token(40));
+    let api_token: ApiToken = insert_into(crate::schema::api_tokens::table)
+        .values(&NewApiToken {
+            user_id: user.id,
+            name: &create_data.name,
+            token_hash: &sha256_hex(&token),
+            scopes: &create_data.scopes,
+            expires: Local::now().naive_local() + Duration::days(create_data.expires_in_days),
+        })
+        .get_result(conn)
+        .await?;
+
+    Ok((
+        jar,
+        Json(CreatedApiToken {
+            token,
+            data: api_token.into(),
+        }),
+    ))
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn revoke_token(
+    UserFromParts { user, jar }: UserFromParts,
+    State(pool): State<PgPool>,
+    Path(id_): Path<i32>,
+) -> Result<CookieJar, HttpError> {
+    use crate::schema::api_tokens::dsl::*;
+
+    let conn = &mut pool.get().await?;
+
+    let deleted = delete(api_tokens.find(id_))
+        .filter(user_id.eq(user.id))
+        .execute(conn)
+        .await?;
+    if deleted == 0 {
+        return Err(HttpError::not_found("API token not found"));
+    }
+
+    Ok(jar)
+}
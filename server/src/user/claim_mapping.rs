@@ -0,0 +1,248 @@
+use std::collections::HashSet;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use diesel::{delete, insert_into, prelude::*, update};
+use diesel_async::RunQueryDsl;
+use openidconnect::core::CoreIdToken;
+use serde_json::Value;
+use tracing::warn;
+
+use crate::{
+    config::ClaimMappings,
+    model::{CreateIsMemberOf, PermissionLevel},
+    PgConn,
+};
+
+/// Read a claim from an ID token that has already been verified
+///
+/// `CoreClient` only parses the standard claims, so custom ones (like
+/// `groups`) are read from the raw payload. Nested claims can be reached with
+/// a dotted path, e.g. `realm_access.roles`.
+pub fn claim_values(id_token: &CoreIdToken, claim_path: &str) -> Vec<String> {
+    let jwt = id_token.to_string();
+    let Some(claims) = jwt
+        .split('.')
+        .nth(1)
+        .and_then(|payload| URL_SAFE_NO_PAD.decode(payload).ok())
+        .and_then(|payload| serde_json::from_slice::<Value>(&payload).ok())
+    else {
+        return Vec::new();
+    };
+
+    let claim = claim_path
+        .split('.')
+        .try_fold(&claims, |value, key| value.get(key));
+
+    match claim {
+        Some(Value::Array(values)) => values
+            .iter()
+            .filter_map(|v| v.as_str().map(str::to_string))
+            .collect(),
+        Some(Value::String(value)) => vec![value.clone()],
+        _ => Vec::new(),
+    }
+}
+
+impl ClaimMappings {
+    /// The highest level asserted; users without a match become students
+    fn permission_level(&self, values: &[String]) -> PermissionLevel {
+        values
+            .iter()
+            .filter_map(|v| self.permission_levels.get(v))
+            .copied()
+            .max()
+            .unwrap_or(PermissionLevel::Student)
+    }
+
+    /// Ids of the mapped groups that are asserted
+    fn asserted_groups(&self, values: &[String]) -> HashSet<i32> {
+        values
+            .iter()
+            .filter_map(|v| self.groups.get(v))
+            .copied()
+            .collect()
+    }
+
+    /// Ids of the mapped groups that aren't asserted
+    fn unasserted_groups(&self, asserted_groups: &HashSet<i32>) -> Vec<i32> {
+        self.groups
+            .values()
+            .filter(|group_id| !asserted_groups.contains(*group_id))
+            .copied()
+            .collect()
+    }
+
+    /// Bring the user's permission level and group memberships in line with
+    /// what the identity provider asserts
+    pub async fn apply(
+        &self,
+        user_id_: i32,
+        id_token: &CoreIdToken,
+        conn: &mut PgConn<'_>,
+    ) -> Result<(), diesel::result::Error> {
+        self.apply_values(user_id_, &claim_values(id_token, &self.claim), conn)
+            .await
+    }
+
+    /// Like `apply`, for values read from somewhere other than an ID token
+    pub async fn apply_values(
+        &self,
+        user_id_: i32,
+        values: &[String],
+        conn: &mut PgConn<'_>,
+    ) -> Result<(), diesel::result::Error> {
+        if !self.permission_levels.is_empty() {
+            use crate::schema::users::dsl::*;
+
+            // The IdP is authoritative here, so users lose levels it stops asserting
+            update(users.find(user_id_))
+                .set(permission_level.eq(self.permission_level(values)))
+                .execute(conn)
+                .await?;
+        }
+
+        if !self.groups.is_empty() {
+            use crate::schema::is_member_of::dsl::*;
+
+            let asserted_groups = self.asserted_groups(values);
+
+            // A mapping to a deleted group shouldn't stop everyone from signing in
+            let existing_groups: HashSet<i32> = crate::schema::groups::table
+                .filter(crate::schema::groups::id.eq_any(asserted_groups.iter().copied()))
+                .select(crate::schema::groups::id)
+                .load::<i32>(conn)
+                .await?
+                .into_iter()
+                .collect();
+            for missing in asserted_groups.difference(&existing_groups) {
+                warn!("Claim mappings refer to group {missing}, which doesn't exist");
+            }
+
+            let new_memberships: Vec<CreateIsMemberOf> = existing_groups
+                .iter()
+                .map(|group_id_| CreateIsMemberOf {
+                    user_id: user_id_,
+                    group_id: *group_id_,
+                    assigned_teacher: None,
+                })
+                .collect();
+            insert_into(is_member_of)
+                .values(&new_memberships)
+                .on_conflict_do_nothing()
+                .execute(conn)
+                .await?;
+
+            // Only memberships in mapped groups are managed by the IdP
+            if self.remove_unasserted_memberships {
+                delete(
+                    is_member_of
+                        .filter(user_id.eq(user_id_))
+                        .filter(group_id.eq_any(self.unasserted_groups(&asserted_groups))),
+                )
+                .execute(conn)
+                .await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use super::*;
+
+    fn mappings() -> ClaimMappings {
+        ClaimMappings {
+            claim: "groups".to_string(),
+            permission_levels: HashMap::from([
+                ("staff".to_string(), PermissionLevel::Teacher),
+                ("admins".to_string(), PermissionLevel::Admin),
+            ]),
+            groups: HashMap::from([("cs101".to_string(), 1), ("cs102".to_string(), 2)]),
+            remove_unasserted_memberships: true,
+        }
+    }
+
+    fn values(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    fn id_token(claims: Value) -> CoreIdToken {
+        let encode = |value: &Value| URL_SAFE_NO_PAD.encode(value.to_string());
+        let mut payload = json!({
+            "iss": "https://idp.example",
+            "sub": "user",
+            "aud": "sceideal",
+            "exp": 4102444800_i64,
+            "iat": 1672531200_i64,
+        });
+        payload
+            .as_object_mut()
+            .unwrap()
+            .extend(claims.as_object().unwrap().clone());
+        format!(
+            "{}.{}.{}",
+            encode(&json!({ "alg": "RS256" })),
+            encode(&payload),
+            URL_SAFE_NO_PAD.encode("signature")
+        )
+        .parse()
+        .unwrap()
+    }
+
+    #[test]
+    fn the_highest_asserted_level_wins() {
+        let mappings = mappings();
+        assert_eq!(
+            mappings.permission_level(&values(&["staff", "admins"])),
+            PermissionLevel::Admin
+        );
+        assert_eq!(
+            mappings.permission_level(&values(&["staff", "other"])),
+            PermissionLevel::Teacher
+        );
+    }
+
+    #[test]
+    fn users_without_a_level_become_students() {
+        let mappings = mappings();
+        assert_eq!(mappings.permission_level(&[]), PermissionLevel::Student);
+        assert_eq!(
+            mappings.permission_level(&values(&["other"])),
+            PermissionLevel::Student
+        );
+    }
+
+    #[test]
+    fn only_mapped_groups_are_asserted() {
+        let mappings = mappings();
+        let asserted = mappings.asserted_groups(&values(&["cs101", "staff", "other"]));
+        assert_eq!(asserted, HashSet::from([1]));
+        assert_eq!(mappings.unasserted_groups(&asserted), vec![2]);
+    }
+
+    #[test]
+    fn reads_list_and_single_claims() {
+        let token = id_token(json!({ "groups": ["cs101", "staff", 3] }));
+        assert_eq!(claim_values(&token, "groups"), values(&["cs101", "staff"]));
+
+        let token = id_token(json!({ "groups": "cs101" }));
+        assert_eq!(claim_values(&token, "groups"), values(&["cs101"]));
+    }
+
+    #[test]
+    fn reads_nested_claims() {
+        let token = id_token(json!({ "realm_access": { "roles": ["admins"] } }));
+        assert_eq!(
+            claim_values(&token, "realm_access.roles"),
+            values(&["admins"])
+        );
+        assert!(claim_values(&token, "realm_access.missing").is_empty());
+        assert!(claim_values(&token, "groups").is_empty());
+    }
+}
@@ -0,0 +1,250 @@
+use axum::{
+    extract::{Query, State},
+    routing::{get, post},
+    Json, Router,
+};
+use axum_extra::extract::CookieJar;
+use diesel::{prelude::*, result::DatabaseErrorKind, update};
+use diesel_async::RunQueryDsl;
+use serde::Deserialize;
+use tracing::debug;
+use typeshare::typeshare;
+
+use crate::{
+    config::{StatefulConfig, VerifiedEmailRequirement},
+    crypto::LinkSigner,
+    http_error::HttpError,
+    mail::Mailer,
+    model::{LocalLogin, User},
+    oauth::{OAuthError, OAuthRedirect},
+    user::{
+        lockout::{check_password, LoginAttemptLimits},
+        password::Passwords,
+        session::ClientInfo,
+        UserFromParts,
+    },
+    AppState, PgPool,
+};
+
+const VERIFICATION_LINK_TTL: i64 = 3600 * 24 * 3; // 3 days in seconds
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/verify", get(verify_email))
+        .route("/resend", post(resend_verification_email))
+        .route("/change", post(change_email))
+}
+
+/// What a verification link signs. Including the address keeps a link from
+/// verifying a different address after the email is changed.
+fn verification_message(user_id: i32, email: &str) -> String {
+    format!("verify-email:{user_id}:{email}")
+}
+
+/// Email a verification link for `email` in the background
+pub fn send_verification_email(
+    user_id: i32,
+    email: String,
+    base_url: &str,
+    signer: &LinkSigner,
+    mailer: &Mailer,
+) {
+    let token = signer.sign(
+        &verification_message(user_id, &email),
+        VERIFICATION_LINK_TTL,
+    );
+    // The token is URL-safe base64, so it doesn't need to be escaped
+    let verify_url = format!("{base_url}/api/user/email/verify?user_id={user_id}&token={token}");
+
+    mailer.send_in_background(
+        email,
+        "Verify your email address",
+        format!(
+            "Open the link below to verify your email address. If you didn't ask \
+            for this, you can ignore this email.\n\n{verify_url}"
+        ),
+    );
+}
+
+/// Whether the config keeps a user from signing in until they verify their
+/// email address
+pub async fn login_blocked(email_verified: bool, config: &StatefulConfig) -> bool {
+    !email_verified && config.read().await.require_verified_email >= VerifiedEmailRequirement::Login
+}
+
+#[derive(Deserialize)]
+pub struct VerificationLink {
+    user_id: i32,
+    token: String,
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn verify_email(
+    State(pool): State<PgPool>,
+    State(signer): State<LinkSigner>,
+    Query(link): Query<VerificationLink>,
+) -> Result<OAuthRedirect, OAuthError> {
+    use crate::schema::users::dsl::*;
+
+    let invalid_link =
+        || OAuthError("This verification link is invalid or has expired".to_string());
+
+    let conn = &mut pool.get().await?;
+    let user = User::get(link.user_id, conn)
+        .await?
+        .ok_or_else(invalid_link)?;
+
+    if signer.verify(&verification_message(user.id, &user.email), &link.token) {
+        update(users.find(user.id))
+            .filter(email.eq(&user.email))
+            .set(email_verified.eq(true))
+            .execute(conn)
+            .await?;
+    } else if let Some(pending_email_) = user
+        .pending_email
+        .as_deref()
+        .filter(|pending| signer.verify(&verification_message(user.id, pending), &link.token))
+    {
+        // The old address stays in use until now
+        update(users.find(user.id))
+            .filter(pending_email.eq(pending_email_))
+            .set((
+                email.eq(pending_email_),
+                email_verified.eq(true),
+                pending_email.eq(None::<String>),
+            ))
+            .execute(conn)
+            .await
+            .map_err(|e| {
+                if let diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _) =
+                    e
+                {
+                    OAuthError("A user with that email already exists".to_string())
+                } else {
+                    e.into()
+                }
+            })?;
+    } else {
+        return Err(invalid_link());
+    }
+
+    Ok(OAuthRedirect)
+}
+
+#[typeshare]
+#[derive(Deserialize)]
+pub struct ResendVerificationData {
+    email: String,
+}
+
+/// Responds the same way whether or not the address belongs to an unverified
+/// account, since signing in may be blocked until it's verified
+#[axum_macros::debug_handler(state = AppState)]
+async fn resend_verification_email(
+    State(pool): State<PgPool>,
+    State(signer): State<LinkSigner>,
+    State(mailer): State<Mailer>,
+    State(config): State<StatefulConfig>,
+    Json(resend_data): Json<ResendVerificationData>,
+) -> Result<(), HttpError> {
+    let conn = &mut pool.get().await?;
+
+    match User::find(&resend_data.email, conn).await? {
+        Some(user) if !user.email_verified => send_verification_email(
+            user.id,
+            user.email,
+            &config.read().await.base_url,
+            &signer,
+            &mailer,
+        ),
+        _ => debug!("Verification email requested for an unknown or verified address"),
+    }
+
+    Ok(())
+}
+
+#[typeshare]
+#[derive(Deserialize)]
+pub struct ChangeEmailData {
+    email: String,
+    /// Required if the user has a password
+    password: Option<String>,
+}
+
+/// Start switching to a new address. The current address keeps working until
+/// the new one is verified.
+#[allow(clippy::too_many_arguments)]
+#[axum_macros::debug_handler(state = AppState)]
+async fn change_email(
+    UserFromParts { user, jar }: UserFromParts,
+    State(pool): State<PgPool>,
+    State(signer): State<LinkSigner>,
+    State(mailer): State<Mailer>,
+    State(config): State<StatefulConfig>,
+    State(passwords): State<Passwords>,
+    State(attempt_limits): State<LoginAttemptLimits>,
+    client: ClientInfo,
+    Json(change_data): Json<ChangeEmailData>,
+) -> Result<CookieJar, HttpError> {
+    use crate::schema::users::dsl::*;
+
+    let conn = &mut pool.get().await?;
+
+    if let Some(local_login_info) = LocalLogin::belonging_to(&user)
+        .first::<LocalLogin>(conn)
+        .await
+        .optional()?
+    {
+        let password = change_data
+            .password
+            .ok_or(HttpError::forbidden("current password required"))?;
+        if !check_password(
+            &local_login_info,
+            &password,
+            &passwords,
+            &attempt_limits,
+            &client,
+            conn,
+        )
+        .await?
+        {
+            return Err(HttpError::forbidden("incorrect password"));
+        }
+    }
+
+    if change_data.email == user.email {
+        return Err(HttpError::bad_request("that is already your email address"));
+    }
+    if User::find(&change_data.email, conn).await?.is_some() {
+        return Err(HttpError::bad_request(
+            "a user with this email already exists",
+        ));
+    }
+
+    update(users.find(user.id))
+        .set(pending_email.eq(&change_data.email))
+        .execute(conn)
+        .await?;
+
+    let base_url = config.read().await.base_url.clone();
+    send_verification_email(
+        user.id,
+        change_data.email.clone(),
+        &base_url,
+        &signer,
+        &mailer,
+    );
+    mailer.send_in_background(
+        user.email,
+        "Your email address is being changed",
+        format!(
+            "Someone asked to change the email address of your account to {}. It \
+            will be changed once the new address is verified; until then, this \
+            address keeps working. If this wasn't you, change your password and \
+            contact an administrator.",
+            change_data.email
+        ),
+    );
+
+    Ok(jar)
+}
@@ -0,0 +1,161 @@
+use axum::{
+    extract::{FromRequestParts, Path, State},
+    http::request::Parts,
+};
+use axum_extra::extract::CookieJar;
+
+use crate::{
+    audit,
+    http_error::HttpError,
+    model::{AuditEvent, NewAuditLogEntry, User},
+    user::{
+        api_token::{is_account_route, request_path},
+        session::{ClientInfo, SessionData, SessionStore},
+        ImpersonatorFromParts, UserFromParts,
+    },
+    AppState, PgConn, PgPool,
+};
+
+/// Sign in as another user to see what they see
+#[axum_macros::debug_handler(state = AppState)]
+pub async fn start_impersonation(
+    ImpersonatorFromParts(UserFromParts { user, jar }): ImpersonatorFromParts,
+    State(pool): State<PgPool>,
+    State(session): State<SessionStore>,
+    client: ClientInfo,
+    Path(id_): Path<i32>,
+) -> Result<CookieJar, HttpError> {
+    // API tokens have no session to go back to
+    let admin_session = session
+        .get(&jar)
+        .await?
+        .ok_or(HttpError::forbidden("sign in to impersonate a user"))?;
+
+    if id_ == user.id {
+        return Err(HttpError::bad_request("you can't impersonate yourself"));
+    }
+
+    let conn = &mut pool.get().await?;
+
+    let target = User::get(id_, conn)
+        .await?
+        .ok_or(HttpError::not_found("user not found"))?;
+    // Impersonating can't be used to gain permissions, globally or in any of
+    // the target's groups
+    if !target
+        .all_permissions(conn)
+        .await?
+        .is_subset(&user.permissions(None, conn).await?)
+    {
+        return Err(HttpError::forbidden(
+            "you can't impersonate users with permissions you don't have",
+        ));
+    }
+    if !target.active {
+        return Err(HttpError::forbidden("this account has been deactivated"));
+    }
+
+    audit::record(
+        NewAuditLogEntry {
+            event: AuditEvent::ImpersonationStarted,
+            user_id: Some(target.id),
+            actor_id: Some(user.id),
+            ip_address: client.ip_address.as_deref(),
+            details: None,
+        },
+        conn,
+    )
+    .await?;
+
+    let jar = session
+        .impersonate(
+            SessionData {
+                two_factor: admin_session.two_factor,
+                impersonator_id: Some(user.id),
+                ..SessionData::new(target.id, admin_session.login_method, client)
+            },
+            jar,
+        )
+        .await?;
+
+    Ok(jar)
+}
+
+/// Go back to the admin's own session
+#[axum_macros::debug_handler(state = AppState)]
+pub async fn stop_impersonation(
+    State(pool): State<PgPool>,
+    State(session): State<SessionStore>,
+    jar: CookieJar,
+    client: ClientInfo,
+) -> Result<CookieJar, HttpError> {
+    let session_data = session
+        .get(&jar)
+        .await?
+        .ok_or(HttpError::forbidden("No user found"))?;
+    let Some(impersonator_id) = session_data.impersonator_id else {
+        return Err(HttpError::bad_request("you aren't impersonating anyone"));
+    };
+
+    let conn = &mut pool.get().await?;
+
+    audit::record(
+        NewAuditLogEntry {
+            event: AuditEvent::ImpersonationStopped,
+            user_id: Some(session_data.user_id),
+            actor_id: Some(impersonator_id),
+            ip_address: client.ip_address.as_deref(),
+            details: None,
+        },
+        conn,
+    )
+    .await?;
+
+    Ok(session.stop_impersonating(jar).await?)
+}
+
+/// Record a change made while impersonating, attributing it to both the user
+/// and the admin. Account settings can't be changed while impersonating.
+pub(super) async fn check_action(
+    parts: &mut Parts,
+    state: &AppState,
+    jar: &CookieJar,
+    user_id_: i32,
+    conn: &mut PgConn<'_>,
+) -> Result<(), HttpError> {
+    if parts.method.is_safe() {
+        return Ok(());
+    }
+    let Some(impersonator_id) = state
+        .session_store
+        .get(jar)
+        .await?
+        .and_then(|session_data| session_data.impersonator_id)
+    else {
+        return Ok(());
+    };
+
+    if is_account_route(parts) {
+        return Err(HttpError::forbidden(
+            "account settings can't be changed while impersonating",
+        ));
+    }
+
+    let client = ClientInfo::from_request_parts(parts, state)
+        .await
+        .unwrap_or_default();
+    let details = format!("{} {}", parts.method, request_path(parts));
+    audit::record(
+        NewAuditLogEntry {
+            event: AuditEvent::ImpersonatedAction,
+            user_id: Some(user_id_),
+            actor_id: Some(impersonator_id),
+            ip_address: client.ip_address.as_deref(),
+            details: Some(&details),
+        },
+        conn,
+    )
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,341 @@
+use std::time::Duration;
+
+use axum::{
+    extract::{Path, State},
+    routing::post,
+    Json, Router,
+};
+use axum_extra::extract::CookieJar;
+use diesel::{insert_into, prelude::*, result::DatabaseErrorKind, update};
+use diesel_async::RunQueryDsl;
+use ldap3::{dn_escape, LdapConnAsync, LdapConnSettings, LdapError, Scope, SearchEntry};
+use serde::Deserialize;
+use typeshare::typeshare;
+
+use crate::{
+    config::{LdapProvider, ProfileField, StatefulConfig},
+    crypto::LinkSigner,
+    http_error::HttpError,
+    mail::Mailer,
+    model::{LdapIdentity, LoginMethod, NewLdapIdentity, NewUser, SyncProfile, User},
+    schema::{ldap_identities, users},
+    user::{
+        email_verification::{login_blocked, send_verification_email},
+        local::LoginResponse,
+        lockout::LoginAttemptLimits,
+        session::{ClientInfo, SessionData, SessionStore},
+        totp::{totp_enabled, PendingTotpLoginCache},
+    },
+    AppState, PgConn, PgPool,
+};
+
+const LDAP_TIMEOUT: u64 = 10; // seconds
+/// The result code for a failed bind
+const INVALID_CREDENTIALS: u32 = 49;
+
+pub fn router() -> Router<AppState> {
+    Router::new().route("/:provider/login", post(login))
+}
+
+/// Bind as the user and read their entry. Returns `None` if the directory
+/// rejects the credentials.
+async fn bind_as_user(
+    provider: &LdapProvider,
+    username: &str,
+    password: &str,
+) -> Result<Option<SearchEntry>, LdapError> {
+    let settings = LdapConnSettings::new()
+        .set_conn_timeout(Duration::from_secs(LDAP_TIMEOUT))
+        .set_starttls(provider.starttls);
+    let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &provider.ldap_url).await?;
+    ldap3::drive!(conn);
+
+    let bind_dn = provider.bind_dn.replace("{username}", &dn_escape(username));
+    let bind = ldap.simple_bind(&bind_dn, password).await?;
+    if bind.rc == INVALID_CREDENTIALS {
+        ldap.unbind().await?;
+        return Ok(None);
+    }
+    bind.success()?;
+
+    let attributes = &provider.attributes;
+    let mut requested = vec![
+        attributes.email.as_str(),
+        attributes.fname.as_str(),
+        attributes.lname.as_str(),
+    ];
+    requested.extend(attributes.phone_number.as_deref());
+    requested.extend(provider.claim_mappings.as_ref().map(|m| m.claim.as_str()));
+
+    let (entries, _) = ldap
+        .search(&bind_dn, Scope::Base, "(objectClass=*)", requested)
+        .await?
+        .success()?;
+    ldap.unbind().await?;
+
+    Ok(entries.into_iter().next().map(SearchEntry::construct))
+}
+
+/// The first value of an attribute
+fn attribute<'a>(entry: &'a SearchEntry, name: &str) -> Option<&'a str> {
+    entry
+        .attrs
+        .get(name)
+        .and_then(|values| values.first())
+        .map(String::as_str)
+}
+
+#[typeshare]
+#[derive(Deserialize)]
+pub struct LdapLoginData {
+    username: String,
+    password: String,
+}
+
+/// Sign in with a directory username and password. Signed-in users link the
+/// directory account to their account instead.
+#[allow(clippy::too_many_arguments)]
+#[axum_macros::debug_handler(state = AppState)]
+async fn login(
+    Path(provider_): Path<String>,
+    State(pool): State<PgPool>,
+    State(session): State<SessionStore>,
+    State(config): State<StatefulConfig>,
+    State(pending_totp_logins): State<PendingTotpLoginCache>,
+    State(attempt_limits): State<LoginAttemptLimits>,
+    State(signer): State<LinkSigner>,
+    State(mailer): State<Mailer>,
+    mut jar: CookieJar,
+    client: ClientInfo,
+    Json(login_data): Json<LdapLoginData>,
+) -> Result<(CookieJar, Json<LoginResponse>), HttpError> {
+    let provider = config
+        .read()
+        .await
+        .ldap_provider(&provider_)
+        .cloned()
+        .ok_or(HttpError::not_found("no provider by that name exists"))?;
+
+    // A bind without a password is an anonymous bind, which would succeed
+    // for any username
+    if login_data.username.is_empty() || login_data.password.is_empty() {
+        return Err(HttpError::bad_request("enter a username and password"));
+    }
+
+    if attempt_limits.ip_blocked(&client).await {
+        return Err(HttpError::too_many_requests(
+            "too many failed logins; try again later",
+        ));
+    }
+
+    let Some(entry) = bind_as_user(&provider, &login_data.username, &login_data.password).await?
+    else {
+        attempt_limits.record_ip_failure(&client).await;
+        return Err(HttpError::forbidden("wrong username or password"));
+    };
+
+    let conn = &mut pool.get().await?;
+
+    let existing_identity = {
+        use crate::schema::ldap_identities::dsl::*;
+        ldap_identities
+            .find((&provider_, &entry.dn))
+            .first::<LdapIdentity>(conn)
+            .await
+            .optional()?
+    };
+    let signed_in_user = User::from_jar(&jar, &session, conn).await?;
+
+    let user_id_ = match (existing_identity, signed_in_user) {
+        (Some(identity), Some(user)) if identity.user_id != user.id => {
+            return Err(HttpError::forbidden(
+                "this directory account is linked to another user",
+            ));
+        }
+        // Sign in
+        (Some(identity), _) => identity.user_id,
+        // Link the directory account to the signed-in user
+        (None, Some(user)) => {
+            link_identity(&provider_, &entry.dn, user.id, conn).await?;
+            user.id
+        }
+        (None, None) => {
+            let email = attribute(&entry, &provider.attributes.email).ok_or(
+                HttpError::bad_request("your directory entry has no email address"),
+            )?;
+
+            if let Some(user) = User::find(email, conn).await? {
+                // Existing accounts have to sign in first and link the
+                // directory account, unless the directory's addresses are
+                // trusted
+                if !provider.trust_email {
+                    return Err(HttpError::forbidden(
+                        "a user with that email already exists; sign in and then sign in with the directory to link it",
+                    ));
+                }
+                link_identity(&provider_, &entry.dn, user.id, conn).await?;
+                user.id
+            } else if config.read().await.allow_signups {
+                let id = sign_up(&provider_, &provider, &entry, email, conn).await?;
+                if !provider.trust_email {
+                    send_verification_email(
+                        id,
+                        email.to_string(),
+                        &config.read().await.base_url,
+                        &signer,
+                        &mailer,
+                    );
+                }
+                id
+            } else {
+                return Err(HttpError::forbidden(
+                    "automatic sign-ups have been disallowed",
+                ));
+            }
+        }
+    };
+
+    let user = User::get(user_id_, conn)
+        .await?
+        .ok_or(HttpError::internal("user not found"))?;
+
+    // Checked before anything is written, so blocked accounts don't pick up
+    // the directory's profile or memberships
+    if !user.active {
+        return Err(HttpError::forbidden("this account has been deactivated"));
+    }
+    if login_blocked(user.email_verified, &config).await {
+        return Err(HttpError::forbidden(
+            "verify your email address before signing in",
+        ));
+    }
+
+    sync_profile(user.id, &provider, &entry, conn).await?;
+    if let Some(mappings) = &provider.claim_mappings {
+        let values = entry
+            .attrs
+            .get(&mappings.claim)
+            .cloned()
+            .unwrap_or_default();
+        mappings.apply_values(user.id, &values, conn).await?;
+    }
+    // Pick up the synced profile and permission level
+    let user = User::get(user.id, conn)
+        .await?
+        .ok_or(HttpError::internal("user not found"))?;
+
+    let session_data = SessionData {
+        login_provider: Some(provider_),
+        ..SessionData::new(user.id, LoginMethod::Ldap, client)
+    };
+    if totp_enabled(user.id, conn).await? {
+        let token = pending_totp_logins.insert(session_data).await;
+        return Ok((jar, Json(LoginResponse::TotpRequired { token })));
+    }
+
+    let user_data = user.get_user_data(conn).await?;
+    jar = session.insert(session_data, jar).await?;
+
+    Ok((jar, Json(LoginResponse::LoggedIn(user_data))))
+}
+
+async fn link_identity(
+    provider_: &str,
+    dn_: &str,
+    user_id_: i32,
+    conn: &mut PgConn<'_>,
+) -> Result<(), HttpError> {
+    insert_into(ldap_identities::table)
+        .values(&NewLdapIdentity {
+            provider: provider_,
+            dn: dn_,
+            user_id: user_id_,
+        })
+        .execute(conn)
+        .await
+        .map_err(|e| {
+            if let diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _) = e {
+                HttpError::forbidden("this directory account is already linked to an account")
+            } else {
+                e.into()
+            }
+        })?;
+    Ok(())
+}
+
+/// Create a user from their directory entry. Returns the new user's id.
+async fn sign_up(
+    provider_: &str,
+    provider: &LdapProvider,
+    entry: &SearchEntry,
+    email: &str,
+    conn: &mut PgConn<'_>,
+) -> Result<i32, HttpError> {
+    let attributes = &provider.attributes;
+    let missing_info_error = || HttpError::bad_request("your directory entry is missing your name");
+
+    let new_user_data = NewUser {
+        email,
+        // Addresses from a trusted directory don't need checking
+        email_verified: provider.trust_email,
+        phone_number: attributes
+            .phone_number
+            .as_deref()
+            .and_then(|name| attribute(entry, name)),
+        fname: attribute(entry, &attributes.fname).ok_or_else(missing_info_error)?,
+        lname: attribute(entry, &attributes.lname).ok_or_else(missing_info_error)?,
+        bio: None,
+        profile_image: None,
+        permission_level: None,
+    };
+    let id: i32 = insert_into(users::table)
+        .values(&new_user_data)
+        .returning(users::id)
+        .get_result(conn)
+        .await?;
+
+    link_identity(provider_, &entry.dn, id, conn).await?;
+
+    Ok(id)
+}
+
+/// Overwrite the profile fields the directory is trusted for. Attributes the
+/// entry doesn't have are left alone.
+async fn sync_profile(
+    user_id_: i32,
+    provider: &LdapProvider,
+    entry: &SearchEntry,
+    conn: &mut PgConn<'_>,
+) -> Result<(), diesel::result::Error> {
+    use crate::schema::users::dsl::*;
+
+    let attributes = &provider.attributes;
+    let field = |f: ProfileField, name: Option<&str>| {
+        provider
+            .sync_profile
+            .contains(&f)
+            .then(|| name.and_then(|name| attribute(entry, name)))
+            .flatten()
+            .map(str::to_string)
+    };
+    let changes = SyncProfile {
+        phone_number: field(
+            ProfileField::PhoneNumber,
+            attributes.phone_number.as_deref(),
+        ),
+        fname: field(ProfileField::Name, Some(&attributes.fname)),
+        lname: field(ProfileField::Name, Some(&attributes.lname)),
+        profile_image: None,
+    };
+
+    // Diesel refuses to run an update without any changes
+    if changes.phone_number.is_none() && changes.fname.is_none() && changes.lname.is_none() {
+        return Ok(());
+    }
+
+    update(users.find(user_id_))
+        .set(&changes)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
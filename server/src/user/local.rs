@@ -2,28 +2,43 @@ use axum::routing::post;
 use axum::Router;
 use axum::{extract::State, Json};
 use axum_extra::extract::CookieJar;
+use chrono::{Duration, Local};
 use diesel::result::DatabaseErrorKind;
-use diesel::{insert_into, prelude::*};
+use diesel::{delete, insert_into, prelude::*, update};
 use diesel_async::RunQueryDsl;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
 use typeshare::typeshare;
 
 use crate::http_error::HttpError;
-use crate::model::NewLocalLogin;
+use crate::model::{NewLocalLogin, NewPasswordResetToken};
 use crate::{
-    model::{LocalLogin, NewUser, User},
-    schema::{local_logins, users},
+    config::{MagicLinkLogin, StatefulConfig},
+    crypto::LinkSigner,
+    mail::Mailer,
+    model::{LocalLogin, LoginMethod, NewUser, User},
+    schema::{local_logins, password_reset_tokens, users},
     user::{
-        session::{SessionData, SessionStore},
-        UserData,
+        email_verification::{login_blocked, send_verification_email},
+        lockout::{check_password, LoginAttemptLimits},
+        password::Passwords,
+        session::{ClientInfo, SessionData, SessionStore},
+        totp::{totp_enabled, PendingTotpLoginCache},
+        UserData, UserFromParts,
     },
+    utils::{random_token, sha256_hex},
     AppState, PgPool,
 };
 
+const PASSWORD_RESET_TTL: i64 = 3600; // 1 hour in seconds
+
 pub fn router() -> Router<AppState> {
     Router::new()
         .route("/sign-up", post(sign_up))
         .route("/login", post(login))
+        .route("/request-reset", post(request_password_reset))
+        .route("/reset-password", post(reset_password))
+        .route("/change-password", post(change_password))
 }
 
 #[typeshare]
@@ -36,13 +51,21 @@ pub struct SignUpData {
     password: String,
 }
 
+#[allow(clippy::too_many_arguments)]
 #[axum_macros::debug_handler(state = AppState)]
 async fn sign_up(
     State(pool): State<PgPool>,
     State(session): State<SessionStore>,
+    State(signer): State<LinkSigner>,
+    State(mailer): State<Mailer>,
+    State(config): State<StatefulConfig>,
+    State(passwords): State<Passwords>,
     mut jar: CookieJar,
+    client: ClientInfo,
     Json(create_user): Json<SignUpData>,
 ) -> Result<(CookieJar, Json<UserData>), HttpError> {
+    passwords.check_policy(&create_user.password)?;
+
     let conn = &mut pool.get().await?;
 
     // Create the user
@@ -73,7 +96,7 @@ async fn sign_up(
     // Create the login method for the user
     let new_local_login = NewLocalLogin {
         user_id: id,
-        hash: &bcrypt::hash(create_user.password, bcrypt::DEFAULT_COST)?,
+        hash: &passwords.hash(&create_user.password)?,
     };
 
     insert_into(local_logins::table)
@@ -87,8 +110,26 @@ async fn sign_up(
         .ok_or(HttpError::internal("user not found"))?;
     let user_data = user.get_user_data(conn).await?;
 
+    send_verification_email(
+        user.id,
+        user.email.clone(),
+        &config.read().await.base_url,
+        &signer,
+        &mailer,
+    );
+
+    // The user is signed in once they verify their email
+    if login_blocked(user.email_verified, &config).await {
+        return Ok((jar, Json(user_data)));
+    }
+
     // Start session
-    jar = session.insert(SessionData::new(user.id), jar).await;
+    jar = session
+        .insert(
+            SessionData::new(user.id, LoginMethod::Password, client),
+            jar,
+        )
+        .await?;
 
     Ok((jar, Json(user_data)))
 }
@@ -99,31 +140,280 @@ pub struct LoginData {
     email: String,
     password: String,
 }
+
+#[typeshare]
+#[derive(Serialize)]
+#[serde(tag = "type", content = "content")]
+pub enum LoginResponse {
+    LoggedIn(UserData),
+    /// The first factor was right; finish signing in at `/user/totp/login`
+    TotpRequired {
+        token: String,
+    },
+}
+
+#[allow(clippy::too_many_arguments)]
 #[axum_macros::debug_handler(state = AppState)]
 async fn login(
     State(pool): State<PgPool>,
     State(session): State<SessionStore>,
+    State(config): State<StatefulConfig>,
+    State(pending_totp_logins): State<PendingTotpLoginCache>,
+    State(attempt_limits): State<LoginAttemptLimits>,
+    State(passwords): State<Passwords>,
     mut jar: CookieJar,
+    client: ClientInfo,
     Json(login_data): Json<LoginData>,
-) -> Result<(CookieJar, Json<UserData>), HttpError> {
+) -> Result<(CookieJar, Json<LoginResponse>), HttpError> {
+    if config.read().await.magic_link_login == MagicLinkLogin::Exclusive {
+        return Err(HttpError::forbidden("sign in with an email link instead"));
+    }
+
+    // Checked before hashing anything, since bcrypt is slow on purpose
+    if attempt_limits.ip_blocked(&client).await {
+        return Err(HttpError::too_many_requests(
+            "too many failed logins; try again later",
+        ));
+    }
+
     let conn = &mut pool.get().await?;
 
     let LoginData { email, password } = login_data;
-    let user = User::find(&email, conn)
-        .await?
-        .ok_or(HttpError::internal("user not found"))?;
+    let Some(user) = User::find(&email, conn).await? else {
+        attempt_limits.record_ip_failure(&client).await;
+        return Err(HttpError::unauthorized("incorrect email or password"));
+    };
 
     if let Some(local_login_info) = LocalLogin::belonging_to(&user)
         .first::<LocalLogin>(conn)
         .await
         .optional()?
     {
-        if bcrypt::verify(password, &local_login_info.hash)? {
+        if check_password(
+            &local_login_info,
+            &password,
+            &passwords,
+            &attempt_limits,
+            &client,
+            conn,
+        )
+        .await?
+        {
+            // Move old hashes to the current algorithm while the password is
+            // at hand
+            if passwords.needs_rehash(&local_login_info.hash) {
+                update(local_logins::table.find(user.id))
+                    .set(local_logins::hash.eq(passwords.hash(&password)?))
+                    .execute(conn)
+                    .await?;
+                debug!("Rehashed the password of user {}", user.id);
+            }
+
+            if !user.active {
+                return Err(HttpError::forbidden("this account has been deactivated"));
+            }
+            if login_blocked(user.email_verified, &config).await {
+                return Err(HttpError::forbidden(
+                    "verify your email address before signing in",
+                ));
+            }
+
+            if totp_enabled(user.id, conn).await? {
+                let token = pending_totp_logins
+                    .insert(SessionData::new(user.id, LoginMethod::Password, client))
+                    .await;
+                return Ok((jar, Json(LoginResponse::TotpRequired { token })));
+            }
+
             let user_data = user.get_user_data(conn).await?;
-            jar = session.insert(SessionData::new(user.id), jar).await;
-            return Ok((jar, Json(user_data)));
+            jar = session
+                .insert(
+                    SessionData::new(user.id, LoginMethod::Password, client),
+                    jar,
+                )
+                .await?;
+            return Ok((jar, Json(LoginResponse::LoggedIn(user_data))));
         }
+
+        return Err(HttpError::unauthorized("incorrect email or password"));
+    }
+
+    attempt_limits.record_ip_failure(&client).await;
+    Err(HttpError::unauthorized("incorrect email or password"))
+}
+
+#[typeshare]
+#[derive(Deserialize)]
+pub struct PasswordResetRequest {
+    email: String,
+}
+
+/// Email a password reset link. Responds the same way whether or not the
+/// address belongs to an account with a password.
+#[axum_macros::debug_handler(state = AppState)]
+async fn request_password_reset(
+    State(pool): State<PgPool>,
+    State(mailer): State<Mailer>,
+    State(config): State<StatefulConfig>,
+    Json(reset_request): Json<PasswordResetRequest>,
+) -> Result<(), HttpError> {
+    let conn = &mut pool.get().await?;
+
+    let Some(user) = User::find(&reset_request.email, conn).await? else {
+        debug!("Password reset requested for unknown email");
+        return Ok(());
+    };
+    let has_local_login = LocalLogin::belonging_to(&user)
+        .first::<LocalLogin>(conn)
+        .await
+        .optional()?
+        .is_some();
+    if !has_local_login {
+        debug!(
+            "Password reset requested for user {} without a password",
+            user.id
+        );
+        return Ok(());
+    }
+
+    // Only the newest link works
+    delete(password_reset_tokens::table.filter(password_reset_tokens::user_id.eq(user.id)))
+        .execute(conn)
+        .await?;
+
+    let token = random_token(30);
+    insert_into(password_reset_tokens::table)
+        .values(&NewPasswordResetToken {
+            token_hash: &sha256_hex(&token),
+            user_id: user.id,
+            expires: Local::now().naive_local() + Duration::seconds(PASSWORD_RESET_TTL),
+        })
+        .execute(conn)
+        .await?;
+
+    // The token is alphanumeric, so it doesn't need to be escaped
+    let reset_url = format!(
+        "{}/reset-password?token={token}",
+        config.read().await.base_url
+    );
+
+    // Sent in the background so that response times don't reveal whether the
+    // account exists
+    mailer.send_in_background(
+        user.email,
+        "Reset your password",
+        format!(
+            "Someone asked to reset the password for your account. If this was \
+            you, open the link below within an hour to choose a new password. \
+            Otherwise, you can ignore this email.\n\n{reset_url}"
+        ),
+    );
+
+    Ok(())
+}
+
+#[typeshare]
+#[derive(Deserialize)]
+pub struct PasswordResetData {
+    token: String,
+    password: String,
+}
+
+/// Set a new password with a token from a reset email. Signs the user out
+/// everywhere.
+#[axum_macros::debug_handler(state = AppState)]
+async fn reset_password(
+    State(pool): State<PgPool>,
+    State(session): State<SessionStore>,
+    State(passwords): State<Passwords>,
+    Json(reset_data): Json<PasswordResetData>,
+) -> Result<(), HttpError> {
+    // Checked first so that a rejected password doesn't use up the link
+    passwords.check_policy(&reset_data.password)?;
+
+    let conn = &mut pool.get().await?;
+
+    // Deleting the token as it's used keeps it from being used twice
+    let user_id_: i32 = delete(
+        password_reset_tokens::table
+            .filter(password_reset_tokens::token_hash.eq(sha256_hex(&reset_data.token)))
+            .filter(password_reset_tokens::expires.gt(Local::now().naive_local())),
+    )
+    .returning(password_reset_tokens::user_id)
+    .get_result(conn)
+    .await
+    .optional()?
+    .ok_or(HttpError::not_found("reset link not found or expired"))?;
+
+    update(local_logins::table.find(user_id_))
+        .set(local_logins::hash.eq(passwords.hash(&reset_data.password)?))
+        .execute(conn)
+        .await?;
+
+    session.remove_user_sessions(user_id_, None).await?;
+
+    Ok(())
+}
+
+#[typeshare]
+#[derive(Deserialize)]
+pub struct ChangePasswordData {
+    current_password: String,
+    new_password: String,
+    /// Sign out every other session, e.g. if the old password may have leaked
+    #[serde(default)]
+    revoke_other_sessions: bool,
+}
+
+#[allow(clippy::too_many_arguments)]
+#[axum_macros::debug_handler(state = AppState)]
+async fn change_password(
+    UserFromParts { user, jar }: UserFromParts,
+    State(pool): State<PgPool>,
+    State(session): State<SessionStore>,
+    State(mailer): State<Mailer>,
+    State(passwords): State<Passwords>,
+    State(attempt_limits): State<LoginAttemptLimits>,
+    client: ClientInfo,
+    Json(change_data): Json<ChangePasswordData>,
+) -> Result<CookieJar, HttpError> {
+    let conn = &mut pool.get().await?;
+
+    let local_login_info = LocalLogin::belonging_to(&user)
+        .first::<LocalLogin>(conn)
+        .await
+        .optional()?
+        .ok_or(HttpError::forbidden("this account has no password"))?;
+    if !check_password(
+        &local_login_info,
+        &change_data.current_password,
+        &passwords,
+        &attempt_limits,
+        &client,
+        conn,
+    )
+    .await?
+    {
+        return Err(HttpError::forbidden("incorrect password"));
     }
+    passwords.check_policy(&change_data.new_password)?;
+
+    update(local_logins::table.find(user.id))
+        .set(local_logins::hash.eq(passwords.hash(&change_data.new_password)?))
+        .execute(conn)
+        .await?;
+
+    if change_data.revoke_other_sessions {
+        session.remove_user_sessions(user.id, Some(&jar)).await?;
+    }
+
+    mailer.send_in_background(
+        user.email,
+        "Your password was changed",
+        "The password for your account was just changed. If this wasn't you, \
+        reset your password and contact an administrator."
+            .to_string(),
+    );
 
-    Err(HttpError::internal("user not found"))
+    Ok(jar)
 }
@@ -0,0 +1,215 @@
+use std::time::Duration as StdDuration;
+
+use chrono::{Duration, Local, NaiveDateTime};
+use diesel::{prelude::*, update};
+use diesel_async::RunQueryDsl;
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+use crate::{
+    audit,
+    http_error::HttpError,
+    model::{AuditEvent, LocalLogin, NewAuditLogEntry},
+    rate_limit::RateLimiter,
+    user::{password::Passwords, session::ClientInfo},
+    PgConn,
+};
+
+/// Failed passwords allowed before an account is locked
+const MAX_FAILED_ATTEMPTS: i32 = 5;
+const BASE_LOCKOUT: i64 = 60; // 1 minute in seconds
+const MAX_LOCKOUT: i64 = 3600 * 24; // 1 day in seconds
+const IP_WINDOW: u64 = 900; // 15 minutes in seconds
+const MAX_FAILURES_PER_IP: u32 = 30;
+
+/// Failed password logins by IP address, so one client can't guess at many
+/// accounts
+#[derive(Clone)]
+pub struct LoginAttemptLimits {
+    per_ip: RateLimiter,
+}
+
+impl Default for LoginAttemptLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LoginAttemptLimits {
+    pub fn new() -> Self {
+        Self {
+            per_ip: RateLimiter::new(MAX_FAILURES_PER_IP, StdDuration::from_secs(IP_WINDOW)),
+        }
+    }
+
+    pub fn spawn_monitor_thread(&self) -> JoinHandle<()> {
+        self.per_ip.spawn_monitor_thread()
+    }
+
+    pub async fn ip_blocked(&self, client: &ClientInfo) -> bool {
+        match &client.ip_address {
+            Some(ip_address) => self.per_ip.is_limited(ip_address).await,
+            None => false,
+        }
+    }
+
+    pub async fn record_ip_failure(&self, client: &ClientInfo) {
+        if let Some(ip_address) = &client.ip_address {
+            self.per_ip.record(ip_address.clone()).await;
+        }
+    }
+}
+
+/// Check a password under the per-IP limit and the account lockout, counting
+/// a wrong one against both. Every route that takes a password goes through
+/// here. A locked account gets a 429 saying when to try again.
+pub async fn check_password(
+    local_login: &LocalLogin,
+    password: &str,
+    passwords: &Passwords,
+    limits: &LoginAttemptLimits,
+    client: &ClientInfo,
+    conn: &mut PgConn<'_>,
+) -> Result<bool, HttpError> {
+    // Checked before hashing anything, since hashing is slow on purpose
+    if limits.ip_blocked(client).await {
+        return Err(HttpError::too_many_requests(
+            "too many failed logins; try again later",
+        ));
+    }
+
+    if let Some(seconds) = lockout_remaining(local_login.locked_until) {
+        limits.record_ip_failure(client).await;
+        return Err(HttpError::retry_after(
+            "this account is locked after too many failed logins; try again later",
+            seconds,
+        ));
+    }
+
+    if passwords.verify(password, &local_login.hash)? {
+        if local_login.failed_attempts > 0 {
+            clear_failures(local_login.user_id, conn).await?;
+        }
+        return Ok(true);
+    }
+
+    record_failure(local_login.user_id, client, conn).await?;
+    limits.record_ip_failure(client).await;
+    Ok(false)
+}
+
+/// Whole seconds until the lockout ends, if there is one
+pub fn lockout_remaining(locked_until: Option<NaiveDateTime>) -> Option<u64> {
+    let remaining = locked_until? - Local::now().naive_local();
+    (remaining > Duration::zero()).then(|| remaining.num_seconds().max(1) as u64)
+}
+
+/// Seconds to lock an account for after this many failures in a row, if
+/// that's past the limit. Each failure past it doubles the lockout.
+pub fn lockout_duration(failures: i32) -> Option<i64> {
+    if failures < MAX_FAILED_ATTEMPTS {
+        return None;
+    }
+    let doublings = (failures - MAX_FAILED_ATTEMPTS).min(20) as u32;
+    Some((BASE_LOCKOUT << doublings).min(MAX_LOCKOUT))
+}
+
+/// Count a wrong password. Past the limit, every failure locks the account
+/// for twice as long as the last one.
+pub async fn record_failure(
+    user_id_: i32,
+    client: &ClientInfo,
+    conn: &mut PgConn<'_>,
+) -> Result<(), diesel::result::Error> {
+    use crate::schema::local_logins::dsl::*;
+
+    let failures: i32 = update(local_logins.find(user_id_))
+        .set(failed_attempts.eq(failed_attempts + 1))
+        .returning(failed_attempts)
+        .get_result(conn)
+        .await?;
+    let Some(lockout) = lockout_duration(failures) else {
+        return Ok(());
+    };
+
+    update(local_logins.find(user_id_))
+        .set(locked_until.eq(Local::now().naive_local() + Duration::seconds(lockout)))
+        .execute(conn)
+        .await?;
+
+    warn!("Locked user {user_id_} out for {lockout} seconds after {failures} failed logins");
+    audit::record(
+        NewAuditLogEntry {
+            event: AuditEvent::AccountLocked,
+            user_id: Some(user_id_),
+            actor_id: None,
+            ip_address: client.ip_address.as_deref(),
+            details: Some(&format!(
+                "{failures} failed logins; locked for {lockout} seconds"
+            )),
+        },
+        conn,
+    )
+    .await
+}
+
+/// Reset the failure count and lift any lockout. Returns the number of local
+/// logins changed.
+pub async fn clear_failures(
+    user_id_: i32,
+    conn: &mut PgConn<'_>,
+) -> Result<usize, diesel::result::Error> {
+    use crate::schema::local_logins::dsl::*;
+
+    update(local_logins.find(user_id_))
+        .set((
+            failed_attempts.eq(0),
+            locked_until.eq(None::<NaiveDateTime>),
+        ))
+        .execute(conn)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lockout_starts_at_the_limit_and_doubles() {
+        assert_eq!(lockout_duration(0), None);
+        assert_eq!(lockout_duration(MAX_FAILED_ATTEMPTS - 1), None);
+        assert_eq!(lockout_duration(MAX_FAILED_ATTEMPTS), Some(BASE_LOCKOUT));
+        assert_eq!(
+            lockout_duration(MAX_FAILED_ATTEMPTS + 1),
+            Some(BASE_LOCKOUT * 2)
+        );
+        assert_eq!(
+            lockout_duration(MAX_FAILED_ATTEMPTS + 3),
+            Some(BASE_LOCKOUT * 8)
+        );
+    }
+
+    #[test]
+    fn lockout_is_capped() {
+        assert_eq!(
+            lockout_duration(MAX_FAILED_ATTEMPTS + 11),
+            Some(MAX_LOCKOUT)
+        );
+        // Large counts mustn't overflow the shift
+        assert_eq!(lockout_duration(i32::MAX), Some(MAX_LOCKOUT));
+    }
+
+    #[test]
+    fn remaining_lockout_rounds_up_to_a_second() {
+        let now = Local::now().naive_local();
+        assert_eq!(lockout_remaining(None), None);
+        assert_eq!(lockout_remaining(Some(now - Duration::seconds(1))), None);
+
+        let remaining = lockout_remaining(Some(now + Duration::seconds(90))).unwrap();
+        assert!((89..=90).contains(&remaining));
+        assert_eq!(
+            lockout_remaining(Some(now + Duration::milliseconds(500))),
+            Some(1)
+        );
+    }
+}
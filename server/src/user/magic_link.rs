@@ -0,0 +1,210 @@
+use std::time::Duration as StdDuration;
+
+use axum::{extract::State, routing::post, Json, Router};
+use axum_extra::extract::CookieJar;
+use chrono::{Duration, Local};
+use diesel::{delete, insert_into, prelude::*, update};
+use diesel_async::RunQueryDsl;
+use serde::Deserialize;
+use tokio::task::JoinHandle;
+use tracing::debug;
+use typeshare::typeshare;
+
+use crate::{
+    config::{MagicLinkLogin, StatefulConfig},
+    http_error::HttpError,
+    mail::Mailer,
+    model::{LoginMethod, NewMagicLinkToken, User},
+    rate_limit::RateLimiter,
+    schema::{magic_link_tokens, users},
+    user::{
+        local::LoginResponse,
+        session::{ClientInfo, SessionData, SessionStore},
+        totp::{totp_enabled, PendingTotpLoginCache},
+    },
+    utils::{random_token, sha256_hex},
+    AppState, PgPool,
+};
+
+const MAGIC_LINK_TTL: i64 = 900; // 15 minutes in seconds
+const RATE_LIMIT_WINDOW: u64 = 3600; // 1 hour in seconds
+const MAX_LINKS_PER_ADDRESS: u32 = 5;
+const MAX_LINKS_PER_IP: u32 = 20;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/request", post(request_magic_link))
+        .route("/login", post(magic_link_login))
+}
+
+/// Limits how often sign-in links can be requested, so the endpoint can't be
+/// used to flood inboxes
+#[derive(Clone)]
+pub struct MagicLinkLimits {
+    per_address: RateLimiter,
+    per_ip: RateLimiter,
+}
+
+impl Default for MagicLinkLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MagicLinkLimits {
+    pub fn new() -> Self {
+        let window = StdDuration::from_secs(RATE_LIMIT_WINDOW);
+        Self {
+            per_address: RateLimiter::new(MAX_LINKS_PER_ADDRESS, window),
+            per_ip: RateLimiter::new(MAX_LINKS_PER_IP, window),
+        }
+    }
+
+    pub fn spawn_monitor_thread(&self) -> JoinHandle<()> {
+        let limits = self.clone();
+        tokio::spawn(async move {
+            tokio::join!(limits.per_address.monitor(), limits.per_ip.monitor());
+        })
+    }
+
+    async fn check(&self, email: &str, client: &ClientInfo) -> bool {
+        if let Some(ip_address) = &client.ip_address {
+            if !self.per_ip.check(ip_address.clone()).await {
+                return false;
+            }
+        }
+        self.per_address.check(email.to_lowercase()).await
+    }
+}
+
+async fn ensure_enabled(config: &StatefulConfig) -> Result<(), HttpError> {
+    if config.read().await.magic_link_login == MagicLinkLogin::Disabled {
+        return Err(HttpError::forbidden("magic link login is disabled"));
+    }
+    Ok(())
+}
+
+#[typeshare]
+#[derive(Deserialize)]
+pub struct MagicLinkRequest {
+    email: String,
+}
+
+/// Email a sign-in link. Responds the same way whether or not the address
+/// belongs to an account.
+#[axum_macros::debug_handler(state = AppState)]
+async fn request_magic_link(
+    State(pool): State<PgPool>,
+    State(mailer): State<Mailer>,
+    State(config): State<StatefulConfig>,
+    State(limits): State<MagicLinkLimits>,
+    client: ClientInfo,
+    Json(link_request): Json<MagicLinkRequest>,
+) -> Result<(), HttpError> {
+    ensure_enabled(&config).await?;
+
+    // Checked before looking up the account so the limit doesn't reveal
+    // whether it exists
+    if !limits.check(&link_request.email, &client).await {
+        return Err(HttpError::too_many_requests(
+            "too many sign-in links requested; try again later",
+        ));
+    }
+
+    let conn = &mut pool.get().await?;
+
+    let Some(user) = User::find(&link_request.email, conn).await? else {
+        debug!("Magic link requested for unknown email");
+        return Ok(());
+    };
+
+    // Only the newest link works
+    delete(magic_link_tokens::table.filter(magic_link_tokens::user_id.eq(user.id)))
+        .execute(conn)
+        .await?;
+
+    let token = random_token(30);
+    insert_into(magic_link_tokens::table)
+        .values(&NewMagicLinkToken {
+            token_hash: &sha256_hex(&token),
+            user_id: user.id,
+            expires: Local::now().naive_local() + Duration::seconds(MAGIC_LINK_TTL),
+        })
+        .execute(conn)
+        .await?;
+
+    // The token is alphanumeric, so it doesn't need to be escaped. The page
+    // posts it back, so that link scanners in mail clients don't use it up.
+    let login_url = format!("{}/magic-link?token={token}", config.read().await.base_url);
+
+    mailer.send_in_background(
+        user.email,
+        "Your sign-in link",
+        format!(
+            "Open the link below within 15 minutes to sign in. If you didn't ask \
+            for this, you can ignore this email.\n\n{login_url}"
+        ),
+    );
+
+    Ok(())
+}
+
+#[typeshare]
+#[derive(Deserialize)]
+pub struct MagicLinkLoginData {
+    token: String,
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn magic_link_login(
+    State(pool): State<PgPool>,
+    State(session): State<SessionStore>,
+    State(config): State<StatefulConfig>,
+    State(pending_totp_logins): State<PendingTotpLoginCache>,
+    mut jar: CookieJar,
+    client: ClientInfo,
+    Json(login_data): Json<MagicLinkLoginData>,
+) -> Result<(CookieJar, Json<LoginResponse>), HttpError> {
+    ensure_enabled(&config).await?;
+
+    let conn = &mut pool.get().await?;
+
+    // Deleting the token as it's used keeps it from being used twice
+    let user_id_: i32 = delete(
+        magic_link_tokens::table
+            .filter(magic_link_tokens::token_hash.eq(sha256_hex(&login_data.token)))
+            .filter(magic_link_tokens::expires.gt(Local::now().naive_local())),
+    )
+    .returning(magic_link_tokens::user_id)
+    .get_result(conn)
+    .await
+    .optional()?
+    .ok_or(HttpError::not_found("sign-in link not found or expired"))?;
+
+    // Opening the link proves the user controls the address
+    let user: User = update(users::table.find(user_id_))
+        .set(users::email_verified.eq(true))
+        .get_result(conn)
+        .await?;
+
+    if !user.active {
+        return Err(HttpError::forbidden("this account has been deactivated"));
+    }
+
+    if totp_enabled(user.id, conn).await? {
+        let token = pending_totp_logins
+            .insert(SessionData::new(user.id, LoginMethod::MagicLink, client))
+            .await;
+        return Ok((jar, Json(LoginResponse::TotpRequired { token })));
+    }
+
+    let user_data = user.get_user_data(conn).await?;
+    jar = session
+        .insert(
+            SessionData::new(user.id, LoginMethod::MagicLink, client),
+            jar,
+        )
+        .await?;
+
+    Ok((jar, Json(LoginResponse::LoggedIn(user_data))))
+}
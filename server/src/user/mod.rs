@@ -3,7 +3,7 @@ use std::collections::HashMap;
 use async_trait::async_trait;
 use axum::{
     extract::{FromRequestParts, Path, State},
-    http::StatusCode,
+    http::{request::Parts, StatusCode},
     routing::{get, post},
     Json, Router,
 };
@@ -15,38 +15,98 @@ use futures::TryStreamExt;
 use itertools::izip;
 use openidconnect::{url::Url, LogoutRequest, PostLogoutRedirectUrl};
 use serde::{Deserialize, Serialize};
+use tracing::warn;
 use typeshare::typeshare;
 
+pub mod account_link;
+pub mod api_token;
+mod backchannel_logout;
+mod claim_mapping;
+mod email_verification;
+mod impersonation;
+mod ldap;
 mod local;
+pub mod lockout;
+pub mod magic_link;
+mod oidc_profile;
 pub mod openid_connect;
+pub mod passkey;
+pub mod password;
+pub mod saml;
 pub mod session;
+pub mod totp;
 
 use crate::{
+    audit,
     config::StatefulConfig,
     http_error::HttpError,
+    integrations::tokens::TokenManager,
     model::{
-        AdminUpdateUser, CreateIsMemberOf, Group, IsMemberOf, LocalLogin, NewLocalLogin, NewUser,
-        OAuthConnection, OAuthProvision, PermissionLevel, UpdateIsMemberOf, UpdateUser, User,
+        AdminUpdateUser, AuditEvent, CreateIsMemberOf, Group, IsMemberOf, LdapIdentity, LocalLogin,
+        LoginMethod, NewAuditLogEntry, NewLocalLogin, NewUser, OAuthConnection, OAuthProvision,
+        PasskeyCredential, Permission, PermissionLevel, SamlIdentity, UpdateIsMemberOf, UpdateUser,
+        User,
     },
+    role::{built_in_role, require_grantable},
     AppState, PgConn, PgPool, SessionStore,
 };
 
-use self::openid_connect::OpenIdClients;
+use self::{
+    openid_connect::OpenIdClients,
+    password::Passwords,
+    session::{ClientInfo, SessionData, SessionError},
+};
 
 pub fn router() -> Router<AppState> {
     Router::new()
         .nest("/local", local::router())
         .nest("/openid", openid_connect::router())
+        .nest("/email", email_verification::router())
+        .nest("/totp", totp::router())
+        .nest("/passkey", passkey::router())
+        .nest("/magic-link", magic_link::router())
+        .nest("/tokens", api_token::router())
+        .nest("/saml", saml::router())
+        .nest("/ldap", ldap::router())
         .route("/", get(get_me).post(add_user).put(update_me))
         .route("/groups", get(get_my_groups))
         .route("/groups/:group_id", get(get_my_group_details))
         .route("/logout", post(logout))
+        .route(
+            "/impersonation",
+            axum::routing::delete(impersonation::stop_impersonation),
+        )
+        .route(
+            "/sessions",
+            get(get_my_sessions).delete(revoke_my_other_sessions),
+        )
+        .route(
+            "/sessions/:session_id",
+            axum::routing::delete(revoke_my_session),
+        )
+        .route(
+            "/connections/:provides/:provider",
+            axum::routing::delete(remove_oauth_connection),
+        )
         .route("/:id", get(get_user))
         .route("/a", get(get_all_users))
         .route(
             "/a/:id",
             get(get_user_admin).put(update_user).delete(delete_user),
         )
+        .route(
+            "/a/:id/sessions",
+            get(get_user_sessions).delete(revoke_user_sessions),
+        )
+        .route(
+            "/a/:id/sessions/:session_id",
+            axum::routing::delete(revoke_user_session),
+        )
+        .route("/a/:id/unlock", post(unlock_user))
+        .route(
+            "/a/:id/impersonate",
+            post(impersonation::start_impersonation),
+        )
         .route("/a/:id/groups", get(get_user_groups))
         .route(
             "/a/:id/groups/:group_id",
@@ -81,14 +141,17 @@ impl User {
         jar: &CookieJar,
         session: &SessionStore,
         connection: &mut PgConn<'_>,
-    ) -> Result<Option<Self>, diesel::result::Error> {
-        let user_id_ = if let Some(session_data) = session.get(jar).await {
+    ) -> Result<Option<Self>, SessionError> {
+        let user_id_ = if let Some(session_data) = session.get(jar).await? {
             session_data.user_id
         } else {
             return Ok(None);
         };
 
-        User::get(user_id_, connection).await
+        // Deactivated users are signed out
+        Ok(User::get(user_id_, connection)
+            .await?
+            .filter(|user| user.active))
     }
 
     pub async fn get_user_data(
@@ -124,11 +187,50 @@ impl User {
             joined_on: self.joined_on,
             updated_at: self.updated_at,
             last_login: self.last_login,
+            pending_email: self.pending_email.clone(),
             local_login: local_login_opt,
             oauth_providers,
+            impersonated_by: None,
         })
     }
 
+    /// Count the ways this user can sign in
+    pub async fn count_login_methods(
+        &self,
+        conn: &mut PgConn<'_>,
+    ) -> Result<i64, diesel::result::Error> {
+        use crate::schema::oauth_connections::dsl::*;
+
+        let local_login_count: i64 = LocalLogin::belonging_to(&self)
+            .count()
+            .get_result(conn)
+            .await?;
+        let passkey_count: i64 = PasskeyCredential::belonging_to(&self)
+            .count()
+            .get_result(conn)
+            .await?;
+        let saml_identity_count: i64 = SamlIdentity::belonging_to(&self)
+            .count()
+            .get_result(conn)
+            .await?;
+        let ldap_identity_count: i64 = LdapIdentity::belonging_to(&self)
+            .count()
+            .get_result(conn)
+            .await?;
+        let auth_connection_count: i64 = oauth_connections
+            .filter(user_id.eq(self.id))
+            .filter(provides.eq(OAuthProvision::Auth))
+            .count()
+            .get_result(conn)
+            .await?;
+
+        Ok(local_login_count
+            + passkey_count
+            + saml_identity_count
+            + ldap_identity_count
+            + auth_connection_count)
+    }
+
     pub fn get_public_user_data(&self) -> PublicUserData {
         PublicUserData {
             id: self.id,
@@ -149,19 +251,25 @@ pub struct UserFromParts {
     pub jar: CookieJar,
 }
 
-#[async_trait]
-impl FromRequestParts<AppState> for UserFromParts {
-    type Rejection = HttpError;
-
-    async fn from_request_parts(
+impl UserFromParts {
+    /// Authenticate with an API token if the request has one, or else with
+    /// the session cookie
+    async fn extract(
         parts: &mut axum::http::request::Parts,
         state: &AppState,
-    ) -> Result<Self, Self::Rejection> {
+        admin_route: bool,
+    ) -> Result<Self, HttpError> {
         // Get cookie value
         let mut jar = CookieJar::from_request_parts(parts, state).await.unwrap();
 
         // Get the user
         let conn = &mut state.pool.get().await?;
+
+        if let Some(token) = api_token::bearer_token(parts) {
+            let user = api_token::authenticate(&token, parts, admin_route, conn).await?;
+            return Ok(UserFromParts { user, jar });
+        }
+
         // Gets user and updates session TTL
         let user = User::from_jar(&jar, &state.session_store, conn)
             .await?
@@ -170,49 +278,140 @@ impl FromRequestParts<AppState> for UserFromParts {
                 msg: "No user found",
             })?;
 
+        impersonation::check_action(parts, state, &jar, user.id, conn).await?;
+
         // Update cookie TTL
-        jar = state.session_store.reup(jar).await;
+        jar = state.session_store.reup(jar).await?;
 
         Ok(UserFromParts { user, jar })
     }
 }
 
-pub struct TeacherFromParts(pub UserFromParts);
-
 #[async_trait]
-impl FromRequestParts<AppState> for TeacherFromParts {
+impl FromRequestParts<AppState> for UserFromParts {
     type Rejection = HttpError;
 
     async fn from_request_parts(
         parts: &mut axum::http::request::Parts,
         state: &AppState,
     ) -> Result<Self, Self::Rejection> {
-        let user_from_parts = UserFromParts::from_request_parts(parts, state).await?;
-        match user_from_parts.user.permission_level {
-            PermissionLevel::Teacher | PermissionLevel::Admin => {
-                Ok(TeacherFromParts(user_from_parts))
-            }
-            _ => Err(HttpError::forbidden("insufficient permissions")),
-        }
+        UserFromParts::extract(parts, state, false).await
     }
 }
 
-pub struct AdminFromParts(pub UserFromParts);
+/// The `group_id` route parameter, if the route has one
+async fn group_id_param(parts: &mut Parts, state: &AppState) -> Option<i32> {
+    let Path(params) = Path::<HashMap<String, String>>::from_request_parts(parts, state)
+        .await
+        .ok()?;
+    params.get("group_id")?.parse().ok()
+}
 
-#[async_trait]
-impl FromRequestParts<AppState> for AdminFromParts {
-    type Rejection = HttpError;
+/// Extract the user and check that they hold a permission. With `in_group`,
+/// roles held in the group named by the route's `group_id` parameter count
+/// too.
+async fn authorize(
+    parts: &mut Parts,
+    state: &AppState,
+    permission: Permission,
+    in_group: bool,
+) -> Result<UserFromParts, HttpError> {
+    let user_from_parts = UserFromParts::extract(parts, state, permission.is_admin()).await?;
+
+    let group_id_ = if in_group {
+        group_id_param(parts, state).await
+    } else {
+        None
+    };
+    let conn = &mut state.pool.get().await?;
+    user_from_parts
+        .user
+        .require(permission, group_id_, conn)
+        .await?;
 
-    async fn from_request_parts(
-        parts: &mut axum::http::request::Parts,
-        state: &AppState,
-    ) -> Result<Self, Self::Rejection> {
-        let user_from_parts = UserFromParts::from_request_parts(parts, state).await?;
-        match user_from_parts.user.permission_level {
-            PermissionLevel::Admin => Ok(AdminFromParts(user_from_parts)),
-            _ => Err(HttpError::forbidden("insufficient permissions")),
+    // Admin-scoped tokens can only be created from a session that passed
+    // this check
+    if permission.is_admin() && api_token::bearer_token(parts).is_none() {
+        check_admin_session(&user_from_parts.jar, &state.session_store, &state.config).await?;
+    }
+
+    Ok(user_from_parts)
+}
+
+/// Define an extractor for users holding a permission, either `global`ly or
+/// `in_group`
+macro_rules! permission_extractor {
+    (@define $(#[$attr:meta])* $name:ident, $permission:expr, $in_group:literal) => {
+        $(#[$attr])*
+        pub struct $name(pub UserFromParts);
+
+        #[async_trait]
+        impl FromRequestParts<AppState> for $name {
+            type Rejection = HttpError;
+
+            async fn from_request_parts(
+                parts: &mut Parts,
+                state: &AppState,
+            ) -> Result<Self, Self::Rejection> {
+                Ok($name(authorize(parts, state, $permission, $in_group).await?))
+            }
         }
+    };
+    ($(#[$attr:meta])* $name:ident, $permission:expr, global) => {
+        permission_extractor!(@define $(#[$attr])* $name, $permission, false);
+    };
+    ($(#[$attr:meta])* $name:ident, $permission:expr, in_group) => {
+        permission_extractor!(@define $(#[$attr])* $name, $permission, true);
+    };
+}
+
+permission_extractor!(
+    /// Can host meetings
+    TeacherFromParts,
+    Permission::ManageLocations,
+    global
+);
+permission_extractor!(UserManagerFromParts, Permission::ManageUsers, global);
+permission_extractor!(
+    /// Can manage every group, or the one in the route
+    GroupManagerFromParts,
+    Permission::ManageGroups,
+    in_group
+);
+permission_extractor!(
+    /// Can manage the members of every group, or of the one in the route
+    MemberManagerFromParts,
+    Permission::ManageMembers,
+    in_group
+);
+permission_extractor!(RoleManagerFromParts, Permission::ManageRoles, global);
+permission_extractor!(AuditorFromParts, Permission::ViewAuditLog, global);
+permission_extractor!(ImpersonatorFromParts, Permission::ImpersonateUsers, global);
+
+/// Enforce `require_admin_2fa` for the session in the jar
+pub async fn check_admin_session(
+    jar: &CookieJar,
+    session: &SessionStore,
+    config: &StatefulConfig,
+) -> Result<(), HttpError> {
+    if !config.read().await.require_admin_2fa {
+        return Ok(());
+    }
+
+    // Providers are trusted to handle their own second factors
+    let password_only = session.get(jar).await?.map_or(true, |session_data| {
+        matches!(
+            session_data.login_method,
+            LoginMethod::Password | LoginMethod::MagicLink | LoginMethod::Ldap
+        ) && !session_data.two_factor
+    });
+    if password_only {
+        return Err(HttpError::forbidden(
+            "sign in with two-factor authentication to use admin features",
+        ));
     }
+
+    Ok(())
 }
 
 #[typeshare]
@@ -233,8 +432,12 @@ pub struct UserData {
     pub updated_at: NaiveDateTime,
     #[typeshare(serialized_as = "Option<String>")]
     pub last_login: Option<NaiveDateTime>,
+    /// Address waiting to be verified before it replaces `email`
+    pub pending_email: Option<String>,
     pub local_login: Option<LocalLoginData>,
     pub oauth_providers: HashMap<OAuthProvision, Vec<OAuthConnectionData>>,
+    /// The admin impersonating this user, so a banner can be shown
+    pub impersonated_by: Option<PublicUserData>,
 }
 
 #[typeshare]
@@ -260,6 +463,7 @@ pub struct OAuthConnectionData {
     pub created_on: NaiveDateTime,
     #[typeshare(serialized_as = "String")]
     pub updated_at: NaiveDateTime,
+    pub needs_reauth: bool,
 }
 
 impl From<OAuthConnection> for OAuthConnectionData {
@@ -268,6 +472,7 @@ impl From<OAuthConnection> for OAuthConnectionData {
             provider: value.provider,
             created_on: value.created_on,
             updated_at: value.updated_at,
+            needs_reauth: value.needs_reauth,
         }
     }
 }
@@ -276,9 +481,21 @@ impl From<OAuthConnection> for OAuthConnectionData {
 async fn get_me(
     UserFromParts { user, jar }: UserFromParts,
     State(pool): State<PgPool>,
+    State(session): State<SessionStore>,
 ) -> Result<(CookieJar, Json<UserData>), HttpError> {
     let mut conn = pool.get().await?;
-    let user_data = user.get_user_data(&mut conn).await?;
+    let mut user_data = user.get_user_data(&mut conn).await?;
+
+    if let Some(impersonator_id) = session
+        .get(&jar)
+        .await?
+        .and_then(|session_data| session_data.impersonator_id)
+    {
+        user_data.impersonated_by = User::get(impersonator_id, &mut conn)
+            .await?
+            .map(|admin| admin.get_public_user_data());
+    }
+
     Ok((jar, Json(user_data)))
 }
 
@@ -288,8 +505,8 @@ async fn logout(
     State(oid_clients): State<OpenIdClients>,
     State(config): State<StatefulConfig>,
     jar: CookieJar,
-) -> (CookieJar, Json<Option<Url>>) {
-    let (session_data, jar) = session.remove(jar).await;
+) -> Result<(CookieJar, Json<Option<Url>>), HttpError> {
+    let (session_data, jar) = session.remove(jar).await?;
 
     let mut logout_url = None;
     if let Some(provider) = session_data.and_then(|data| {
@@ -310,7 +527,138 @@ async fn logout(
         }
     }
 
-    (jar, Json(logout_url))
+    Ok((jar, Json(logout_url)))
+}
+
+#[typeshare]
+#[derive(Serialize)]
+pub struct SessionInfo {
+    /// Used to revoke the session
+    pub id: String,
+    /// Whether this is the session making the request
+    pub current: bool,
+    #[typeshare(serialized_as = "String")]
+    pub created_on: NaiveDateTime,
+    #[typeshare(serialized_as = "String")]
+    pub last_seen: NaiveDateTime,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub login_method: LoginMethod,
+    pub login_provider: Option<String>,
+    /// The admin who started the session, for impersonation sessions
+    pub impersonator_id: Option<i32>,
+}
+
+impl SessionInfo {
+    fn new(id_hash: String, session_data: SessionData, current_id_hash: Option<&str>) -> Self {
+        Self {
+            current: current_id_hash == Some(id_hash.as_str()),
+            id: id_hash,
+            created_on: session_data.created_on,
+            last_seen: session_data.last_seen,
+            ip_address: session_data.client.ip_address,
+            user_agent: session_data.client.user_agent,
+            login_method: session_data.login_method,
+            login_provider: session_data.login_provider,
+            impersonator_id: session_data.impersonator_id,
+        }
+    }
+}
+
+async fn list_sessions(
+    session: &SessionStore,
+    user_id: i32,
+    jar: &CookieJar,
+) -> Result<Vec<SessionInfo>, SessionError> {
+    let current_id_hash = session.current_id_hash(jar);
+    let mut sessions = session
+        .list_for_user(user_id)
+        .await?
+        .into_iter()
+        .map(|(id_hash, session_data)| {
+            SessionInfo::new(id_hash, session_data, current_id_hash.as_deref())
+        })
+        .collect::<Vec<_>>();
+    sessions.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+    Ok(sessions)
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn get_my_sessions(
+    UserFromParts { user, jar }: UserFromParts,
+    State(session): State<SessionStore>,
+) -> Result<(CookieJar, Json<Vec<SessionInfo>>), HttpError> {
+    let sessions = list_sessions(&session, user.id, &jar).await?;
+    Ok((jar, Json(sessions)))
+}
+
+/// Sign out everywhere except here
+#[axum_macros::debug_handler(state = AppState)]
+async fn revoke_my_other_sessions(
+    UserFromParts { user, jar }: UserFromParts,
+    State(session): State<SessionStore>,
+) -> Result<(CookieJar, Json<usize>), HttpError> {
+    let removed = session.remove_user_sessions(user.id, Some(&jar)).await?;
+    Ok((jar, Json(removed)))
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn revoke_my_session(
+    UserFromParts { user, jar }: UserFromParts,
+    State(session): State<SessionStore>,
+    Path(session_id): Path<String>,
+) -> Result<CookieJar, HttpError> {
+    // Revoking the current session is the same as logging out
+    if session.current_id_hash(&jar).as_deref() == Some(session_id.as_str()) {
+        let (_, jar) = session.remove(jar).await?;
+        return Ok(jar);
+    }
+
+    if !session.remove_user_session(user.id, &session_id).await? {
+        return Err(HttpError::not_found("session not found"));
+    }
+
+    Ok(jar)
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn remove_oauth_connection(
+    UserFromParts { user, jar }: UserFromParts,
+    State(pool): State<PgPool>,
+    State(token_manager): State<TokenManager>,
+    Path((provides_, provider_)): Path<(OAuthProvision, String)>,
+) -> Result<CookieJar, HttpError> {
+    use crate::schema::oauth_connections::dsl::*;
+
+    let conn = &mut pool.get().await?;
+
+    let connection: OAuthConnection = oauth_connections
+        .find((user.id, &provider_, provides_))
+        .first(conn)
+        .await
+        .optional()?
+        .ok_or(HttpError::not_found("connection not found"))?;
+
+    // Don't let users lock themselves out
+    if provides_ == OAuthProvision::Auth && user.count_login_methods(conn).await? <= 1 {
+        return Err(HttpError::forbidden(
+            "cannot remove the last login method for this account",
+        ));
+    }
+
+    // Removing the connection shouldn't depend on the provider being reachable
+    if let Err(e) = token_manager.revoke(&connection).await {
+        warn!(
+            "Failed to revoke {provider_} tokens for user {}: {e}",
+            user.id
+        );
+    }
+
+    delete(oauth_connections.find((user.id, &provider_, provides_)))
+        .execute(conn)
+        .await?;
+
+    Ok(jar)
 }
 
 #[axum_macros::debug_handler(state = AppState)]
@@ -372,15 +720,25 @@ pub struct CreateLocalUser {
 
 #[axum_macros::debug_handler(state = AppState)]
 async fn add_user(
-    AdminFromParts(UserFromParts { jar, .. }): AdminFromParts,
+    UserManagerFromParts(UserFromParts { user, jar }): UserManagerFromParts,
     State(pool): State<PgPool>,
+    State(passwords): State<Passwords>,
     Json(user_data): Json<CreateLocalUser>,
 ) -> Result<(CookieJar, String), HttpError> {
     use crate::schema::local_logins::dsl::*;
     use crate::schema::users::dsl::*;
 
+    passwords.check_policy(&user_data.password)?;
+
     let conn = &mut pool.get().await?;
 
+    // Permission levels hold the built-in roles
+    if let Some(level) = user_data.permission_level {
+        user.require(Permission::ManageRoles, None, conn).await?;
+        let role = built_in_role(level, conn).await?;
+        require_grantable(&user, &role.permissions, conn).await?;
+    }
+
     let new_user = NewUser {
         email: &user_data.email,
         email_verified: false,
@@ -400,7 +758,7 @@ async fn add_user(
 
     let new_local_login = NewLocalLogin {
         user_id: id_,
-        hash: &bcrypt::hash(user_data.password, bcrypt::DEFAULT_COST)?,
+        hash: &passwords.hash(&user_data.password)?,
     };
 
     insert_into(local_logins)
@@ -413,7 +771,7 @@ async fn add_user(
 
 #[axum_macros::debug_handler(state = AppState)]
 async fn update_user(
-    AdminFromParts(UserFromParts { jar, .. }): AdminFromParts,
+    UserManagerFromParts(UserFromParts { user, jar }): UserManagerFromParts,
     Path(id_): Path<i32>,
     State(pool): State<PgPool>,
     Json(update_user): Json<AdminUpdateUser>,
@@ -422,6 +780,13 @@ async fn update_user(
 
     let conn = &mut pool.get().await?;
 
+    // Permission levels hold the built-in roles
+    if let Some(level) = update_user.permission_level {
+        user.require(Permission::ManageRoles, None, conn).await?;
+        let role = built_in_role(level, conn).await?;
+        require_grantable(&user, &role.permissions, conn).await?;
+    }
+
     update(users)
         .filter(id.eq(id_))
         .set(update_user)
@@ -433,7 +798,7 @@ async fn update_user(
 
 #[axum_macros::debug_handler(state = AppState)]
 async fn get_all_users(
-    AdminFromParts(UserFromParts { jar, .. }): AdminFromParts,
+    UserManagerFromParts(UserFromParts { jar, .. }): UserManagerFromParts,
     State(pool): State<PgPool>,
 ) -> Result<(CookieJar, Json<Vec<UserData>>), HttpError> {
     use crate::schema::users::dsl::*;
@@ -477,8 +842,10 @@ async fn get_all_users(
                 joined_on: user.joined_on,
                 updated_at: user.updated_at,
                 last_login: user.last_login,
+                pending_email: user.pending_email,
                 local_login: local_login.pop().map(|l| l.into()),
                 oauth_providers: provider_map,
+                impersonated_by: None,
             }
         })
         .collect::<Vec<_>>();
@@ -488,7 +855,7 @@ async fn get_all_users(
 
 #[axum_macros::debug_handler(state = AppState)]
 async fn get_user_admin(
-    AdminFromParts(UserFromParts { jar, .. }): AdminFromParts,
+    UserManagerFromParts(UserFromParts { jar, .. }): UserManagerFromParts,
     State(pool): State<PgPool>,
     Path(id_): Path<i32>,
 ) -> Result<(CookieJar, Json<UserData>), HttpError> {
@@ -504,7 +871,7 @@ async fn get_user_admin(
 
 #[axum_macros::debug_handler(state = AppState)]
 async fn delete_user(
-    AdminFromParts(UserFromParts { jar, .. }): AdminFromParts,
+    UserManagerFromParts(UserFromParts { jar, .. }): UserManagerFromParts,
     State(pool): State<PgPool>,
     Path(id_): Path<i32>,
 ) -> Result<CookieJar, HttpError> {
@@ -517,6 +884,68 @@ async fn delete_user(
     Ok(jar)
 }
 
+#[axum_macros::debug_handler(state = AppState)]
+async fn get_user_sessions(
+    UserManagerFromParts(UserFromParts { jar, .. }): UserManagerFromParts,
+    State(session): State<SessionStore>,
+    Path(id_): Path<i32>,
+) -> Result<(CookieJar, Json<Vec<SessionInfo>>), HttpError> {
+    let sessions = list_sessions(&session, id_, &jar).await?;
+    Ok((jar, Json(sessions)))
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn revoke_user_sessions(
+    UserManagerFromParts(UserFromParts { jar, .. }): UserManagerFromParts,
+    State(session): State<SessionStore>,
+    Path(id_): Path<i32>,
+) -> Result<(CookieJar, Json<usize>), HttpError> {
+    // Keep the admin signed in if they revoke their own sessions
+    let removed = session.remove_user_sessions(id_, Some(&jar)).await?;
+    Ok((jar, Json(removed)))
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn revoke_user_session(
+    UserManagerFromParts(UserFromParts { jar, .. }): UserManagerFromParts,
+    State(session): State<SessionStore>,
+    Path((id_, session_id)): Path<(i32, String)>,
+) -> Result<CookieJar, HttpError> {
+    if !session.remove_user_session(id_, &session_id).await? {
+        return Err(HttpError::not_found("session not found"));
+    }
+
+    Ok(jar)
+}
+
+/// Lift a lockout from too many failed logins
+#[axum_macros::debug_handler(state = AppState)]
+async fn unlock_user(
+    UserManagerFromParts(UserFromParts { user, jar }): UserManagerFromParts,
+    State(pool): State<PgPool>,
+    client: ClientInfo,
+    Path(id_): Path<i32>,
+) -> Result<CookieJar, HttpError> {
+    let conn = &mut pool.get().await?;
+
+    if lockout::clear_failures(id_, conn).await? == 0 {
+        return Err(HttpError::not_found("this user has no password"));
+    }
+    audit::record(
+        NewAuditLogEntry {
+            event: AuditEvent::AccountUnlocked,
+            user_id: Some(id_),
+            actor_id: Some(user.id),
+            ip_address: client.ip_address.as_deref(),
+            details: None,
+        },
+        conn,
+    )
+    .await?;
+
+    Ok(jar)
+}
+
 #[axum_macros::debug_handler(state = AppState)]
 async fn get_my_groups(
     UserFromParts { jar, user }: UserFromParts,
@@ -566,11 +995,13 @@ async fn get_my_group_details(
 struct MembershipData {
     group: Group,
     assigned_teacher: Option<PublicUserData>,
+    /// Role held within the group
+    role_id: Option<i32>,
 }
 
 #[axum_macros::debug_handler(state = AppState)]
 async fn get_user_groups(
-    AdminFromParts(UserFromParts { jar, .. }): AdminFromParts,
+    UserManagerFromParts(UserFromParts { jar, .. }): UserManagerFromParts,
     Path(id_): Path<i32>,
     State(pool): State<PgPool>,
 ) -> Result<(CookieJar, Json<Vec<MembershipData>>), HttpError> {
@@ -590,10 +1021,11 @@ async fn get_user_groups(
     let groups = member_records
         .into_iter()
         .map(|record| {
-            let (_, group, teacher) = record;
+            let (membership, group, teacher) = record;
             MembershipData {
                 group,
                 assigned_teacher: teacher.map(|u| u.get_public_user_data()),
+                role_id: membership.role_id,
             }
         })
         .collect();
@@ -603,7 +1035,7 @@ async fn get_user_groups(
 
 #[axum_macros::debug_handler(state = AppState)]
 async fn add_user_to_group(
-    AdminFromParts(UserFromParts { jar, .. }): AdminFromParts,
+    MemberManagerFromParts(UserFromParts { jar, .. }): MemberManagerFromParts,
     State(pool): State<PgPool>,
     Path((user_id_, group_id_)): Path<(i32, i32)>,
 ) -> Result<CookieJar, HttpError> {
@@ -627,7 +1059,7 @@ async fn add_user_to_group(
 
 #[axum_macros::debug_handler(state = AppState)]
 async fn remove_user_from_group(
-    AdminFromParts(UserFromParts { jar, .. }): AdminFromParts,
+    MemberManagerFromParts(UserFromParts { jar, .. }): MemberManagerFromParts,
     State(pool): State<PgPool>,
     Path((user_id_, group_id_)): Path<(i32, i32)>,
 ) -> Result<CookieJar, HttpError> {
@@ -644,7 +1076,7 @@ async fn remove_user_from_group(
 
 #[axum_macros::debug_handler(state = AppState)]
 async fn update_group_membership(
-    AdminFromParts(UserFromParts { jar, .. }): AdminFromParts,
+    MemberManagerFromParts(UserFromParts { jar, .. }): MemberManagerFromParts,
     State(pool): State<PgPool>,
     Path((user_id_, group_id_)): Path<(i32, i32)>,
     Json(update_is_member_of): Json<UpdateIsMemberOf>,
@@ -653,10 +1085,37 @@ async fn update_group_membership(
 
     let conn = &mut pool.get().await?;
 
-    update(is_member_of.find((user_id_, group_id_)))
+    // Members can only be assigned to teachers of their own group
+    if let Some(teacher_id) = update_is_member_of.assigned_teacher {
+        let teacher = User::get(teacher_id, conn)
+            .await?
+            .ok_or(HttpError::not_found("teacher not found"))?;
+        let membership = is_member_of
+            .find((teacher.id, group_id_))
+            .first::<IsMemberOf>(conn)
+            .await
+            .optional()?;
+        if membership.is_none()
+            || !teacher
+                .permissions(Some(group_id_), conn)
+                .await?
+                .contains(&Permission::ManageLocations)
+        {
+            return Err(HttpError::bad_request(
+                "the assigned teacher must be a teacher in the group",
+            ));
+        }
+    }
+
+    let updated = update(is_member_of.find((user_id_, group_id_)))
         .set(update_is_member_of)
         .execute(conn)
         .await?;
+    if updated == 0 {
+        return Err(HttpError::not_found(
+            "this user isn't a member of the group",
+        ));
+    }
 
     Ok(jar)
 }
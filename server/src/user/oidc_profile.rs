@@ -0,0 +1,149 @@
+use diesel::{prelude::*, update};
+use diesel_async::RunQueryDsl;
+use openidconnect::{
+    core::{CoreClient, CoreIdTokenClaims, CoreUserInfoClaims},
+    reqwest::async_http_client,
+    AccessToken,
+};
+use tracing::{debug, warn};
+
+use crate::{config::ProfileField, model::SyncProfile, PgConn};
+
+/// Profile information about an OpenID Connect user
+///
+/// Many providers keep the ID token small and only return profile claims from
+/// the UserInfo endpoint, so anything missing from the ID token is looked up
+/// there.
+#[derive(Default)]
+pub struct OidcProfile {
+    pub email: Option<String>,
+    pub email_verified: Option<bool>,
+    pub fname: Option<String>,
+    pub lname: Option<String>,
+    pub phone_number: Option<String>,
+    pub picture: Option<String>,
+}
+
+impl OidcProfile {
+    /// Read the profile from the ID token, falling back to the UserInfo
+    /// endpoint when the fields needed for sign-up or `sync_fields` are
+    /// missing
+    pub async fn load(
+        client: &CoreClient,
+        id_token_claims: &CoreIdTokenClaims,
+        access_token: &AccessToken,
+        sync_fields: &[ProfileField],
+    ) -> Self {
+        let mut profile = Self::from_id_token(id_token_claims);
+        if profile.is_complete(sync_fields) {
+            return profile;
+        }
+
+        let request = match client.user_info(
+            access_token.clone(),
+            Some(id_token_claims.subject().clone()),
+        ) {
+            Ok(request) => request,
+            Err(e) => {
+                debug!("Not fetching UserInfo: {e}");
+                return profile;
+            }
+        };
+        match request.request_async(async_http_client).await {
+            Ok(claims) => profile.fill_from_user_info(&claims),
+            Err(e) => warn!("Failed to fetch UserInfo: {e}"),
+        }
+
+        profile
+    }
+
+    fn from_id_token(claims: &CoreIdTokenClaims) -> Self {
+        Self {
+            email: claims.email().map(|e| e.to_string()),
+            email_verified: claims.email_verified(),
+            fname: claims
+                .given_name()
+                .and_then(|n| n.iter().next().map(|(_, n)| n.to_string())),
+            lname: claims
+                .family_name()
+                .and_then(|n| n.iter().next().map(|(_, n)| n.to_string())),
+            phone_number: claims.phone_number().map(|p| p.to_string()),
+            picture: claims
+                .picture()
+                .and_then(|p| p.iter().next().map(|(_, p)| p.to_string())),
+        }
+    }
+
+    fn fill_from_user_info(&mut self, claims: &CoreUserInfoClaims) {
+        // Only trust UserInfo's verification status for the email it returned
+        if self.email.is_none() {
+            self.email = claims.email().map(|e| e.to_string());
+            self.email_verified = claims.email_verified();
+        }
+        if self.fname.is_none() {
+            self.fname = claims
+                .given_name()
+                .and_then(|n| n.iter().next().map(|(_, n)| n.to_string()));
+        }
+        if self.lname.is_none() {
+            self.lname = claims
+                .family_name()
+                .and_then(|n| n.iter().next().map(|(_, n)| n.to_string()));
+        }
+        if self.phone_number.is_none() {
+            self.phone_number = claims.phone_number().map(|p| p.to_string());
+        }
+        if self.picture.is_none() {
+            self.picture = claims
+                .picture()
+                .and_then(|p| p.iter().next().map(|(_, p)| p.to_string()));
+        }
+    }
+
+    fn is_complete(&self, sync_fields: &[ProfileField]) -> bool {
+        self.email.is_some()
+            && self.fname.is_some()
+            && self.lname.is_some()
+            && sync_fields.iter().all(|field| match field {
+                ProfileField::Name => true,
+                ProfileField::PhoneNumber => self.phone_number.is_some(),
+                ProfileField::ProfileImage => self.picture.is_some(),
+            })
+    }
+
+    /// Overwrite the user's profile with the fields the provider is trusted for.
+    /// Fields the provider didn't return are left alone.
+    pub async fn sync(
+        &self,
+        user_id_: i32,
+        sync_fields: &[ProfileField],
+        conn: &mut PgConn<'_>,
+    ) -> Result<(), diesel::result::Error> {
+        use crate::schema::users::dsl::*;
+
+        let field = |f: ProfileField, value: &Option<String>| {
+            sync_fields.contains(&f).then(|| value.clone()).flatten()
+        };
+        let changes = SyncProfile {
+            phone_number: field(ProfileField::PhoneNumber, &self.phone_number),
+            fname: field(ProfileField::Name, &self.fname),
+            lname: field(ProfileField::Name, &self.lname),
+            profile_image: field(ProfileField::ProfileImage, &self.picture),
+        };
+
+        // Diesel refuses to run an update without any changes
+        if changes.phone_number.is_none()
+            && changes.fname.is_none()
+            && changes.lname.is_none()
+            && changes.profile_image.is_none()
+        {
+            return Ok(());
+        }
+
+        update(users.find(user_id_))
+            .set(&changes)
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+}
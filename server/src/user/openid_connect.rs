@@ -3,7 +3,8 @@ use std::{collections::HashMap, sync::Arc};
 use axum::{
     extract::{Path, Query, State},
     http::StatusCode,
-    routing::get,
+    response::{IntoResponse, Response},
+    routing::{get, post},
     Router,
 };
 use axum_extra::extract::CookieJar;
@@ -14,22 +15,32 @@ use diesel_async::RunQueryDsl;
 use futures::{stream::FuturesUnordered, StreamExt};
 use oauth2::TokenResponse;
 use openidconnect::{
-    core::{CoreClient, CoreResponseType},
+    core::{CoreClient, CoreJsonWebKeySet, CoreResponseType},
     reqwest::async_http_client,
-    AuthenticationFlow, AuthorizationCode, ClaimsVerificationError, CsrfToken, EndSessionUrl,
-    Nonce, ProviderMetadataWithLogout, RedirectUrl,
+    AuthenticationFlow, AuthorizationCode, ClaimsVerificationError, ClientId, CsrfToken,
+    EndSessionUrl, IssuerUrl, Nonce, PkceCodeChallenge, PkceCodeVerifier,
+    ProviderMetadataWithLogout, RedirectUrl,
 };
 use retainer::Cache;
 use serde::Deserialize;
-use tokio::task::JoinHandle;
+use tokio::{sync::RwLockReadGuard, task::JoinHandle};
 use tracing::warn;
 
 use crate::{
-    config::{Config, ProviderUrls, StatefulConfig},
+    config::{ClaimMappings, Config, ProfileField, ProviderUrls, StatefulConfig},
+    crypto::{LinkSigner, TokenCipher},
+    mail::Mailer,
     model::{NewOAuthConnection, NewUser, OAuthConnection, OAuthProvision, User},
     oauth::{impl_oauth_error, OAuthError, OAuthRedirect},
     schema::{oauth_connections, users},
-    user::session::{SessionData, SessionStore},
+    user::{
+        account_link::{self, LinkAccountRedirect, PendingLink, PendingLinkCache},
+        backchannel_logout::backchannel_logout,
+        claim_mapping::claim_values,
+        email_verification::{login_blocked, send_verification_email},
+        oidc_profile::OidcProfile,
+        session::{ClientInfo, ProviderSession, SessionData, SessionStore},
+    },
     AppState, PgPool,
 };
 
@@ -37,6 +48,8 @@ pub fn router() -> Router<AppState> {
     Router::new()
         .route("/:provider/generate_url", get(get_oauth_url))
         .route("/:provider/callback", get(openid_callback))
+        .route("/:provider/backchannel_logout", post(backchannel_logout))
+        .nest("/link", account_link::router())
 }
 
 #[derive(Clone)]
@@ -45,13 +58,16 @@ pub struct OpenIdClients(Arc<HashMap<String, OpenIdClientRecord>>);
 pub struct OpenIdClientRecord {
     pub client: CoreClient,
     pub end_session_endpoint: Option<EndSessionUrl>,
+    /// Kept to verify logout tokens, which `CoreClient` has no verifier for
+    pub issuer: IssuerUrl,
+    pub client_id: ClientId,
+    pub jwks: CoreJsonWebKeySet,
 }
 
 impl OpenIdClients {
     pub async fn from_config(config: &Config) -> Self {
         let futures: FuturesUnordered<_> = config
-            .integrations
-            .iter()
+            .oauth_providers()
             .filter(|(_, p)| p.provides.contains(&OAuthProvision::Auth) || p.urls.is_open_id())
             .map(|(k, v)| async move {
                 let issuer_url = if let ProviderUrls::OpenIdConnect { ref issuer_url } = v.urls {
@@ -79,6 +95,8 @@ impl OpenIdClients {
                     .additional_metadata()
                     .end_session_endpoint
                     .as_ref().cloned();
+                let issuer = provider_metadata.issuer().clone();
+                let jwks = provider_metadata.jwks().clone();
 
                 let redirect_url = match RedirectUrl::new(format!(
                     "{}/api/user/openid/{k}/callback",
@@ -91,15 +109,24 @@ impl OpenIdClients {
                     }
                 };
 
-                let client =
-                    CoreClient::from_provider_metadata(provider_metadata, client_id, client_secret)
-                        .set_redirect_uri(redirect_url);
+                let mut client = CoreClient::from_provider_metadata(
+                    provider_metadata,
+                    client_id.clone(),
+                    client_secret,
+                )
+                .set_redirect_uri(redirect_url);
+                if let Some(revocation_url) = v.revocation_url.clone() {
+                    client = client.set_revocation_uri(revocation_url);
+                }
 
                 Some((
                     k.clone(),
                     OpenIdClientRecord {
                         client,
                         end_session_endpoint,
+                        issuer,
+                        client_id,
+                        jwks,
                     },
                 ))
             })
@@ -141,8 +168,13 @@ impl Ord for CachableCsrfToken {
     }
 }
 
+pub struct CsrfNonceRecord {
+    nonce: Nonce,
+    pkce_verifier: PkceCodeVerifier,
+}
+
 #[derive(Clone)]
-pub struct CsrfNonceCache(Arc<Cache<CachableCsrfToken, Nonce>>);
+pub struct CsrfNonceCache(Arc<Cache<CachableCsrfToken, CsrfNonceRecord>>);
 
 impl CsrfNonceCache {
     pub fn new() -> Self {
@@ -165,12 +197,22 @@ const NONCE_TIMEOUT: u64 = 3600; // 1 hour in seconds
 async fn get_oauth_url(
     State(oid_clients): State<OpenIdClients>,
     State(cn_cache): State<CsrfNonceCache>,
+    State(config): State<StatefulConfig>,
     Path(provider_): Path<String>,
 ) -> Result<String, StatusCode> {
     let client_record = oid_clients
         .get(&provider_)
         .await
         .ok_or(StatusCode::NOT_FOUND)?;
+    let scopes = config
+        .read()
+        .await
+        .oauth_provider(&provider_)
+        .ok_or(StatusCode::NOT_FOUND)?
+        .scopes(OAuthProvision::Auth);
+
+    // PKCE protects the code exchange, even for public clients without a secret
+    let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
     let (authorize_url, csrf_state, nonce) = client_record
         .client
         .authorize_url(
@@ -178,12 +220,8 @@ async fn get_oauth_url(
             CsrfToken::new_random,
             Nonce::new_random,
         )
-        // .add_scope(Scope::new(let CodeStatePair { code, state } = pair;
-        //     "https://www.googleapis.com/auth/calendar.events.readonly".to_string(),
-        // ))
-        // .add_scope(Scope::new(
-        //     "https://www.googleapis.com/auth/calendar.readonly".to_string(),
-        // ))
+        .add_scopes(scopes)
+        .set_pkce_challenge(pkce_challenge)
         .url();
 
     // Add state to cache
@@ -191,7 +229,10 @@ async fn get_oauth_url(
         .0
         .insert(
             CachableCsrfToken(csrf_state),
-            nonce,
+            CsrfNonceRecord {
+                nonce,
+                pkce_verifier,
+            },
             std::time::Duration::from_secs(NONCE_TIMEOUT),
         )
         .await;
@@ -199,6 +240,27 @@ async fn get_oauth_url(
     Ok(authorize_url.to_string())
 }
 
+async fn claim_mappings<'a>(
+    config: &'a StatefulConfig,
+    provider_: &str,
+) -> Option<RwLockReadGuard<'a, ClaimMappings>> {
+    RwLockReadGuard::try_map(config.read().await, |config| {
+        config
+            .oauth_provider(provider_)
+            .and_then(|p| p.claim_mappings.as_ref())
+    })
+    .ok()
+}
+
+async fn sync_fields(config: &StatefulConfig, provider_: &str) -> Vec<ProfileField> {
+    config
+        .read()
+        .await
+        .oauth_provider(provider_)
+        .map(|p| p.sync_profile.clone())
+        .unwrap_or_default()
+}
+
 impl_oauth_error!(
     ClaimsVerificationError,
     "Open Id Connect Claim Verification Error: {}"
@@ -220,14 +282,22 @@ async fn openid_callback(
     mut jar: CookieJar,
     State(config): State<StatefulConfig>,
     State(session): State<SessionStore>,
-) -> Result<(CookieJar, OAuthRedirect), OAuthError> {
+    State(cipher): State<TokenCipher>,
+    State(links): State<PendingLinkCache>,
+    State(signer): State<LinkSigner>,
+    State(mailer): State<Mailer>,
+    client: ClientInfo,
+) -> Result<(CookieJar, Response), OAuthError> {
     // Verify openid
     let CodeStatePair { code, state } = pair;
     let client_record = oid_clients
         .get(&provider_)
         .await
         .ok_or(OAuthError("No provider by that name exists".to_string()))?;
-    let nonce = cn_cache
+    let CsrfNonceRecord {
+        nonce,
+        pkce_verifier,
+    } = cn_cache
         .0
         .remove(&CachableCsrfToken(state))
         .await
@@ -235,46 +305,56 @@ async fn openid_callback(
     let token_response = client_record
         .client
         .exchange_code(code)
+        .set_pkce_verifier(pkce_verifier)
         .request_async(async_http_client)
         .await?;
     let id_token_verifier = client_record.client.id_token_verifier();
 
-    let id_token_claims = token_response
+    let id_token = token_response
         .extra_fields()
         .id_token()
-        .expect("Server did not return an ID token")
-        .claims(&id_token_verifier, &nonce)?;
+        .expect("Server did not return an ID token");
+    let id_token_claims = id_token.claims(&id_token_verifier, &nonce)?;
 
     let oid_subject_: &str = id_token_claims.subject();
+    let provider_session = ProviderSession {
+        provider: provider_.clone(),
+        subject: oid_subject_.to_string(),
+        sid: claim_values(id_token, "sid").into_iter().next(),
+    };
 
     // Get the current user if one is present
     let conn = &mut pool.get().await?;
     if let Some(user) = User::from_jar(&jar, &session, conn).await? {
         if client_record.end_session_endpoint.is_some() {
-            session.add_rp_provider(&jar, provider_.clone()).await;
+            session.add_rp_provider(&jar, provider_.clone()).await?;
         }
+        session.add_provider_session(&jar, provider_session).await?;
 
         // Add this provier to the database
         let access_token_expires = token_response.expires_in().and_then(|d| {
             let chrono_duration = chrono::Duration::from_std(d).ok()?;
             Some(Local::now().naive_local() + chrono_duration)
         });
+        let access_token = cipher.encrypt(token_response.access_token().secret())?;
+        let refresh_token = token_response
+            .refresh_token()
+            .map(|t| cipher.encrypt(t.secret()))
+            .transpose()?;
         let new_oauth_login = &NewOAuthConnection {
             user_id: user.id,
             provider: &provider_,
             provides: &OAuthProvision::Auth,
-            access_token: token_response.access_token().secret(),
+            access_token: &access_token,
             access_token_expires: access_token_expires.as_ref(),
-            refresh_token: token_response.refresh_token().map(|t| t.secret().as_str()),
+            refresh_token: refresh_token.as_deref(),
             refresh_token_expires: None,
             oid_subject: Some(oid_subject_),
+            token_key_id: Some(cipher.current_key_id()),
         };
-        insert_into(oauth_connections::table)
-            .values(new_oauth_login)
-            .execute(conn)
-            .await?;
+        new_oauth_login.upsert(conn).await?;
 
-        Ok((jar, OAuthRedirect))
+        Ok((jar, OAuthRedirect.into_response()))
     } else if let Some(oid_connection) = {
         use crate::schema::oauth_connections::dsl::*;
         oauth_connections
@@ -290,18 +370,46 @@ async fn openid_callback(
             .get_result(conn)
             .await?;
 
+        if !user.active {
+            return Err(OAuthError("This account has been deactivated".to_string()));
+        }
+        if login_blocked(user.email_verified, &config).await {
+            return Err(OAuthError(
+                "Verify your email address before signing in".to_string(),
+            ));
+        }
+
+        if let Some(mappings) = claim_mappings(&config, &provider_).await.as_deref() {
+            mappings.apply(user.id, id_token, conn).await?;
+        }
+
+        let sync_fields = sync_fields(&config, &provider_).await;
+        if !sync_fields.is_empty() {
+            let profile = OidcProfile::load(
+                &client_record.client,
+                id_token_claims,
+                token_response.access_token(),
+                &sync_fields,
+            )
+            .await;
+            // A bad value from the provider shouldn't stop the user from signing in
+            if let Err(e) = profile.sync(user.id, &sync_fields, conn).await {
+                warn!(
+                    "Failed to sync profile of user {} from {provider_}: {e}",
+                    user.id
+                );
+            }
+        }
+
         // Start session
         jar = session
             .insert(
-                SessionData {
-                    user_id: user.id,
-                    rp_logout_providers_with_open_sessions: vec![provider_],
-                },
+                SessionData::from_provider_session(user.id, provider_session, client),
                 jar,
             )
-            .await;
+            .await?;
 
-        Ok((jar, OAuthRedirect))
+        Ok((jar, OAuthRedirect.into_response()))
     } else if config.read().await.allow_signups {
         // Sign up
 
@@ -309,29 +417,62 @@ async fn openid_callback(
         let missing_info_error = OAuthError(
             "The OpenId Connect provider couldn't produce required information".to_string(),
         );
-        let email = id_token_claims
-            .email()
+        let profile = OidcProfile::load(
+            &client_record.client,
+            id_token_claims,
+            token_response.access_token(),
+            &sync_fields(&config, &provider_).await,
+        )
+        .await;
+        let email = profile
+            .email
+            .as_deref()
             .ok_or_else(|| missing_info_error.clone())?;
+
+        // The provider's word alone isn't enough to hand over an existing
+        // account; its owner has to confirm the link first
+        if let Some(existing_user) = User::find(email, conn).await? {
+            if profile.email_verified != Some(true) {
+                return Err(OAuthError(
+                    "A user with that email already exists".to_string(),
+                ));
+            }
+
+            let access_token_expires = token_response.expires_in().and_then(|d| {
+                let chrono_duration = chrono::Duration::from_std(d).ok()?;
+                Some(Local::now().naive_local() + chrono_duration)
+            });
+            let link_token = links
+                .insert(PendingLink {
+                    user_id: existing_user.id,
+                    provider: provider_,
+                    oid_subject: oid_subject_.to_string(),
+                    sid: provider_session.sid,
+                    access_token: cipher.encrypt(token_response.access_token().secret())?,
+                    access_token_expires,
+                    refresh_token: token_response
+                        .refresh_token()
+                        .map(|t| cipher.encrypt(t.secret()))
+                        .transpose()?,
+                    token_key_id: cipher.current_key_id().to_string(),
+                    email_secret: None,
+                })
+                .await;
+
+            return Ok((jar, LinkAccountRedirect(link_token).into_response()));
+        }
+
         let new_user_data = NewUser {
             email,
-            email_verified: id_token_claims.email_verified().unwrap_or_default(),
-            phone_number: id_token_claims.phone_number().map(|p| p.as_str()),
-            fname: id_token_claims
-                .given_name()
-                .ok_or_else(|| missing_info_error.clone())?
-                .iter()
-                .next()
-                .map(|(_, n)| n.as_str())
+            email_verified: profile.email_verified.unwrap_or_default(),
+            phone_number: profile.phone_number.as_deref(),
+            fname: profile
+                .fname
+                .as_deref()
                 .ok_or_else(|| missing_info_error.clone())?,
-            lname: id_token_claims
-                .family_name()
-                .ok_or_else(|| missing_info_error.clone())?
-                .iter()
-                .next()
-                .map(|(_, n)| n.as_str())
-                .ok_or(missing_info_error)?,
+            lname: profile.lname.as_deref().ok_or(missing_info_error)?,
             bio: None,
-            profile_image: None,
+            profile_image: profile.picture.as_deref(),
             permission_level: None,
         };
 
@@ -355,33 +496,55 @@ async fn openid_callback(
             let chrono_duration = chrono::Duration::from_std(d).ok()?;
             Some(Local::now().naive_local() + chrono_duration)
         });
+        let access_token = cipher.encrypt(token_response.access_token().secret())?;
+        let refresh_token = token_response
+            .refresh_token()
+            .map(|t| cipher.encrypt(t.secret()))
+            .transpose()?;
         let new_oauth_login = &NewOAuthConnection {
             user_id: id,
             provider: &provider_,
             provides: &OAuthProvision::Auth,
-            access_token: token_response.access_token().secret(),
+            access_token: &access_token,
             access_token_expires: access_token_expires.as_ref(),
-            refresh_token: token_response.refresh_token().map(|t| t.secret().as_str()),
+            refresh_token: refresh_token.as_deref(),
             refresh_token_expires: None,
             oid_subject: Some(oid_subject_),
+            token_key_id: Some(cipher.current_key_id()),
         };
         insert_into(oauth_connections::table)
             .values(new_oauth_login)
             .execute(conn)
             .await?;
 
+        if let Some(mappings) = claim_mappings(&config, &provider_).await.as_deref() {
+            mappings.apply(id, id_token, conn).await?;
+        }
+
+        if !new_user_data.email_verified {
+            send_verification_email(
+                id,
+                email.to_string(),
+                &config.read().await.base_url,
+                &signer,
+                &mailer,
+            );
+            if login_blocked(false, &config).await {
+                return Err(OAuthError(
+                    "Check your email to verify your address before signing in".to_string(),
+                ));
+            }
+        }
+
         // Start session
         jar = session
             .insert(
-                SessionData {
-                    user_id: id,
-                    rp_logout_providers_with_open_sessions: vec![provider_],
-                },
+                SessionData::from_provider_session(id, provider_session, client),
                 jar,
             )
-            .await;
+            .await?;
 
-        Ok((jar, OAuthRedirect))
+        Ok((jar, OAuthRedirect.into_response()))
     } else {
         Err(OAuthError(
             "Automatic sign-ups have been disallowed".to_string(),
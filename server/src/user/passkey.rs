@@ -0,0 +1,637 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Path, State},
+    routing::{get, post, put},
+    Json, Router,
+};
+use axum_extra::extract::CookieJar;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{Local, NaiveDateTime};
+use color_eyre::{eyre::eyre, Result};
+use diesel::{delete, insert_into, prelude::*, update};
+use diesel_async::RunQueryDsl;
+use hmac::{Hmac, Mac};
+use retainer::Cache;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::Sha256;
+use tokio::task::JoinHandle;
+use tracing::warn;
+use typeshare::typeshare;
+use uuid::Uuid;
+use webauthn_rs::prelude::{
+    CreationChallengeResponse, Passkey, PasskeyAuthentication, PasskeyRegistration,
+    PublicKeyCredential, RegisterPublicKeyCredential, RequestChallengeResponse, Url, Webauthn,
+    WebauthnBuilder,
+};
+
+use crate::{
+    config::{Config, StatefulConfig},
+    http_error::HttpError,
+    model::{LoginMethod, NewPasskey, PasskeyCredential, User},
+    user::{
+        email_verification::login_blocked,
+        session::{ClientInfo, SessionData, SessionStore},
+        UserData, UserFromParts,
+    },
+    utils::random_token,
+    AppState, PgPool,
+};
+
+const CEREMONY_TIMEOUT: u64 = 300; // 5 minutes in seconds
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/", get(get_my_passkeys))
+        .route("/:id", put(rename_passkey).delete(delete_passkey))
+        .route("/register/start", post(start_registration))
+        .route("/register/finish", post(finish_registration))
+        .route("/login/start", post(start_login))
+        .route("/login/finish", post(finish_login))
+}
+
+/// State kept between the start and finish of a WebAuthn ceremony
+enum Ceremony {
+    Registration {
+        user_id: i32,
+        user_handle: Uuid,
+        name: String,
+        state: PasskeyRegistration,
+    },
+    Authentication {
+        user_id: i32,
+        state: PasskeyAuthentication,
+    },
+}
+
+#[derive(Clone)]
+pub struct PasskeyAuth {
+    webauthn: Arc<Webauthn>,
+    ceremonies: Arc<Cache<String, Ceremony>>,
+    rp_id: String,
+    /// Derives made-up credential ids for accounts without passkeys
+    decoy_key: Arc<Hmac<Sha256>>,
+}
+
+impl PasskeyAuth {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let origin = Url::parse(
+            config
+                .passkeys
+                .origin
+                .as_deref()
+                .unwrap_or(&config.base_url),
+        )?;
+        let rp_id = match &config.passkeys.rp_id {
+            Some(rp_id) => rp_id.clone(),
+            None => origin
+                .host_str()
+                .ok_or(eyre!("The passkey origin has no host; set passkeys.rp_id"))?
+                .to_string(),
+        };
+
+        Self::new(&rp_id, &origin)
+    }
+
+    pub fn new(rp_id: &str, origin: &Url) -> Result<Self> {
+        let webauthn = WebauthnBuilder::new(rp_id, origin)?
+            .rp_name("Sceideal")
+            .timeout(Duration::from_secs(CEREMONY_TIMEOUT))
+            .build()?;
+        let decoy_key = Hmac::<Sha256>::new_from_slice(&rand::random::<[u8; 32]>())
+            .map_err(|_| eyre!("could not create the passkey decoy key"))?;
+
+        Ok(Self {
+            webauthn: Arc::new(webauthn),
+            ceremonies: Arc::new(Cache::new()),
+            rp_id: rp_id.to_string(),
+            decoy_key: Arc::new(decoy_key),
+        })
+    }
+
+    pub fn spawn_monitor_thread(&self) -> JoinHandle<()> {
+        let store = self.ceremonies.clone();
+        tokio::spawn(async move { store.monitor(4, 0.25, Duration::from_secs(3)).await })
+    }
+
+    /// Store a ceremony and return the token the browser uses to finish it
+    async fn insert(&self, ceremony: Ceremony) -> String {
+        let token = random_token(30);
+        self.ceremonies
+            .insert(
+                token.clone(),
+                ceremony,
+                Duration::from_secs(CEREMONY_TIMEOUT),
+            )
+            .await;
+        token
+    }
+
+    /// Ceremonies can only be finished once
+    async fn take(&self, token: &str) -> Option<Ceremony> {
+        self.ceremonies.remove(&token.to_string()).await
+    }
+
+    /// Options shaped like a real login's, for emails without passkeys, so
+    /// that starting a login doesn't show which accounts have them. The
+    /// made-up credential id stays the same for an email, like a real one.
+    fn decoy_options(&self, email: &str) -> Result<RequestChallengeResponse, serde_json::Error> {
+        let mut mac = Hmac::clone(&self.decoy_key);
+        mac.update(email.to_lowercase().as_bytes());
+        let credential_id = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+
+        serde_json::from_value(json!({
+            "publicKey": {
+                "challenge": URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>()),
+                "timeout": CEREMONY_TIMEOUT * 1000,
+                "rpId": self.rp_id,
+                "allowCredentials": [{ "type": "public-key", "id": credential_id }],
+                "userVerification": "required",
+            }
+        }))
+    }
+}
+
+fn encode_credential_id(passkey: &Passkey) -> String {
+    URL_SAFE_NO_PAD.encode(passkey.cred_id())
+}
+
+/// Authenticators that keep a counter must increase it on every use; if it
+/// doesn't, the credential may have been cloned. Ones without a counter
+/// always send 0.
+fn counter_regressed(counter: i64, stored: i64) -> bool {
+    (counter != 0 || stored != 0) && counter <= stored
+}
+
+#[typeshare]
+#[derive(Serialize)]
+pub struct PasskeyData {
+    pub id: i32,
+    pub name: String,
+    #[typeshare(serialized_as = "String")]
+    pub created_on: NaiveDateTime,
+    #[typeshare(serialized_as = "Option<String>")]
+    pub last_used: Option<NaiveDateTime>,
+}
+
+impl From<PasskeyCredential> for PasskeyData {
+    fn from(value: PasskeyCredential) -> Self {
+        Self {
+            id: value.id,
+            name: value.name,
+            created_on: value.created_on,
+            last_used: value.last_used,
+        }
+    }
+}
+
+/// Options to pass to `navigator.credentials`, and the token to send back
+/// with the result
+#[derive(Serialize)]
+pub struct PasskeyChallenge<T> {
+    token: String,
+    options: T,
+}
+
+#[typeshare]
+#[derive(Deserialize)]
+pub struct PasskeyName {
+    name: String,
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn get_my_passkeys(
+    UserFromParts { user, jar }: UserFromParts,
+    State(pool): State<PgPool>,
+) -> Result<(CookieJar, Json<Vec<PasskeyData>>), HttpError> {
+    let conn = &mut pool.get().await?;
+
+    let passkeys = PasskeyCredential::belonging_to(&user)
+        .load::<PasskeyCredential>(conn)
+        .await?
+        .into_iter()
+        .map(PasskeyData::from)
+        .collect();
+
+    Ok((jar, Json(passkeys)))
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn rename_passkey(
+    UserFromParts { user, jar }: UserFromParts,
+    State(pool): State<PgPool>,
+    Path(id_): Path<i32>,
+    Json(passkey_name): Json<PasskeyName>,
+) -> Result<CookieJar, HttpError> {
+    use crate::schema::passkeys::dsl::*;
+
+    let conn = &mut pool.get().await?;
+
+    let updated = update(passkeys.find(id_))
+        .filter(user_id.eq(user.id))
+        .set(name.eq(passkey_name.name))
+        .execute(conn)
+        .await?;
+    if updated == 0 {
+        return Err(HttpError::not_found("passkey not found"));
+    }
+
+    Ok(jar)
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn delete_passkey(
+    UserFromParts { user, jar }: UserFromParts,
+    State(pool): State<PgPool>,
+    Path(id_): Path<i32>,
+) -> Result<CookieJar, HttpError> {
+    use crate::schema::passkeys::dsl::*;
+
+    let conn = &mut pool.get().await?;
+
+    // Don't let users lock themselves out
+    if user.count_login_methods(conn).await? <= 1 {
+        return Err(HttpError::forbidden(
+            "cannot remove the last login method for this account",
+        ));
+    }
+
+    let deleted = delete(passkeys.find(id_))
+        .filter(user_id.eq(user.id))
+        .execute(conn)
+        .await?;
+    if deleted == 0 {
+        return Err(HttpError::not_found("passkey not found"));
+    }
+
+    Ok(jar)
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn start_registration(
+    UserFromParts { user, jar }: UserFromParts,
+    State(pool): State<PgPool>,
+    State(passkey_auth): State<PasskeyAuth>,
+    Json(passkey_name): Json<PasskeyName>,
+) -> Result<(CookieJar, Json<PasskeyChallenge<CreationChallengeResponse>>), HttpError> {
+    let conn = &mut pool.get().await?;
+
+    let existing = PasskeyCredential::belonging_to(&user)
+        .load::<PasskeyCredential>(conn)
+        .await?;
+
+    // All of a user's passkeys share a handle, so authenticators can tell
+    // they belong to the same account
+    let user_handle = existing
+        .first()
+        .and_then(|credential| Uuid::parse_str(&credential.user_handle).ok())
+        .unwrap_or_else(Uuid::new_v4);
+    // Keeps authenticators from registering a second passkey for the account
+    let exclude_credentials = existing
+        .iter()
+        .filter_map(|credential| serde_json::from_str::<Passkey>(&credential.passkey).ok())
+        .map(|passkey| passkey.cred_id().clone())
+        .collect();
+
+    let (options, state) = passkey_auth
+        .webauthn
+        .start_passkey_registration(
+            user_handle,
+            &user.email,
+            &format!("{} {}", user.fname, user.lname),
+            Some(exclude_credentials),
+        )
+        .map_err(|e| {
+            warn!("Failed to start passkey registration: {e}");
+            HttpError::internal("could not start passkey registration")
+        })?;
+
+    let token = passkey_auth
+        .insert(Ceremony::Registration {
+            user_id: user.id,
+            user_handle,
+            name: passkey_name.name,
+            state,
+        })
+        .await;
+
+    Ok((jar, Json(PasskeyChallenge { token, options })))
+}
+
+#[derive(Deserialize)]
+pub struct PasskeyRegistrationData {
+    token: String,
+    credential: RegisterPublicKeyCredential,
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn finish_registration(
+    UserFromParts { user, jar }: UserFromParts,
+    State(pool): State<PgPool>,
+    State(passkey_auth): State<PasskeyAuth>,
+    Json(registration): Json<PasskeyRegistrationData>,
+) -> Result<(CookieJar, Json<PasskeyData>), HttpError> {
+    let Some(Ceremony::Registration {
+        user_id,
+        user_handle,
+        name,
+        state,
+    }) = passkey_auth.take(&registration.token).await
+    else {
+        return Err(HttpError::not_found("registration expired; start again"));
+    };
+    if user_id != user.id {
+        return Err(HttpError::not_found("registration expired; start again"));
+    }
+
+    let passkey = passkey_auth
+        .webauthn
+        .finish_passkey_registration(&registration.credential, &state)
+        .map_err(|e| {
+            warn!("Passkey registration for user {} failed: {e}", user.id);
+            HttpError::forbidden("passkey registration failed")
+        })?;
+
+    let conn = &mut pool.get().await?;
+    let credential: PasskeyCredential = insert_into(crate::schema::passkeys::table)
+        .values(&NewPasskey {
+            user_id: user.id,
+            credential_id: &encode_credential_id(&passkey),
+            user_handle: &user_handle.to_string(),
+            name: &name,
+            passkey: &serde_json::to_string(&passkey)?,
+            sign_count: 0,
+        })
+        .get_result(conn)
+        .await?;
+
+    Ok((jar, Json(credential.into())))
+}
+
+#[typeshare]
+#[derive(Deserialize)]
+pub struct PasskeyLoginStart {
+    email: String,
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn start_login(
+    State(pool): State<PgPool>,
+    State(passkey_auth): State<PasskeyAuth>,
+    Json(login_start): Json<PasskeyLoginStart>,
+) -> Result<Json<PasskeyChallenge<RequestChallengeResponse>>, HttpError> {
+    let conn = &mut pool.get().await?;
+    let user = User::find(&login_start.email, conn).await?;
+    let credentials: Vec<Passkey> = match &user {
+        Some(user) => PasskeyCredential::belonging_to(user)
+            .load::<PasskeyCredential>(conn)
+            .await?
+            .iter()
+            .map(|credential| serde_json::from_str(&credential.passkey))
+            .collect::<Result<_, _>>()?,
+        None => Vec::new(),
+    };
+    let Some(user) = user.filter(|_| !credentials.is_empty()) else {
+        // Nothing can answer the challenge, so there's no ceremony to finish
+        let options = passkey_auth.decoy_options(&login_start.email)?;
+        return Ok(Json(PasskeyChallenge {
+            token: random_token(30),
+            options,
+        }));
+    };
+
+    let (options, state) = passkey_auth
+        .webauthn
+        .start_passkey_authentication(&credentials)
+        .map_err(|e| {
+            warn!("Failed to start passkey authentication: {e}");
+            HttpError::internal("could not start passkey login")
+        })?;
+
+    let token = passkey_auth
+        .insert(Ceremony::Authentication {
+            user_id: user.id,
+            state,
+        })
+        .await;
+
+    Ok(Json(PasskeyChallenge { token, options }))
+}
+
+#[derive(Deserialize)]
+pub struct PasskeyLoginData {
+    token: String,
+    credential: PublicKeyCredential,
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn finish_login(
+    State(pool): State<PgPool>,
+    State(session): State<SessionStore>,
+    State(passkey_auth): State<PasskeyAuth>,
+    State(config): State<StatefulConfig>,
+    mut jar: CookieJar,
+    client: ClientInfo,
+    Json(login_data): Json<PasskeyLoginData>,
+) -> Result<(CookieJar, Json<UserData>), HttpError> {
+    use crate::schema::passkeys::dsl::*;
+
+    let Some(Ceremony::Authentication {
+        user_id: user_id_,
+        state,
+    }) = passkey_auth.take(&login_data.token).await
+    else {
+        return Err(HttpError::not_found("login expired; start again"));
+    };
+
+    let result = passkey_auth
+        .webauthn
+        .finish_passkey_authentication(&login_data.credential, &state)
+        .map_err(|e| {
+            warn!("Passkey login for user {user_id_} failed: {e}");
+            HttpError::forbidden("passkey login failed")
+        })?;
+
+    let conn = &mut pool.get().await?;
+    let credential: PasskeyCredential = passkeys
+        .filter(user_id.eq(user_id_))
+        .filter(credential_id.eq(URL_SAFE_NO_PAD.encode(result.cred_id())))
+        .first(conn)
+        .await
+        .optional()?
+        .ok_or(HttpError::forbidden("passkey login failed"))?;
+
+    let counter = i64::from(result.counter());
+    if counter_regressed(counter, credential.sign_count) {
+        warn!(
+            "Passkey {} of user {user_id_} reused sign counter {counter}",
+            credential.id
+        );
+        return Err(HttpError::forbidden("passkey login failed"));
+    }
+
+    let mut stored_passkey: Passkey = serde_json::from_str(&credential.passkey)?;
+    stored_passkey.update_credential(&result);
+    update(passkeys.find(credential.id))
+        .set((
+            passkey.eq(serde_json::to_string(&stored_passkey)?),
+            sign_count.eq(counter),
+            last_used.eq(Local::now().naive_local()),
+        ))
+        .execute(conn)
+        .await?;
+
+    let user = User::get(user_id_, conn)
+        .await?
+        .ok_or(HttpError::not_found("user not found"))?;
+    if !user.active {
+        return Err(HttpError::forbidden("this account has been deactivated"));
+    }
+    if login_blocked(user.email_verified, &config).await {
+        return Err(HttpError::forbidden(
+            "verify your email address before signing in",
+        ));
+    }
+
+    let user_data = user.get_user_data(conn).await?;
+    jar = session
+        .insert(SessionData::new(user.id, LoginMethod::Passkey, client), jar)
+        .await?;
+
+    Ok((jar, Json(user_data)))
+}
+
+#[cfg(test)]
+mod tests {
+    use webauthn_authenticator_rs::{softpasskey::SoftPasskey, WebauthnAuthenticator};
+    use webauthn_rs::prelude::{AuthenticationResult, WebauthnError};
+
+    use super::*;
+
+    const RP_ID: &str = "sceideal.example";
+
+    fn setup() -> (PasskeyAuth, Url, WebauthnAuthenticator<SoftPasskey>) {
+        let origin = Url::parse(&format!("https://{RP_ID}")).unwrap();
+        let passkey_auth = PasskeyAuth::new(RP_ID, &origin).unwrap();
+        (
+            passkey_auth,
+            origin,
+            WebauthnAuthenticator::new(SoftPasskey::new()),
+        )
+    }
+
+    fn register(
+        passkey_auth: &PasskeyAuth,
+        origin: &Url,
+        authenticator: &mut WebauthnAuthenticator<SoftPasskey>,
+    ) -> Passkey {
+        let (options, state) = passkey_auth
+            .webauthn
+            .start_passkey_registration(Uuid::new_v4(), "ada@example.com", "Ada Lovelace", None)
+            .unwrap();
+        let credential = authenticator
+            .do_registration(origin.clone(), options)
+            .unwrap();
+        passkey_auth
+            .webauthn
+            .finish_passkey_registration(&credential, &state)
+            .unwrap()
+    }
+
+    fn authenticate(
+        passkey_auth: &PasskeyAuth,
+        origin: &Url,
+        authenticator: &mut WebauthnAuthenticator<SoftPasskey>,
+        passkey: &Passkey,
+    ) -> Result<AuthenticationResult, WebauthnError> {
+        let (options, state) = passkey_auth
+            .webauthn
+            .start_passkey_authentication(&[passkey.clone()])
+            .unwrap();
+        let credential = authenticator
+            .do_authentication(origin.clone(), options)
+            .unwrap();
+        passkey_auth
+            .webauthn
+            .finish_passkey_authentication(&credential, &state)
+    }
+
+    #[test]
+    fn registers_and_signs_in_with_a_software_passkey() {
+        let (passkey_auth, origin, mut authenticator) = setup();
+        let mut passkey = register(&passkey_auth, &origin, &mut authenticator);
+
+        // Stored and loaded the way `finish_registration` does
+        passkey = serde_json::from_str(&serde_json::to_string(&passkey).unwrap()).unwrap();
+        let mut stored_count = 0;
+        for _ in 0..2 {
+            let result = authenticate(&passkey_auth, &origin, &mut authenticator, &passkey)
+                .expect("the registered passkey signs in");
+            assert_eq!(result.cred_id(), passkey.cred_id());
+
+            let counter = i64::from(result.counter());
+            assert!(!counter_regressed(counter, stored_count));
+            passkey.update_credential(&result);
+            stored_count = counter;
+        }
+    }
+
+    #[test]
+    fn rejects_passkeys_from_other_authenticators() {
+        let (passkey_auth, origin, mut authenticator) = setup();
+        let passkey = register(&passkey_auth, &origin, &mut authenticator);
+
+        let mut other_authenticator = WebauthnAuthenticator::new(SoftPasskey::new());
+        let (options, state) = passkey_auth
+            .webauthn
+            .start_passkey_authentication(&[passkey])
+            .unwrap();
+        // The other authenticator holds no matching credential
+        let signed_in = other_authenticator
+            .do_authentication(origin, options)
+            .ok()
+            .map(|credential| {
+                passkey_auth
+                    .webauthn
+                    .finish_passkey_authentication(&credential, &state)
+            });
+        assert!(!matches!(signed_in, Some(Ok(_))));
+    }
+
+    #[test]
+    fn rejects_sign_counters_that_dont_increase() {
+        assert!(!counter_regressed(6, 5));
+        assert!(counter_regressed(5, 5));
+        assert!(counter_regressed(4, 5));
+        // Dropping back to 0 counts too
+        assert!(counter_regressed(0, 5));
+        assert!(!counter_regressed(0, 0));
+        assert!(!counter_regressed(1, 0));
+    }
+
+    #[test]
+    fn decoy_options_match_for_the_same_email() {
+        let (passkey_auth, _, _) = setup();
+        let options =
+            |email| serde_json::to_value(passkey_auth.decoy_options(email).unwrap()).unwrap();
+
+        let first = options("nobody@example.com");
+        let second = options("Nobody@example.com");
+        let other = options("someone@example.com");
+
+        assert_eq!(first["publicKey"]["rpId"], RP_ID);
+        assert_eq!(
+            first["publicKey"]["allowCredentials"],
+            second["publicKey"]["allowCredentials"]
+        );
+        assert_ne!(
+            first["publicKey"]["allowCredentials"],
+            other["publicKey"]["allowCredentials"]
+        );
+        assert_ne!(
+            first["publicKey"]["challenge"],
+            second["publicKey"]["challenge"]
+        );
+    }
+}
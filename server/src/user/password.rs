@@ -0,0 +1,136 @@
+use std::{collections::HashSet, sync::Arc};
+
+use argon2::{
+    password_hash::{
+        self, rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+    },
+    Algorithm, Argon2, Params, Version,
+};
+use color_eyre::{eyre::eyre, Result};
+use thiserror::Error;
+use tracing::info;
+
+use crate::{config::Config, http_error::HttpError};
+
+/// Longer passwords are refused so that hashing them can't tie up the server
+const MAX_PASSWORD_LENGTH: usize = 1024;
+
+#[derive(Debug, Error)]
+pub enum PasswordError {
+    #[error(transparent)]
+    Bcrypt(#[from] bcrypt::BcryptError),
+    #[error("password hashing failed: {0}")]
+    Argon2(#[from] password_hash::Error),
+}
+
+/// Hashes and checks passwords
+///
+/// New hashes use Argon2id and are stored as PHC strings. bcrypt hashes from
+/// before the switch are still accepted, and `needs_rehash` reports them (and
+/// Argon2 hashes with outdated parameters) so they can be replaced on login.
+#[derive(Clone)]
+pub struct Passwords(Arc<PasswordsInner>);
+
+struct PasswordsInner {
+    argon2: Argon2<'static>,
+    min_length: usize,
+    /// Lowercased
+    breached: HashSet<String>,
+}
+
+impl Passwords {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let policy = &config.passwords;
+
+        let params = Params::new(
+            policy.argon2_memory_kib,
+            policy.argon2_iterations,
+            policy.argon2_parallelism,
+            None,
+        )
+        .map_err(|e| eyre!("Invalid Argon2 parameters: {e}"))?;
+
+        let breached = match &policy.breached_list {
+            Some(path) => {
+                let breached: HashSet<String> = std::fs::read_to_string(path)?
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty())
+                    .map(str::to_lowercase)
+                    .collect();
+                info!("Loaded {} breached passwords", breached.len());
+                breached
+            }
+            None => HashSet::new(),
+        };
+
+        Ok(Self(Arc::new(PasswordsInner {
+            argon2: Argon2::new(Algorithm::Argon2id, Version::V0x13, params),
+            min_length: policy.min_length,
+            breached,
+        })))
+    }
+
+    pub fn hash(&self, password: &str) -> Result<String, PasswordError> {
+        let salt = SaltString::generate(&mut OsRng);
+        Ok(self
+            .0
+            .argon2
+            .hash_password(password.as_bytes(), &salt)?
+            .to_string())
+    }
+
+    pub fn verify(&self, password: &str, hash: &str) -> Result<bool, PasswordError> {
+        if is_bcrypt(hash) {
+            return Ok(bcrypt::verify(password, hash)?);
+        }
+
+        // Argon2 takes the algorithm and parameters from the hash itself
+        let parsed = PasswordHash::new(hash)?;
+        match self.0.argon2.verify_password(password.as_bytes(), &parsed) {
+            Ok(()) => Ok(true),
+            Err(password_hash::Error::Password) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Whether a hash should be replaced with one made by `hash`
+    pub fn needs_rehash(&self, hash: &str) -> bool {
+        let Ok(parsed) = PasswordHash::new(hash) else {
+            return true;
+        };
+        if parsed.algorithm != Algorithm::Argon2id.ident() {
+            return true;
+        }
+
+        let current = self.0.argon2.params();
+        Params::try_from(&parsed).map_or(true, |params| {
+            params.m_cost() != current.m_cost()
+                || params.t_cost() != current.t_cost()
+                || params.p_cost() != current.p_cost()
+        })
+    }
+
+    /// Refuse passwords that are too short or known to be breached
+    pub fn check_policy(&self, password: &str) -> Result<(), HttpError> {
+        let length = password.chars().count();
+        if length < self.0.min_length {
+            return Err(HttpError::bad_request("password is too short"));
+        }
+        if length > MAX_PASSWORD_LENGTH {
+            return Err(HttpError::bad_request("password is too long"));
+        }
+        if self.0.breached.contains(&password.to_lowercase()) {
+            return Err(HttpError::bad_request(
+                "this password has appeared in a data breach; choose another",
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn is_bcrypt(hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$"]
+        .iter()
+        .any(|prefix| hash.starts_with(prefix))
+}
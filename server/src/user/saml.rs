@@ -0,0 +1,413 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Path, State},
+    http::header,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Form, Router,
+};
+use axum_extra::extract::CookieJar;
+use color_eyre::{eyre::eyre, Result};
+use diesel::{insert_into, prelude::*, result::DatabaseErrorKind};
+use diesel_async::RunQueryDsl;
+use futures::{stream::FuturesUnordered, StreamExt};
+use openssl::{pkey::PKey, x509::X509};
+use retainer::Cache;
+use samael::{
+    metadata::{EntityDescriptor, HTTP_REDIRECT_BINDING},
+    schema::Assertion,
+    service_provider::{ServiceProvider, ServiceProviderBuilder},
+};
+use serde::Deserialize;
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+use crate::{
+    config::{Config, SamlProvider, StatefulConfig},
+    crypto::LinkSigner,
+    http_error::HttpError,
+    mail::Mailer,
+    model::{LoginMethod, NewSamlIdentity, NewUser, SamlIdentity, User},
+    oauth::{impl_oauth_error, OAuthError, OAuthRedirect},
+    schema::{saml_identities, users},
+    user::{
+        email_verification::{login_blocked, send_verification_email},
+        session::{ClientInfo, SessionData, SessionStore},
+    },
+    utils::random_token,
+    AppState, PgPool,
+};
+
+const SAML_REQUEST_TIMEOUT: u64 = 600; // 10 minutes in seconds
+const EMAIL_NAME_ID_FORMAT: &str = "urn:oasis:names:tc:SAML:1.1:nameid-format:emailAddress";
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/:provider/metadata", get(get_metadata))
+        .route("/:provider/generate_url", get(get_login_url))
+        .route("/:provider/acs", post(assertion_consumer_service))
+}
+
+impl_oauth_error!(samael::service_provider::Error, "SAML error: {}");
+
+#[derive(Clone)]
+pub struct SamlProviders(Arc<HashMap<String, ServiceProvider>>);
+
+impl SamlProviders {
+    pub async fn from_config(config: &Config) -> Self {
+        let futures: FuturesUnordered<_> = config
+            .saml
+            .iter()
+            .map(|(name, provider)| async move {
+                match load_service_provider(name, provider, &config.base_url).await {
+                    Ok(service_provider) => Some((name.clone(), service_provider)),
+                    Err(e) => {
+                        warn!("Failed to set up SAML provider {name}: {e}");
+                        None
+                    }
+                }
+            })
+            .collect();
+
+        SamlProviders(Arc::new(
+            futures
+                .filter_map(|r| async { r })
+                .collect::<HashMap<String, ServiceProvider>>()
+                .await,
+        ))
+    }
+
+    pub fn get(&self, provider_: &str) -> Option<&ServiceProvider> {
+        self.0.get(provider_)
+    }
+}
+
+async fn load_service_provider(
+    name: &str,
+    provider: &SamlProvider,
+    base_url: &str,
+) -> Result<ServiceProvider> {
+    let metadata_xml = match (&provider.idp_metadata_url, &provider.idp_metadata_path) {
+        (Some(url), _) => reqwest::get(url).await?.error_for_status()?.text().await?,
+        (None, Some(path)) => tokio::fs::read_to_string(path).await?,
+        (None, None) => return Err(eyre!("Set idp_metadata_url or idp_metadata_path")),
+    };
+    let idp_metadata: EntityDescriptor = metadata_xml
+        .parse()
+        .map_err(|e| eyre!("Invalid IdP metadata: {e}"))?;
+
+    let certificate = X509::from_pem(&tokio::fs::read(&provider.certificate_path).await?)?;
+    let key = PKey::private_key_from_pem(&tokio::fs::read(&provider.private_key_path).await?)?;
+
+    let sp_url = format!("{base_url}/api/user/saml/{name}");
+    ServiceProviderBuilder::default()
+        .entity_id(
+            provider
+                .entity_id
+                .clone()
+                .unwrap_or_else(|| format!("{sp_url}/metadata")),
+        )
+        .key(key)
+        .certificate(certificate)
+        // Every assertion has to answer a request we made
+        .allow_idp_initiated(false)
+        .idp_metadata(idp_metadata)
+        .acs_url(format!("{sp_url}/acs"))
+        .build()
+        .map_err(|e| eyre!("Invalid SAML settings: {e}"))
+}
+
+/// An AuthnRequest waiting for the IdP's response, by relay state
+struct PendingSamlRequest {
+    provider: String,
+    request_id: String,
+    /// Set when a signed-in user is linking this identity to their account
+    link_user_id: Option<i32>,
+}
+
+#[derive(Clone)]
+pub struct SamlRequestCache(Arc<Cache<String, PendingSamlRequest>>);
+
+impl Default for SamlRequestCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SamlRequestCache {
+    pub fn new() -> Self {
+        Self(Arc::new(Cache::new()))
+    }
+
+    pub fn spawn_monitor_thread(&self) -> JoinHandle<()> {
+        let store = self.0.clone();
+        tokio::spawn(async move { store.monitor(4, 0.25, Duration::from_secs(3)).await })
+    }
+}
+
+/// The first value of an attribute, matched by name or friendly name
+fn attribute<'a>(assertion: &'a Assertion, name: &str) -> Option<&'a str> {
+    assertion
+        .attribute_statements
+        .iter()
+        .flatten()
+        .flat_map(|statement| &statement.attributes)
+        .find(|attribute| {
+            attribute.name.as_deref() == Some(name)
+                || attribute.friendly_name.as_deref() == Some(name)
+        })
+        .and_then(|attribute| attribute.values.first())
+        .and_then(|value| value.value.as_deref())
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn get_metadata(
+    State(providers): State<SamlProviders>,
+    Path(provider_): Path<String>,
+) -> Result<Response, HttpError> {
+    let service_provider = providers
+        .get(&provider_)
+        .ok_or(HttpError::not_found("no provider by that name exists"))?;
+
+    let metadata = service_provider
+        .metadata()
+        .map_err(|e| eyre!("Could not build SP metadata: {e}"))?
+        .to_xml()
+        .map_err(|e| eyre!("Could not serialize SP metadata: {e}"))?;
+
+    Ok((
+        [(header::CONTENT_TYPE, "application/samlmetadata+xml")],
+        metadata,
+    )
+        .into_response())
+}
+
+/// URL of the IdP's sign-in page. Signed-in users link the identity to their
+/// account instead.
+#[axum_macros::debug_handler(state = AppState)]
+async fn get_login_url(
+    State(providers): State<SamlProviders>,
+    State(requests): State<SamlRequestCache>,
+    State(pool): State<PgPool>,
+    State(session): State<SessionStore>,
+    jar: CookieJar,
+    Path(provider_): Path<String>,
+) -> Result<String, HttpError> {
+    let service_provider = providers
+        .get(&provider_)
+        .ok_or(HttpError::not_found("no provider by that name exists"))?;
+    let sso_url = service_provider
+        .sso_binding_location(HTTP_REDIRECT_BINDING)
+        .ok_or(HttpError::internal(
+            "the identity provider has no HTTP-Redirect sign-in endpoint",
+        ))?;
+
+    let authn_request = service_provider
+        .make_authentication_request(&sso_url)
+        .map_err(|e| eyre!("Could not create AuthnRequest: {e}"))?;
+    let relay_state = random_token(30);
+    let url = authn_request
+        .redirect(&relay_state)
+        .map_err(|e| eyre!("Could not encode AuthnRequest: {e}"))?
+        .ok_or(HttpError::internal("could not create a sign-in URL"))?;
+
+    let conn = &mut pool.get().await?;
+    let link_user_id = User::from_jar(&jar, &session, conn)
+        .await?
+        .map(|user| user.id);
+
+    requests
+        .0
+        .insert(
+            relay_state,
+            PendingSamlRequest {
+                provider: provider_,
+                request_id: authn_request.id.clone(),
+                link_user_id,
+            },
+            Duration::from_secs(SAML_REQUEST_TIMEOUT),
+        )
+        .await;
+
+    Ok(url.to_string())
+}
+
+#[derive(Deserialize)]
+pub struct SamlResponseForm {
+    #[serde(rename = "SAMLResponse")]
+    saml_response: String,
+    #[serde(rename = "RelayState")]
+    relay_state: String,
+}
+
+/// Receives the IdP's response through the HTTP-POST binding
+#[allow(clippy::too_many_arguments)]
+#[axum_macros::debug_handler(state = AppState)]
+async fn assertion_consumer_service(
+    Path(provider_): Path<String>,
+    State(providers): State<SamlProviders>,
+    State(requests): State<SamlRequestCache>,
+    State(pool): State<PgPool>,
+    State(session): State<SessionStore>,
+    State(config): State<StatefulConfig>,
+    State(signer): State<LinkSigner>,
+    State(mailer): State<Mailer>,
+    mut jar: CookieJar,
+    client: ClientInfo,
+    Form(form): Form<SamlResponseForm>,
+) -> Result<(CookieJar, Response), OAuthError> {
+    let service_provider = providers
+        .get(&provider_)
+        .ok_or(OAuthError("No provider by that name exists".to_string()))?;
+    let pending = requests
+        .0
+        .remove(&form.relay_state)
+        .await
+        .filter(|pending| pending.provider == provider_)
+        .ok_or(OAuthError(
+            "This sign-in request has expired; try again".to_string(),
+        ))?;
+
+    // Checks the signature against the IdP's metadata, along with the
+    // audience, the validity window and that it answers our request
+    let assertion = service_provider
+        .parse_base64_response(&form.saml_response, Some(&[pending.request_id.as_str()]))?;
+    let name_id_ = assertion
+        .subject
+        .as_ref()
+        .and_then(|subject| subject.name_id.as_ref())
+        .ok_or(OAuthError(
+            "The identity provider didn't send a NameID".to_string(),
+        ))?;
+
+    let conn = &mut pool.get().await?;
+
+    let existing_identity = {
+        use crate::schema::saml_identities::dsl::*;
+        saml_identities
+            .find((&provider_, &name_id_.value))
+            .first::<SamlIdentity>(conn)
+            .await
+            .optional()?
+    };
+
+    let user_id_ = if let Some(link_user_id) = pending.link_user_id {
+        // Link the identity to the user who started the request
+        insert_into(saml_identities::table)
+            .values(&NewSamlIdentity {
+                provider: &provider_,
+                name_id: &name_id_.value,
+                user_id: link_user_id,
+            })
+            .execute(conn)
+            .await
+            .map_err(|e| {
+                if let diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _) =
+                    e
+                {
+                    OAuthError("This identity is already linked to an account".to_string())
+                } else {
+                    e.into()
+                }
+            })?;
+        link_user_id
+    } else if let Some(identity) = existing_identity {
+        // Sign in
+        let user: User = users::table.find(identity.user_id).get_result(conn).await?;
+        if !user.active {
+            return Err(OAuthError("This account has been deactivated".to_string()));
+        }
+        if login_blocked(user.email_verified, &config).await {
+            return Err(OAuthError(
+                "Verify your email address before signing in".to_string(),
+            ));
+        }
+        user.id
+    } else if config.read().await.allow_signups {
+        // Sign up
+        let attributes = config
+            .read()
+            .await
+            .saml
+            .get(&provider_)
+            .map(|provider| provider.attributes.clone())
+            .ok_or(OAuthError("No provider by that name exists".to_string()))?;
+        let missing_info_error =
+            OAuthError("The identity provider couldn't produce required information".to_string());
+
+        let email = attribute(&assertion, &attributes.email)
+            .or_else(|| {
+                (name_id_.format.as_deref() == Some(EMAIL_NAME_ID_FORMAT))
+                    .then_some(name_id_.value.as_str())
+            })
+            .ok_or_else(|| missing_info_error.clone())?;
+
+        // Existing accounts have to sign in first and link the identity
+        if User::find(email, conn).await?.is_some() {
+            return Err(OAuthError(format!(
+                "A user with that email already exists; sign in and then continue with {provider_} to link it"
+            )));
+        }
+
+        let new_user_data = NewUser {
+            email,
+            email_verified: false,
+            phone_number: attributes
+                .phone_number
+                .as_deref()
+                .and_then(|name| attribute(&assertion, name)),
+            fname: attribute(&assertion, &attributes.fname)
+                .ok_or_else(|| missing_info_error.clone())?,
+            lname: attribute(&assertion, &attributes.lname).ok_or(missing_info_error)?,
+            bio: None,
+            profile_image: None,
+            permission_level: None,
+        };
+        let id: i32 = insert_into(users::table)
+            .values(&new_user_data)
+            .returning(users::id)
+            .get_result(conn)
+            .await?;
+
+        insert_into(saml_identities::table)
+            .values(&NewSamlIdentity {
+                provider: &provider_,
+                name_id: &name_id_.value,
+                user_id: id,
+            })
+            .execute(conn)
+            .await?;
+
+        send_verification_email(
+            id,
+            email.to_string(),
+            &config.read().await.base_url,
+            &signer,
+            &mailer,
+        );
+        if login_blocked(false, &config).await {
+            return Err(OAuthError(
+                "Check your email to verify your address before signing in".to_string(),
+            ));
+        }
+        id
+    } else {
+        return Err(OAuthError(
+            "Automatic sign-ups have been disallowed".to_string(),
+        ));
+    };
+
+    // Start session
+    jar = session
+        .insert(
+            SessionData {
+                login_provider: Some(provider_),
+                ..SessionData::new(user_id_, LoginMethod::Saml, client)
+            },
+            jar,
+        )
+        .await?;
+
+    Ok((jar, OAuthRedirect.into_response()))
+}
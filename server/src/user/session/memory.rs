@@ -0,0 +1,251 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use chrono::Local;
+use retainer::Cache;
+use tokio::{sync::Mutex, task::JoinHandle};
+
+use super::{ProviderSession, SessionBackend, SessionData, SessionError};
+
+const INDEX_PRUNE_INTERVAL: u64 = 300; // 5 minutes in seconds
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum IndexKey {
+    User(i32),
+    Subject { provider: String, subject: String },
+    Sid { provider: String, sid: String },
+}
+
+impl SessionData {
+    fn index_keys(&self) -> Vec<IndexKey> {
+        let mut keys = vec![IndexKey::User(self.user_id)];
+        keys.extend(
+            self.provider_sessions
+                .iter()
+                .flat_map(ProviderSession::index_keys),
+        );
+        keys
+    }
+}
+
+impl ProviderSession {
+    fn index_keys(&self) -> Vec<IndexKey> {
+        let mut keys = vec![IndexKey::Subject {
+            provider: self.provider.clone(),
+            subject: self.subject.clone(),
+        }];
+        if let Some(sid) = &self.sid {
+            keys.push(IndexKey::Sid {
+                provider: self.provider.clone(),
+                sid: sid.clone(),
+            });
+        }
+        keys
+    }
+}
+
+/// Keeps sessions in memory; they are lost on restart
+#[derive(Clone)]
+pub struct MemorySessionBackend {
+    sessions: Arc<Cache<String, SessionData>>,
+    /// Session id hashes by user and provider session. Entries for sessions
+    /// that expired are pruned periodically.
+    index: Arc<Mutex<HashMap<IndexKey, HashSet<String>>>>,
+}
+
+impl Default for MemorySessionBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemorySessionBackend {
+    pub fn new() -> Self {
+        Self {
+            sessions: Arc::new(Cache::new()),
+            index: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    async fn index(&self, id_hash: &str, keys: Vec<IndexKey>) {
+        let mut index = self.index.lock().await;
+        for key in keys {
+            index.entry(key).or_default().insert(id_hash.to_string());
+        }
+    }
+
+    async fn unindex(&self, id_hash: &str, keys: Vec<IndexKey>) {
+        let mut index = self.index.lock().await;
+        for key in keys {
+            if let Some(id_hashes) = index.get_mut(&key) {
+                id_hashes.remove(id_hash);
+                if id_hashes.is_empty() {
+                    index.remove(&key);
+                }
+            }
+        }
+    }
+
+    async fn lookup(&self, key: &IndexKey) -> HashSet<String> {
+        self.index
+            .lock()
+            .await
+            .get(key)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    async fn prune_index(&self) {
+        let mut index = self.index.lock().await;
+
+        let mut expired = HashSet::new();
+        for id_hash in index.values().flatten() {
+            if self.sessions.get(id_hash).await.is_none() {
+                expired.insert(id_hash.clone());
+            }
+        }
+
+        for id_hashes in index.values_mut() {
+            id_hashes.retain(|id_hash| !expired.contains(id_hash));
+        }
+        index.retain(|_, id_hashes| !id_hashes.is_empty());
+    }
+}
+
+#[async_trait]
+impl SessionBackend for MemorySessionBackend {
+    fn spawn_monitor_thread(&self) -> JoinHandle<()> {
+        let backend = self.clone();
+        tokio::spawn(async move {
+            let prune_index = async {
+                let prune_interval = Duration::from_secs(INDEX_PRUNE_INTERVAL);
+                let mut interval = tokio::time::interval(prune_interval);
+                loop {
+                    interval.tick().await;
+                    backend.prune_index().await;
+                }
+            };
+            tokio::join!(
+                backend.sessions.monitor(4, 0.25, Duration::from_secs(3)),
+                prune_index
+            );
+        })
+    }
+
+    async fn insert(
+        &self,
+        id_hash: &str,
+        session_data: SessionData,
+        ttl: Duration,
+    ) -> Result<(), SessionError> {
+        self.index(id_hash, session_data.index_keys()).await;
+        self.sessions
+            .insert(id_hash.to_string(), session_data, ttl)
+            .await;
+        Ok(())
+    }
+
+    async fn get(&self, id_hash: &str) -> Result<Option<SessionData>, SessionError> {
+        Ok(self
+            .sessions
+            .get(&id_hash.to_string())
+            .await
+            .map(|session_data| SessionData::clone(&session_data)))
+    }
+
+    async fn touch(&self, id_hash: &str, ttl: Duration) -> Result<(), SessionError> {
+        let id_hash = id_hash.to_string();
+        self.sessions
+            .update(&id_hash, |session_data| {
+                session_data.last_seen = Local::now().naive_local()
+            })
+            .await;
+        self.sessions.set_expiration(&id_hash, ttl).await;
+        Ok(())
+    }
+
+    async fn remove(&self, id_hash: &str) -> Result<Option<SessionData>, SessionError> {
+        let session_data = self.sessions.remove(&id_hash.to_string()).await;
+        if let Some(session_data) = &session_data {
+            self.unindex(id_hash, session_data.index_keys()).await;
+        }
+        Ok(session_data)
+    }
+
+    async fn add_rp_provider(&self, id_hash: &str, provider: String) -> Result<(), SessionError> {
+        self.sessions
+            .update(&id_hash.to_string(), |session_data| {
+                session_data.add_rp_providers(provider)
+            })
+            .await;
+        Ok(())
+    }
+
+    async fn add_provider_session(
+        &self,
+        id_hash: &str,
+        provider_session: ProviderSession,
+    ) -> Result<(), SessionError> {
+        self.index(id_hash, provider_session.index_keys()).await;
+        self.sessions
+            .update(&id_hash.to_string(), |session_data| {
+                session_data.provider_sessions.push(provider_session)
+            })
+            .await;
+        Ok(())
+    }
+
+    async fn find_by_provider_session(
+        &self,
+        provider: &str,
+        subject: Option<&str>,
+        sid: Option<&str>,
+    ) -> Result<Vec<String>, SessionError> {
+        let key = match (sid, subject) {
+            (Some(sid), _) => IndexKey::Sid {
+                provider: provider.to_string(),
+                sid: sid.to_string(),
+            },
+            (None, Some(subject)) => IndexKey::Subject {
+                provider: provider.to_string(),
+                subject: subject.to_string(),
+            },
+            (None, None) => return Ok(Vec::new()),
+        };
+
+        let id_hashes = self.lookup(&key).await;
+
+        // A sid is only unique per subject at some providers
+        let mut matching = Vec::new();
+        for id_hash in id_hashes {
+            let subject_matches = match (subject, self.sessions.get(&id_hash).await) {
+                (Some(subject), Some(session_data)) => session_data
+                    .provider_sessions
+                    .iter()
+                    .any(|s| s.provider == provider && s.subject == subject),
+                _ => true,
+            };
+            if subject_matches {
+                matching.push(id_hash);
+            }
+        }
+        Ok(matching)
+    }
+
+    async fn list_for_user(
+        &self,
+        user_id: i32,
+    ) -> Result<Vec<(String, SessionData)>, SessionError> {
+        let mut user_sessions = Vec::new();
+        for id_hash in self.lookup(&IndexKey::User(user_id)).await {
+            if let Some(session_data) = self.sessions.get(&id_hash).await {
+                user_sessions.push((id_hash, SessionData::clone(&session_data)));
+            }
+        }
+        Ok(user_sessions)
+    }
+}
@@ -0,0 +1,521 @@
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::{header, request::Parts, HeaderMap},
+};
+use axum_extra::extract::CookieJar;
+use chrono::{Local, NaiveDateTime};
+use cookie::{Cookie, SameSite};
+use thiserror::Error;
+use tokio::task::JoinHandle;
+
+use crate::{
+    config::{Config, SessionBackendKind},
+    model::LoginMethod,
+    oauth::impl_oauth_error,
+    utils::{random_token, sha256_hex},
+    AppState, PgPool,
+};
+
+mod memory;
+mod postgres;
+
+pub use memory::MemorySessionBackend;
+pub use postgres::PgSessionBackend;
+
+pub const SESSION_COOKIE_NAME: &str = "sid";
+/// Holds an admin's own session while they impersonate someone
+pub const IMPERSONATOR_COOKIE_NAME: &str = "impersonator_sid";
+pub const SESSION_TTL: u64 = 3600 * 5; // 5 hrs in seconds
+
+#[derive(Clone)]
+pub struct SessionData {
+    pub user_id: i32,
+    pub rp_logout_providers_with_open_sessions: Vec<String>,
+    /// OpenID Connect sessions this session is tied to, so that it can be
+    /// ended by back-channel logout
+    pub provider_sessions: Vec<ProviderSession>,
+    pub client: ClientInfo,
+    pub login_method: LoginMethod,
+    /// Provider the user signed in with, for OpenID Connect logins
+    pub login_provider: Option<String>,
+    pub created_on: NaiveDateTime,
+    pub last_seen: NaiveDateTime,
+    /// Whether a second factor was checked when signing in
+    pub two_factor: bool,
+    /// The admin impersonating the user, if this is an impersonation session
+    pub impersonator_id: Option<i32>,
+}
+
+/// A user's session at an OpenID Connect provider
+#[derive(Clone)]
+pub struct ProviderSession {
+    pub provider: String,
+    pub subject: String,
+    /// The provider's `sid` claim, if it sends one
+    pub sid: Option<String>,
+}
+
+/// The device a session was started from
+#[derive(Clone, Default)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+/// The client's address, behind any trusted proxies. Each proxy appends the
+/// address it got the request from to `X-Forwarded-For`, so the header is
+/// read from the end for as long as the hop it came from is trusted.
+fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpAddr]) -> IpAddr {
+    let forwarded: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+
+    let mut client = peer;
+    for hop in forwarded.into_iter().rev() {
+        if !trusted_proxies.contains(&client) {
+            break;
+        }
+        match hop.parse() {
+            Ok(ip) => client = ip,
+            Err(_) => break,
+        }
+    }
+    client
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for ClientInfo {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let user_agent = parts
+            .headers
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        let ip_address = match peer {
+            Some(peer) => {
+                let config = state.config.read().await;
+                Some(client_ip(peer, &parts.headers, &config.trusted_proxies).to_string())
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            user_agent,
+            ip_address,
+        })
+    }
+}
+
+impl SessionData {
+    pub fn new(user_id: i32, login_method: LoginMethod, client: ClientInfo) -> Self {
+        let now = Local::now().naive_local();
+        Self {
+            user_id,
+            rp_logout_providers_with_open_sessions: Vec::new(),
+            provider_sessions: Vec::new(),
+            client,
+            login_method,
+            login_provider: None,
+            created_on: now,
+            last_seen: now,
+            two_factor: false,
+            impersonator_id: None,
+        }
+    }
+
+    /// A session started by signing in with an OpenID Connect provider
+    pub fn from_provider_session(
+        user_id: i32,
+        provider_session: ProviderSession,
+        client: ClientInfo,
+    ) -> Self {
+        Self {
+            rp_logout_providers_with_open_sessions: vec![provider_session.provider.clone()],
+            login_provider: Some(provider_session.provider.clone()),
+            provider_sessions: vec![provider_session],
+            ..Self::new(user_id, LoginMethod::OpenId, client)
+        }
+    }
+
+    fn add_rp_providers(&mut self, provider: String) {
+        if !self
+            .rp_logout_providers_with_open_sessions
+            .contains(&provider)
+        {
+            self.rp_logout_providers_with_open_sessions.push(provider);
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SessionError {
+    #[error("database error: {0}")]
+    Database(#[from] diesel::result::Error),
+    #[error("pool error: {0}")]
+    Pool(#[from] diesel_async::pooled_connection::bb8::RunError),
+}
+
+impl_oauth_error!(SessionError, "Session error: {}");
+
+/// Storage for sessions. Sessions are keyed by the SHA-256 of their id, so
+/// backends never see the cookie value itself.
+#[async_trait]
+pub trait SessionBackend: Send + Sync {
+    /// Remove expired sessions (and anything else that needs cleaning up) in
+    /// the background
+    fn spawn_monitor_thread(&self) -> JoinHandle<()>;
+
+    async fn insert(
+        &self,
+        id_hash: &str,
+        session_data: SessionData,
+        ttl: Duration,
+    ) -> Result<(), SessionError>;
+
+    async fn get(&self, id_hash: &str) -> Result<Option<SessionData>, SessionError>;
+
+    /// Extend the session and record that it was just used
+    async fn touch(&self, id_hash: &str, ttl: Duration) -> Result<(), SessionError>;
+
+    async fn remove(&self, id_hash: &str) -> Result<Option<SessionData>, SessionError>;
+
+    async fn add_rp_provider(&self, id_hash: &str, provider: String) -> Result<(), SessionError>;
+
+    async fn add_provider_session(
+        &self,
+        id_hash: &str,
+        provider_session: ProviderSession,
+    ) -> Result<(), SessionError>;
+
+    /// Sessions tied to a provider session. With a `sid` only that provider
+    /// session matches; otherwise every session of the subject does.
+    async fn find_by_provider_session(
+        &self,
+        provider: &str,
+        subject: Option<&str>,
+        sid: Option<&str>,
+    ) -> Result<Vec<String>, SessionError>;
+
+    /// A user's sessions and their id hashes
+    async fn list_for_user(&self, user_id: i32)
+        -> Result<Vec<(String, SessionData)>, SessionError>;
+}
+
+#[derive(Clone)]
+pub struct SessionStore(Arc<dyn SessionBackend>);
+
+impl SessionStore {
+    pub fn new(backend: impl SessionBackend + 'static) -> Self {
+        Self(Arc::new(backend))
+    }
+
+    pub fn from_config(config: &Config, pool: &PgPool) -> Self {
+        match config.session_backend {
+            SessionBackendKind::Memory => Self::new(MemorySessionBackend::new()),
+            SessionBackendKind::Postgres => Self::new(PgSessionBackend::new(pool.clone())),
+        }
+    }
+
+    pub fn spawn_monitor_thread(&self) -> JoinHandle<()> {
+        self.0.spawn_monitor_thread()
+    }
+
+    pub async fn insert(
+        &self,
+        session_data: SessionData,
+        jar: CookieJar,
+    ) -> Result<CookieJar, SessionError> {
+        let sid = random_token(30);
+
+        self.0
+            .insert(
+                &sha256_hex(&sid),
+                session_data,
+                Duration::from_secs(SESSION_TTL),
+            )
+            .await?;
+
+        Ok(jar.add(session_cookie(SESSION_COOKIE_NAME, sid)))
+    }
+
+    /// Start a session for someone else, keeping the current session aside
+    /// until `stop_impersonating`
+    pub async fn impersonate(
+        &self,
+        session_data: SessionData,
+        mut jar: CookieJar,
+    ) -> Result<CookieJar, SessionError> {
+        if let Some(cookie) = jar.get(SESSION_COOKIE_NAME) {
+            let sid = cookie.value().to_string();
+            jar = jar.add(session_cookie(IMPERSONATOR_COOKIE_NAME, sid));
+        }
+        self.insert(session_data, jar).await
+    }
+
+    /// End the current session and switch back to the one `impersonate` kept
+    /// aside
+    pub async fn stop_impersonating(&self, mut jar: CookieJar) -> Result<CookieJar, SessionError> {
+        if let Some(cookie) = jar.get(SESSION_COOKIE_NAME) {
+            self.0.remove(&sha256_hex(cookie.value())).await?;
+        }
+        jar = jar.remove(Cookie::named(SESSION_COOKIE_NAME));
+
+        if let Some(cookie) = jar.get(IMPERSONATOR_COOKIE_NAME) {
+            let sid = cookie.value().to_string();
+            jar = jar
+                .remove(Cookie::named(IMPERSONATOR_COOKIE_NAME))
+                .add(session_cookie(SESSION_COOKIE_NAME, sid));
+        }
+        Ok(jar)
+    }
+
+    pub async fn get(&self, jar: &CookieJar) -> Result<Option<SessionData>, SessionError> {
+        match jar.get(SESSION_COOKIE_NAME) {
+            Some(cookie) => self.0.get(&sha256_hex(cookie.value())).await,
+            None => Ok(None),
+        }
+    }
+
+    pub async fn reup(&self, jar: CookieJar) -> Result<CookieJar, SessionError> {
+        if let Some(cookie) = jar.get(SESSION_COOKIE_NAME) {
+            self.0
+                .touch(
+                    &sha256_hex(cookie.value()),
+                    Duration::from_secs(SESSION_TTL),
+                )
+                .await?;
+            // cookie.set_max_age()
+            // jar = jar.add(cookie);
+        }
+        Ok(jar)
+    }
+
+    pub async fn remove(
+        &self,
+        mut jar: CookieJar,
+    ) -> Result<(Option<SessionData>, CookieJar), SessionError> {
+        let mut session_data = None;
+        if let Some(cookie) = jar.get(SESSION_COOKIE_NAME) {
+            session_data = self.0.remove(&sha256_hex(cookie.value())).await?;
+        }
+        // Signing out while impersonating signs the admin out too
+        if let Some(cookie) = jar.get(IMPERSONATOR_COOKIE_NAME) {
+            self.0.remove(&sha256_hex(cookie.value())).await?;
+        }
+
+        jar = jar
+            .remove(Cookie::named(SESSION_COOKIE_NAME))
+            .remove(Cookie::named(IMPERSONATOR_COOKIE_NAME));
+        Ok((session_data, jar))
+    }
+
+    pub async fn add_rp_provider(
+        &self,
+        jar: &CookieJar,
+        provider: String,
+    ) -> Result<(), SessionError> {
+        if let Some(cookie) = jar.get(SESSION_COOKIE_NAME) {
+            self.0
+                .add_rp_provider(&sha256_hex(cookie.value()), provider)
+                .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn add_provider_session(
+        &self,
+        jar: &CookieJar,
+        provider_session: ProviderSession,
+    ) -> Result<(), SessionError> {
+        if let Some(cookie) = jar.get(SESSION_COOKIE_NAME) {
+            self.0
+                .add_provider_session(&sha256_hex(cookie.value()), provider_session)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// End every session tied to a provider session. Returns the number of
+    /// sessions removed.
+    pub async fn remove_provider_sessions(
+        &self,
+        provider: &str,
+        subject: Option<&str>,
+        sid: Option<&str>,
+    ) -> Result<usize, SessionError> {
+        let id_hashes = self
+            .0
+            .find_by_provider_session(provider, subject, sid)
+            .await?;
+
+        self.remove_all(id_hashes).await
+    }
+
+    /// Id hash of the session the cookie belongs to; this is what the
+    /// session endpoints use to refer to a session
+    pub fn current_id_hash(&self, jar: &CookieJar) -> Option<String> {
+        jar.get(SESSION_COOKIE_NAME)
+            .map(|cookie| sha256_hex(cookie.value()))
+    }
+
+    pub async fn list_for_user(
+        &self,
+        user_id: i32,
+    ) -> Result<Vec<(String, SessionData)>, SessionError> {
+        self.0.list_for_user(user_id).await
+    }
+
+    /// Remove one of a user's sessions. Returns false if the user has no
+    /// session with that id hash.
+    pub async fn remove_user_session(
+        &self,
+        user_id: i32,
+        id_hash: &str,
+    ) -> Result<bool, SessionError> {
+        match self.0.get(id_hash).await? {
+            Some(session_data) if session_data.user_id == user_id => {
+                Ok(self.0.remove(id_hash).await?.is_some())
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Remove all of a user's sessions, except the one `keep` belongs to.
+    /// Returns the number of sessions removed.
+    pub async fn remove_user_sessions(
+        &self,
+        user_id: i32,
+        keep: Option<&CookieJar>,
+    ) -> Result<usize, SessionError> {
+        let keep = keep.and_then(|jar| self.current_id_hash(jar));
+        let id_hashes = self
+            .0
+            .list_for_user(user_id)
+            .await?
+            .into_iter()
+            .map(|(id_hash, _)| id_hash)
+            .filter(|id_hash| Some(id_hash) != keep.as_ref())
+            .collect();
+
+        self.remove_all(id_hashes).await
+    }
+
+    async fn remove_all(&self, id_hashes: Vec<String>) -> Result<usize, SessionError> {
+        let mut removed = 0;
+        for id_hash in id_hashes {
+            if self.0.remove(&id_hash).await?.is_some() {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+fn session_cookie(name: &'static str, sid: String) -> Cookie<'static> {
+    Cookie::build(name, sid)
+        .max_age(cookie::time::Duration::seconds(SESSION_TTL as i64))
+        .path("/")
+        .secure(cfg!(not(debug_assertions)))
+        .same_site(if cfg!(debug_assertions) {
+            SameSite::Lax
+        } else {
+            SameSite::Strict
+        })
+        .http_only(true)
+        .finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    const PROXY: &str = "10.0.0.1";
+    const CLIENT: &str = "203.0.113.7";
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn forwarded_for(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append("x-forwarded-for", HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn untrusted_peers_are_the_client() {
+        let headers = forwarded_for(&["198.51.100.1"]);
+        assert_eq!(client_ip(ip(CLIENT), &headers, &[]), ip(CLIENT));
+        assert_eq!(client_ip(ip(CLIENT), &headers, &[ip(PROXY)]), ip(CLIENT));
+    }
+
+    #[test]
+    fn trusted_proxies_pass_on_the_client() {
+        let headers = forwarded_for(&[CLIENT]);
+        assert_eq!(client_ip(ip(PROXY), &headers, &[ip(PROXY)]), ip(CLIENT));
+    }
+
+    #[test]
+    fn spoofed_hops_before_untrusted_ones_are_ignored() {
+        // The client made up the first address; the proxy appended the real one
+        let headers = forwarded_for(&["198.51.100.1", CLIENT]);
+        assert_eq!(client_ip(ip(PROXY), &headers, &[ip(PROXY)]), ip(CLIENT));
+    }
+
+    #[test]
+    fn chains_of_trusted_proxies_are_followed() {
+        let inner = "10.0.0.2";
+        let headers = forwarded_for(&[&format!("{CLIENT}, {inner}")]);
+        assert_eq!(
+            client_ip(ip(PROXY), &headers, &[ip(PROXY), ip(inner)]),
+            ip(CLIENT)
+        );
+
+        // Headers can also be repeated instead of joined
+        let headers = forwarded_for(&[CLIENT, inner]);
+        assert_eq!(
+            client_ip(ip(PROXY), &headers, &[ip(PROXY), ip(inner)]),
+            ip(CLIENT)
+        );
+    }
+
+    #[test]
+    fn unparseable_hops_stop_the_search() {
+        let headers = forwarded_for(&[&format!("{CLIENT}, unknown")]);
+        assert_eq!(client_ip(ip(PROXY), &headers, &[ip(PROXY)]), ip(PROXY));
+    }
+
+    #[test]
+    fn ipv6_addresses_are_supported() {
+        let headers = forwarded_for(&["2001:db8::7"]);
+        assert_eq!(
+            client_ip(ip("::1"), &headers, &[ip("::1")]),
+            ip("2001:db8::7")
+        );
+    }
+}
@@ -0,0 +1,282 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{Local, NaiveDateTime};
+use diesel::{delete, insert_into, prelude::*, update};
+use diesel_async::RunQueryDsl;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+use super::{ClientInfo, ProviderSession, SessionBackend, SessionData, SessionError};
+use crate::{
+    model::{NewSession, Session, SessionProviderSession},
+    PgConn, PgPool,
+};
+
+const CLEANUP_INTERVAL: u64 = 600; // 10 minutes in seconds
+
+/// Keeps sessions in the `sessions` table, so they survive restarts and can be
+/// shared between instances
+#[derive(Clone)]
+pub struct PgSessionBackend {
+    pool: PgPool,
+}
+
+impl PgSessionBackend {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    async fn remove_expired(&self) -> Result<usize, SessionError> {
+        use crate::schema::sessions::dsl::*;
+
+        let conn = &mut self.pool.get().await?;
+        Ok(
+            delete(sessions.filter(expires.lt(Local::now().naive_local())))
+                .execute(conn)
+                .await?,
+        )
+    }
+
+    async fn load(
+        session: Session,
+        conn: &mut PgConn<'_>,
+    ) -> Result<SessionData, diesel::result::Error> {
+        let provider_sessions = SessionProviderSession::belonging_to(&session)
+            .load::<SessionProviderSession>(conn)
+            .await?
+            .into_iter()
+            .map(|s| ProviderSession {
+                provider: s.provider,
+                subject: s.subject,
+                sid: s.sid,
+            })
+            .collect();
+
+        Ok(SessionData {
+            user_id: session.user_id,
+            rp_logout_providers_with_open_sessions: session.rp_logout_providers_with_open_sessions,
+            provider_sessions,
+            client: ClientInfo {
+                user_agent: session.user_agent,
+                ip_address: session.ip_address,
+            },
+            login_method: session.login_method,
+            login_provider: session.login_provider,
+            created_on: session.created_on,
+            last_seen: session.last_seen,
+            two_factor: session.two_factor,
+            impersonator_id: session.impersonator_id,
+        })
+    }
+}
+
+fn expires_in(ttl: Duration) -> NaiveDateTime {
+    let ttl = chrono::Duration::from_std(ttl).unwrap_or_else(|_| chrono::Duration::zero());
+    Local::now().naive_local() + ttl
+}
+
+async fn insert_provider_session(
+    id_hash_: &str,
+    provider_session: ProviderSession,
+    conn: &mut PgConn<'_>,
+) -> Result<(), diesel::result::Error> {
+    insert_into(crate::schema::session_provider_sessions::table)
+        .values(&SessionProviderSession {
+            session_id_hash: id_hash_.to_string(),
+            provider: provider_session.provider,
+            subject: provider_session.subject,
+            sid: provider_session.sid,
+        })
+        .on_conflict_do_nothing()
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+#[async_trait]
+impl SessionBackend for PgSessionBackend {
+    fn spawn_monitor_thread(&self) -> JoinHandle<()> {
+        let backend = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(CLEANUP_INTERVAL));
+            loop {
+                interval.tick().await;
+                match backend.remove_expired().await {
+                    Ok(count) => debug!("Removed {count} expired sessions"),
+                    Err(e) => warn!("Failed to remove expired sessions: {e}"),
+                }
+            }
+        })
+    }
+
+    async fn insert(
+        &self,
+        id_hash_: &str,
+        session_data: SessionData,
+        ttl: Duration,
+    ) -> Result<(), SessionError> {
+        let conn = &mut self.pool.get().await?;
+
+        insert_into(crate::schema::sessions::table)
+            .values(&NewSession {
+                id_hash: id_hash_,
+                user_id: session_data.user_id,
+                rp_logout_providers_with_open_sessions: &session_data
+                    .rp_logout_providers_with_open_sessions,
+                user_agent: session_data.client.user_agent.as_deref(),
+                ip_address: session_data.client.ip_address.as_deref(),
+                expires: expires_in(ttl),
+                created_on: session_data.created_on,
+                login_method: session_data.login_method,
+                login_provider: session_data.login_provider.as_deref(),
+                last_seen: session_data.last_seen,
+                two_factor: session_data.two_factor,
+                impersonator_id: session_data.impersonator_id,
+            })
+            .execute(conn)
+            .await?;
+
+        for provider_session in session_data.provider_sessions {
+            insert_provider_session(id_hash_, provider_session, conn).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn get(&self, id_hash_: &str) -> Result<Option<SessionData>, SessionError> {
+        use crate::schema::sessions::dsl::*;
+
+        let conn = &mut self.pool.get().await?;
+
+        let session = sessions
+            .find(id_hash_)
+            .filter(expires.gt(Local::now().naive_local()))
+            .first::<Session>(conn)
+            .await
+            .optional()?;
+        match session {
+            Some(session) => Ok(Some(Self::load(session, conn).await?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn touch(&self, id_hash_: &str, ttl: Duration) -> Result<(), SessionError> {
+        use crate::schema::sessions::dsl::*;
+
+        let conn = &mut self.pool.get().await?;
+        update(sessions.find(id_hash_))
+            .set((
+                expires.eq(expires_in(ttl)),
+                last_seen.eq(Local::now().naive_local()),
+            ))
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn remove(&self, id_hash_: &str) -> Result<Option<SessionData>, SessionError> {
+        use crate::schema::sessions::dsl::*;
+
+        let conn = &mut self.pool.get().await?;
+
+        let session = sessions
+            .find(id_hash_)
+            .first::<Session>(conn)
+            .await
+            .optional()?;
+        let Some(session) = session else {
+            return Ok(None);
+        };
+        let expired = session.expires < Local::now().naive_local();
+        let session_data = Self::load(session, conn).await?;
+
+        // Provider sessions are removed by the cascade
+        delete(sessions.find(id_hash_)).execute(conn).await?;
+
+        Ok((!expired).then_some(session_data))
+    }
+
+    async fn add_rp_provider(&self, id_hash_: &str, provider: String) -> Result<(), SessionError> {
+        use crate::schema::sessions::dsl::*;
+
+        let conn = &mut self.pool.get().await?;
+
+        let providers: Option<Vec<String>> = sessions
+            .find(id_hash_)
+            .select(rp_logout_providers_with_open_sessions)
+            .first(conn)
+            .await
+            .optional()?;
+        if let Some(mut providers) = providers {
+            if !providers.contains(&provider) {
+                providers.push(provider);
+                update(sessions.find(id_hash_))
+                    .set(rp_logout_providers_with_open_sessions.eq(providers))
+                    .execute(conn)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn add_provider_session(
+        &self,
+        id_hash_: &str,
+        provider_session: ProviderSession,
+    ) -> Result<(), SessionError> {
+        let conn = &mut self.pool.get().await?;
+        Ok(insert_provider_session(id_hash_, provider_session, conn).await?)
+    }
+
+    async fn find_by_provider_session(
+        &self,
+        provider_: &str,
+        subject_: Option<&str>,
+        sid_: Option<&str>,
+    ) -> Result<Vec<String>, SessionError> {
+        use crate::schema::session_provider_sessions::dsl::*;
+
+        if subject_.is_none() && sid_.is_none() {
+            return Ok(Vec::new());
+        }
+
+        let conn = &mut self.pool.get().await?;
+
+        let mut query = session_provider_sessions
+            .filter(provider.eq(provider_))
+            .select(session_id_hash)
+            .into_boxed();
+        if let Some(subject_) = subject_ {
+            query = query.filter(subject.eq(subject_));
+        }
+        if let Some(sid_) = sid_ {
+            query = query.filter(sid.eq(sid_));
+        }
+
+        Ok(query.load(conn).await?)
+    }
+
+    async fn list_for_user(
+        &self,
+        user_id_: i32,
+    ) -> Result<Vec<(String, SessionData)>, SessionError> {
+        use crate::schema::sessions::dsl::*;
+
+        let conn = &mut self.pool.get().await?;
+
+        let user_sessions = sessions
+            .filter(user_id.eq(user_id_))
+            .filter(expires.gt(Local::now().naive_local()))
+            .order(last_seen.desc())
+            .load::<Session>(conn)
+            .await?;
+
+        let mut loaded = Vec::with_capacity(user_sessions.len());
+        for session in user_sessions {
+            let id_hash_ = session.id_hash.clone();
+            loaded.push((id_hash_, Self::load(session, conn).await?));
+        }
+        Ok(loaded)
+    }
+}
@@ -0,0 +1,563 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use axum_extra::extract::CookieJar;
+use chrono::{Local, NaiveDateTime, Utc};
+use color_eyre::Result;
+use diesel::{delete, insert_into, prelude::*, update};
+use diesel_async::RunQueryDsl;
+use retainer::Cache;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use totp_rs::{Algorithm, Secret, TOTP};
+use tracing::warn;
+use typeshare::typeshare;
+
+use crate::{
+    audit,
+    config::StatefulConfig,
+    crypto::TokenCipher,
+    http_error::HttpError,
+    model::{AuditEvent, NewAuditLogEntry, NewRecoveryCode, NewTotpLogin, TotpLogin, User},
+    user::{
+        lockout,
+        session::{SessionData, SessionStore},
+        UserData, UserFromParts,
+    },
+    utils::{random_token, sha256_hex},
+    AppState, PgConn, PgPool,
+};
+
+const TOTP_ISSUER: &str = "Sceideal";
+const TOTP_STEP: u64 = 30; // seconds, as recommended by RFC 6238
+const TOTP_LOGIN_TIMEOUT: u64 = 300; // 5 minutes in seconds
+const RECOVERY_CODE_COUNT: usize = 10;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/enroll", post(enroll))
+        .route("/confirm", post(confirm_enrollment))
+        .route("/disable", post(disable))
+        .route("/recovery-codes", post(regenerate_recovery_codes))
+        .route("/login", post(totp_login))
+}
+
+/// Sign-ins that passed the first factor and are waiting for a code, holding
+/// the session each one starts
+#[derive(Clone)]
+pub struct PendingTotpLoginCache(Arc<Cache<String, SessionData>>);
+
+impl Default for PendingTotpLoginCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PendingTotpLoginCache {
+    pub fn new() -> Self {
+        Self(Arc::new(Cache::new()))
+    }
+
+    pub fn spawn_monitor_thread(&self) -> JoinHandle<()> {
+        let store = self.0.clone();
+        tokio::spawn(async move { store.monitor(4, 0.25, Duration::from_secs(3)).await })
+    }
+
+    /// Store the pending login and return the token the browser uses to finish it
+    pub async fn insert(&self, session_data: SessionData) -> String {
+        let token = random_token(30);
+        self.0
+            .insert(
+                token.clone(),
+                session_data,
+                Duration::from_secs(TOTP_LOGIN_TIMEOUT),
+            )
+            .await;
+        token
+    }
+}
+
+/// Sends the browser to the frontend page that asks for a code, for sign-ins
+/// that finish with a redirect rather than a JSON response
+pub struct TotpLoginRedirect(pub String);
+
+impl IntoResponse for TotpLoginRedirect {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::FOUND,
+            [(header::LOCATION, format!("/login/totp?token={}", self.0))],
+        )
+            .into_response()
+    }
+}
+
+/// Checks codes for a single time step; `TotpLogin::check` handles drift so
+/// that it knows which step matched
+fn build_totp(secret: Vec<u8>, account: &str) -> Result<TOTP> {
+    Ok(TOTP::new(
+        Algorithm::SHA1,
+        6,
+        0,
+        TOTP_STEP,
+        secret,
+        Some(TOTP_ISSUER.to_string()),
+        account.to_string(),
+    )?)
+}
+
+impl TotpLogin {
+    async fn get(
+        user_id_: i32,
+        conn: &mut PgConn<'_>,
+    ) -> Result<Option<Self>, diesel::result::Error> {
+        use crate::schema::totp_logins::dsl::*;
+        totp_logins.find(user_id_).first(conn).await.optional()
+    }
+
+    /// The time step `code` belongs to, if it's valid and hasn't been used.
+    /// Codes from one step before or after now are accepted to allow for
+    /// clock drift.
+    fn check(&self, cipher: &TokenCipher, account: &str, code: &str) -> Result<Option<i64>> {
+        let secret = cipher.decrypt(Some(&self.secret_key_id), &self.secret)?;
+        let totp = build_totp(Secret::Encoded(secret).to_bytes()?, account)?;
+
+        let now = Utc::now().timestamp() / TOTP_STEP as i64;
+        Ok((now - 1..=now + 1)
+            .filter(|step| self.last_used_step.map_or(true, |last| *step > last))
+            .find(|step| totp.check(code, *step as u64 * TOTP_STEP)))
+    }
+}
+
+/// Whether signing in as this user needs a code after the password
+pub async fn totp_enabled(
+    user_id_: i32,
+    conn: &mut PgConn<'_>,
+) -> Result<bool, diesel::result::Error> {
+    Ok(TotpLogin::get(user_id_, conn)
+        .await?
+        .map_or(false, |totp_login| totp_login.confirmed))
+}
+
+/// Accept a code from the user's authenticator or one of their recovery
+/// codes. Either can only be used once. Wrong codes count towards the same
+/// kind of lockout as wrong passwords, however many logins they're spread
+/// across.
+pub async fn verify_second_factor(
+    user: &User,
+    code: &str,
+    cipher: &TokenCipher,
+    conn: &mut PgConn<'_>,
+) -> Result<bool, HttpError> {
+    use crate::schema::{recovery_codes, totp_logins};
+
+    let Some(totp_login) = TotpLogin::get(user.id, conn)
+        .await?
+        .filter(|totp_login| totp_login.confirmed)
+    else {
+        return Ok(false);
+    };
+    if let Some(seconds) = lockout::lockout_remaining(totp_login.locked_until) {
+        return Err(HttpError::retry_after(
+            "too many incorrect codes; try again later",
+            seconds,
+        ));
+    }
+
+    let code = code.trim();
+    let verified = if let Some(step) = totp_login.check(cipher, &user.email, code)? {
+        update(totp_logins::table.find(user.id))
+            .set(totp_logins::last_used_step.eq(step))
+            .execute(conn)
+            .await?;
+        true
+    } else {
+        let used = delete(recovery_codes::table.find((user.id, sha256_hex(code))))
+            .execute(conn)
+            .await?;
+        used > 0
+    };
+
+    if !verified {
+        record_code_failure(user.id, conn).await?;
+    } else if totp_login.failed_attempts > 0 {
+        update(totp_logins::table.find(user.id))
+            .set((
+                totp_logins::failed_attempts.eq(0),
+                totp_logins::locked_until.eq(None::<NaiveDateTime>),
+            ))
+            .execute(conn)
+            .await?;
+    }
+    Ok(verified)
+}
+
+/// Count a wrong code, locking the second factor once there are too many
+async fn record_code_failure(
+    user_id_: i32,
+    conn: &mut PgConn<'_>,
+) -> Result<(), diesel::result::Error> {
+    use crate::schema::totp_logins::dsl::*;
+
+    let failures: i32 = update(totp_logins.find(user_id_))
+        .set(failed_attempts.eq(failed_attempts + 1))
+        .returning(failed_attempts)
+        .get_result(conn)
+        .await?;
+    let Some(lockout) = lockout::lockout_duration(failures) else {
+        return Ok(());
+    };
+
+    update(totp_logins.find(user_id_))
+        .set(locked_until.eq(Local::now().naive_local() + chrono::Duration::seconds(lockout)))
+        .execute(conn)
+        .await?;
+
+    warn!(
+        "Locked user {user_id_}'s second factor for {lockout} seconds after {failures} wrong codes"
+    );
+    audit::record(
+        NewAuditLogEntry {
+            event: AuditEvent::AccountLocked,
+            user_id: Some(user_id_),
+            actor_id: None,
+            ip_address: None,
+            details: Some(&format!(
+                "{failures} incorrect codes; locked for {lockout} seconds"
+            )),
+        },
+        conn,
+    )
+    .await
+}
+
+/// Replace the user's recovery codes, returning the new ones
+async fn generate_recovery_codes(
+    user_id_: i32,
+    conn: &mut PgConn<'_>,
+) -> Result<Vec<String>, diesel::result::Error> {
+    use crate::schema::recovery_codes::dsl::*;
+
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT).map(|_| random_token(12)).collect();
+    let code_hashes: Vec<String> = codes.iter().map(|code| sha256_hex(code)).collect();
+
+    delete(recovery_codes.filter(user_id.eq(user_id_)))
+        .execute(conn)
+        .await?;
+    insert_into(recovery_codes)
+        .values(
+            code_hashes
+                .iter()
+                .map(|code_hash_| NewRecoveryCode {
+                    user_id: user_id_,
+                    code_hash: code_hash_,
+                })
+                .collect::<Vec<_>>(),
+        )
+        .execute(conn)
+        .await?;
+
+    Ok(codes)
+}
+
+#[typeshare]
+#[derive(Serialize)]
+pub struct TotpEnrollment {
+    /// Base32 secret, for entering into an authenticator by hand
+    pub secret: String,
+    /// `otpauth://` URI to show as a QR code
+    pub provisioning_uri: String,
+}
+
+/// Start enrolling a new authenticator. Two-factor authentication is only
+/// turned on once a code is confirmed.
+#[axum_macros::debug_handler(state = AppState)]
+async fn enroll(
+    UserFromParts { user, jar }: UserFromParts,
+    State(pool): State<PgPool>,
+    State(cipher): State<TokenCipher>,
+) -> Result<(CookieJar, Json<TotpEnrollment>), HttpError> {
+    use crate::schema::totp_logins::dsl::*;
+
+    let conn = &mut pool.get().await?;
+
+    if totp_enabled(user.id, conn).await? {
+        return Err(HttpError::bad_request(
+            "two-factor authentication is already enabled",
+        ));
+    }
+
+    let totp = build_totp(rand::random::<[u8; 20]>().to_vec(), &user.email)?;
+    let secret_ = totp.get_secret_base32();
+    let encrypted_secret = cipher.encrypt(&secret_)?;
+
+    insert_into(totp_logins)
+        .values(&NewTotpLogin {
+            user_id: user.id,
+            secret: &encrypted_secret,
+            secret_key_id: cipher.current_key_id(),
+        })
+        .on_conflict(user_id)
+        .do_update()
+        .set((
+            secret.eq(&encrypted_secret),
+            secret_key_id.eq(cipher.current_key_id()),
+            last_used_step.eq(None::<i64>),
+            failed_attempts.eq(0),
+            locked_until.eq(None::<NaiveDateTime>),
+        ))
+        .execute(conn)
+        .await?;
+
+    Ok((
+        jar,
+        Json(TotpEnrollment {
+            secret: secret_,
+            provisioning_uri: totp.get_url(),
+        }),
+    ))
+}
+
+#[typeshare]
+#[derive(Deserialize)]
+pub struct TotpCode {
+    code: String,
+}
+
+/// Finish enrolling with a code from the authenticator. Returns the recovery
+/// codes, which are only shown this once.
+#[axum_macros::debug_handler(state = AppState)]
+async fn confirm_enrollment(
+    UserFromParts { user, jar }: UserFromParts,
+    State(pool): State<PgPool>,
+    State(cipher): State<TokenCipher>,
+    Json(totp_code): Json<TotpCode>,
+) -> Result<(CookieJar, Json<Vec<String>>), HttpError> {
+    use crate::schema::totp_logins::dsl::*;
+
+    let conn = &mut pool.get().await?;
+
+    let totp_login = TotpLogin::get(user.id, conn)
+        .await?
+        .filter(|totp_login| !totp_login.confirmed)
+        .ok_or(HttpError::not_found("no enrollment in progress"))?;
+    let step = totp_login
+        .check(&cipher, &user.email, totp_code.code.trim())?
+        .ok_or(HttpError::forbidden("incorrect code"))?;
+
+    update(totp_logins.find(user.id))
+        .set((confirmed.eq(true), last_used_step.eq(step)))
+        .execute(conn)
+        .await?;
+    let codes = generate_recovery_codes(user.id, conn).await?;
+
+    Ok((jar, Json(codes)))
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn disable(
+    UserFromParts { user, jar }: UserFromParts,
+    State(pool): State<PgPool>,
+    State(cipher): State<TokenCipher>,
+    State(config): State<StatefulConfig>,
+    Json(totp_code): Json<TotpCode>,
+) -> Result<CookieJar, HttpError> {
+    use crate::schema::{recovery_codes, totp_logins};
+
+    let conn = &mut pool.get().await?;
+
+    if config.read().await.require_admin_2fa && user.is_staff(conn).await? {
+        return Err(HttpError::forbidden(
+            "two-factor authentication is required for admins",
+        ));
+    }
+
+    if !verify_second_factor(&user, &totp_code.code, &cipher, conn).await? {
+        return Err(HttpError::forbidden("incorrect code"));
+    }
+
+    delete(totp_logins::table.find(user.id))
+        .execute(conn)
+        .await?;
+    delete(recovery_codes::table.filter(recovery_codes::user_id.eq(user.id)))
+        .execute(conn)
+        .await?;
+
+    Ok(jar)
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn regenerate_recovery_codes(
+    UserFromParts { user, jar }: UserFromParts,
+    State(pool): State<PgPool>,
+    State(cipher): State<TokenCipher>,
+    Json(totp_code): Json<TotpCode>,
+) -> Result<(CookieJar, Json<Vec<String>>), HttpError> {
+    let conn = &mut pool.get().await?;
+
+    if !verify_second_factor(&user, &totp_code.code, &cipher, conn).await? {
+        return Err(HttpError::forbidden("incorrect code"));
+    }
+    let codes = generate_recovery_codes(user.id, conn).await?;
+
+    Ok((jar, Json(codes)))
+}
+
+#[typeshare]
+#[derive(Deserialize)]
+pub struct TotpLoginData {
+    token: String,
+    code: String,
+}
+
+/// Second step of a password or magic link login
+#[axum_macros::debug_handler(state = AppState)]
+async fn totp_login(
+    State(pool): State<PgPool>,
+    State(session): State<SessionStore>,
+    State(cipher): State<TokenCipher>,
+    State(pending_logins): State<PendingTotpLoginCache>,
+    mut jar: CookieJar,
+    Json(login_data): Json<TotpLoginData>,
+) -> Result<(CookieJar, Json<UserData>), HttpError> {
+    let pending = pending_logins
+        .0
+        .get(&login_data.token)
+        .await
+        .map(|pending| SessionData::clone(&pending))
+        .ok_or(HttpError::not_found("login expired; sign in again"))?;
+
+    let conn = &mut pool.get().await?;
+    let user = User::get(pending.user_id, conn)
+        .await?
+        .ok_or(HttpError::not_found("user not found"))?;
+
+    match verify_second_factor(&user, &login_data.code, &cipher, conn).await {
+        Ok(true) => {}
+        Ok(false) => return Err(HttpError::forbidden("incorrect code")),
+        Err(e) => {
+            // Usually a lockout, which means starting the login over
+            pending_logins.0.remove(&login_data.token).await;
+            return Err(e);
+        }
+    }
+    pending_logins.0.remove(&login_data.token).await;
+
+    let user_data = user.get_user_data(conn).await?;
+    jar = session
+        .insert(
+            SessionData {
+                two_factor: true,
+                ..pending
+            },
+            jar,
+        )
+        .await?;
+
+    Ok((jar, Json(user_data)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    use super::*;
+
+    const ACCOUNT: &str = "user@example.com";
+
+    fn cipher() -> TokenCipher {
+        let key = STANDARD.encode(rand::random::<[u8; 32]>());
+        TokenCipher::new("key", &HashMap::from([("key".to_string(), key)])).unwrap()
+    }
+
+    /// An enrolled login and the authenticator's view of it
+    fn enrolled(cipher: &TokenCipher, last_used_step: Option<i64>) -> (TotpLogin, TOTP) {
+        let totp = build_totp(rand::random::<[u8; 20]>().to_vec(), ACCOUNT).unwrap();
+        let now = Local::now().naive_utc();
+        let login = TotpLogin {
+            user_id: 1,
+            secret: cipher.encrypt(&totp.get_secret_base32()).unwrap(),
+            secret_key_id: cipher.current_key_id().to_string(),
+            confirmed: true,
+            last_used_step,
+            created_on: now,
+            updated_at: now,
+            failed_attempts: 0,
+            locked_until: None,
+        };
+        (login, totp)
+    }
+
+    fn current_step() -> i64 {
+        Utc::now().timestamp() / TOTP_STEP as i64
+    }
+
+    fn code_at(totp: &TOTP, step: i64) -> String {
+        totp.generate(step as u64 * TOTP_STEP)
+    }
+
+    #[test]
+    fn accepts_current_codes() {
+        let cipher = cipher();
+        let (login, totp) = enrolled(&cipher, None);
+        let step = current_step();
+
+        let code = code_at(&totp, step);
+        assert_eq!(login.check(&cipher, ACCOUNT, &code).unwrap(), Some(step));
+    }
+
+    #[test]
+    fn allows_one_step_of_drift() {
+        let cipher = cipher();
+        let (login, totp) = enrolled(&cipher, None);
+        let step = current_step();
+
+        for drifted in [step - 1, step + 1] {
+            let code = code_at(&totp, drifted);
+            assert_eq!(login.check(&cipher, ACCOUNT, &code).unwrap(), Some(drifted));
+        }
+        for drifted in [step - 3, step + 3] {
+            let code = code_at(&totp, drifted);
+            assert_eq!(login.check(&cipher, ACCOUNT, &code).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn rejects_reused_steps() {
+        let cipher = cipher();
+        let step = current_step();
+        let (login, totp) = enrolled(&cipher, Some(step));
+
+        for used in [step - 1, step] {
+            let code = code_at(&totp, used);
+            assert_eq!(login.check(&cipher, ACCOUNT, &code).unwrap(), None);
+        }
+        let code = code_at(&totp, step + 1);
+        assert_eq!(
+            login.check(&cipher, ACCOUNT, &code).unwrap(),
+            Some(step + 1)
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_codes() {
+        let cipher = cipher();
+        let (login, totp) = enrolled(&cipher, None);
+        let (_, other) = enrolled(&cipher, None);
+        let step = current_step();
+
+        let code = code_at(&other, step);
+        if code != code_at(&totp, step) {
+            assert_eq!(login.check(&cipher, ACCOUNT, &code).unwrap(), None);
+        }
+        assert_eq!(login.check(&cipher, ACCOUNT, "").unwrap(), None);
+        assert_eq!(login.check(&cipher, ACCOUNT, "abcdef").unwrap(), None);
+    }
+}
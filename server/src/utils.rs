@@ -1,4 +1,6 @@
+use rand::{distributions::Alphanumeric, Rng};
 use serde::{Deserialize, Deserializer};
+use sha2::{Digest, Sha256};
 
 pub fn some_option<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
 where
@@ -7,3 +9,18 @@ where
 {
     Option::<T>::deserialize(deserializer).map(Some)
 }
+
+/// Random alphanumeric string, for tokens that are handed out in links
+pub fn random_token(length: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
+
+/// Hex-encoded SHA-256, for secrets that only need to be compared (session
+/// ids, single-use tokens)
+pub fn sha256_hex(value: &str) -> String {
+    format!("{:x}", Sha256::digest(value.as_bytes()))
+}